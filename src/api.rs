@@ -1,13 +1,47 @@
+pub mod auth;
+pub mod control;
 pub mod http;
 pub mod stdio;
+pub mod tls;
+pub mod unix;
+use crate::session;
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
 use std::str::FromStr;
 
+/// Listener of a server of the API
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Access is controlled by the socket file's permissions
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Subscription {
     init: bool,
     snapshot: bool,
     resize: bool,
     output: bool,
+    marker: bool,
+    export: bool,
+    title: bool,
+    cwd: bool,
+    notification: bool,
+    command_started: bool,
+    command_finished: bool,
+    clipboard: bool,
 }
 
 impl FromStr for Subscription {
@@ -22,6 +56,14 @@ impl FromStr for Subscription {
                 "output" => sub.output = true,
                 "resize" => sub.resize = true,
                 "snapshot" => sub.snapshot = true,
+                "marker" => sub.marker = true,
+                "export" => sub.export = true,
+                "title" => sub.title = true,
+                "cwd" => sub.cwd = true,
+                "notification" => sub.notification = true,
+                "commandStarted" => sub.command_started = true,
+                "commandFinished" => sub.command_finished = true,
+                "clipboard" => sub.clipboard = true,
                 _ => return Err(format!("invalid event name: {event}")),
             }
         }
@@ -29,3 +71,25 @@ impl FromStr for Subscription {
         Ok(sub)
     }
 }
+
+impl Subscription {
+    pub fn includes(&self, event: &session::Event) -> bool {
+        use session::Event::*;
+
+        match event {
+            Init(_, _, _, _, _, _, _) => self.init,
+            Output(_, _) => self.output,
+            Resize(_, _, _) => self.resize,
+            Snapshot(_, _, _, _, _) => self.snapshot,
+            Marker(_, _) => self.marker,
+            Export(_, _) => self.export,
+            Title(_, _) => self.title,
+            Cwd(_, _) => self.cwd,
+            Notification(_, _, _) => self.notification,
+            CommandStarted(_, _) => self.command_started,
+            CommandFinished(_, _) => self.command_finished,
+            Clipboard(_, _, _) => self.clipboard,
+            Input(_, _) | Exit(_, _) => false,
+        }
+    }
+}
@@ -0,0 +1,331 @@
+/// Authentication and access control for the HTTP server
+use anyhow::{bail, Result};
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::prelude::*;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// What a client is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// Watching the terminal: the live preview, event streams and snapshots
+    Read,
+    /// Everything, including sending commands (and so input) to the terminal
+    Control,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    /// Bearer tokens, and the scope each of them grants
+    tokens: Vec<(String, Scope)>,
+    /// Basic auth credentials (`user:password`), granting control
+    basic: Option<String>,
+    /// Origins allowed to send requests, any origin when empty
+    origins: Vec<String>,
+    /// Control token generated when no credentials are given, read access
+    /// staying open
+    generated: Option<String>,
+}
+
+/// Scope required by a group of routes
+#[derive(Clone)]
+pub struct Guard {
+    auth: Arc<AuthConfig>,
+    scope: Scope,
+}
+
+impl AuthConfig {
+    pub fn add_token(&mut self, token: String, scope: Scope) {
+        self.tokens.push((token, scope));
+    }
+
+    pub fn set_basic(&mut self, credentials: &str) -> Result<()> {
+        match credentials.split_once(':') {
+            Some((user, password)) if !user.is_empty() && !password.is_empty() => {
+                self.basic = Some(credentials.to_string());
+                Ok(())
+            }
+
+            _ => bail!("basic auth credentials must be given as USER:PASSWORD"),
+        }
+    }
+
+    pub fn allow_origin(&mut self, origin: String) {
+        self.origins.push(origin.trim_end_matches('/').to_string());
+    }
+
+    /// Protects control with `token`, usually a random one, when no
+    /// credentials are given, so that pages open in a browser can't type into
+    /// the terminal
+    pub fn default_control_token(&mut self, token: String) {
+        if !self.is_enabled() {
+            self.generated = Some(token);
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty() || self.basic.is_some()
+    }
+
+    /// Refuses to expose the server beyond the local machine without
+    /// authentication
+    pub fn check_addr(&self, addr: SocketAddr) -> Result<()> {
+        if !addr.ip().is_loopback() && !self.is_enabled() {
+            bail!(
+                "refusing to listen on {addr} without authentication \
+                 (use --auth-token, --read-token or --basic-auth)"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the token to put in the live preview URL, the least
+    /// privileged one
+    pub fn preview_token(&self) -> Option<&str> {
+        self.tokens
+            .iter()
+            .min_by_key(|(_, scope)| *scope)
+            .map(|(token, _)| token.as_str())
+    }
+
+    pub fn print_tokens(&self) {
+        for (token, scope) in &self.tokens {
+            match scope {
+                Scope::Read => eprintln!("read-only token: {token}"),
+                Scope::Control => eprintln!("auth token: {token}"),
+            }
+        }
+
+        if let Some(token) = &self.generated {
+            eprintln!("auth token: {token}");
+        }
+    }
+
+    pub fn guard(self: &Arc<Self>, scope: Scope) -> Guard {
+        Guard {
+            auth: self.clone(),
+            scope,
+        }
+    }
+
+    /// Returns the scope granted to the request's credentials
+    ///
+    /// Tokens are taken from the `Authorization: Bearer` header, from the
+    /// password of basic auth not matching `--basic-auth`, or from the `token` query
+    /// parameter for clients which can't set headers, like browsers opening
+    /// WebSockets.
+    fn scope(&self, headers: &HeaderMap, query_token: Option<&str>) -> Option<Scope> {
+        let authorization = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+
+        // asciinema clients send their install ID as the basic auth password,
+        // with the user's name or no user
+        let install_id = authorization
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| BASE64_STANDARD.decode(encoded).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| decoded.split_once(':').map(|(_, id)| id.to_string()));
+
+        let token = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or(install_id.as_deref())
+            .or(query_token);
+
+        if !self.is_enabled() {
+            let Some(generated) = &self.generated else {
+                return Some(Scope::Control);
+            };
+
+            return match token {
+                Some(token) if constant_time_eq(token.as_bytes(), generated.as_bytes()) => {
+                    Some(Scope::Control)
+                }
+
+                _ => Some(Scope::Read),
+            };
+        }
+
+        if let (Some(credentials), Some(authorization)) = (&self.basic, authorization) {
+            if let Some(encoded) = authorization.strip_prefix("Basic ") {
+                let decoded = BASE64_STANDARD.decode(encoded).unwrap_or_default();
+
+                if constant_time_eq(&decoded, credentials.as_bytes()) {
+                    return Some(Scope::Control);
+                }
+            }
+        }
+
+        let token = token?;
+
+        self.tokens
+            .iter()
+            .filter(|(t, _)| constant_time_eq(t.as_bytes(), token.as_bytes()))
+            .map(|(_, scope)| *scope)
+            .max()
+    }
+
+    fn origin_allowed(&self, headers: &HeaderMap) -> bool {
+        if self.origins.is_empty() {
+            return true;
+        }
+
+        // Clients other than browsers don't send it
+        let Some(origin) = headers.get(header::ORIGIN) else {
+            return true;
+        };
+
+        let origin = origin.to_str().unwrap_or_default();
+
+        self.origins.iter().any(|allowed| allowed == origin)
+    }
+}
+
+/// Middleware rejecting requests without the guard's scope, and requests
+/// from origins which aren't allowed
+pub async fn require(
+    State(guard): State<Guard>,
+    Query(query): Query<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let auth = &guard.auth;
+    let headers = request.headers();
+
+    if !auth.origin_allowed(headers) {
+        return (StatusCode::FORBIDDEN, "origin not allowed").into_response();
+    }
+
+    match auth.scope(headers, query.get("token").map(String::as_str)) {
+        Some(scope) if scope >= guard.scope => next.run(request).await,
+
+        Some(_) => (StatusCode::FORBIDDEN, "insufficient scope").into_response(),
+
+        None => {
+            let challenge = if auth.basic.is_some() {
+                "Basic realm=\"ht\""
+            } else {
+                "Bearer"
+            };
+
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, challenge)],
+                "unauthorized",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Generates a random token
+pub fn generate_token() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(name: header::HeaderName, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_scopes() {
+        let mut auth = AuthConfig::default();
+        assert_eq!(auth.scope(&HeaderMap::new(), None), Some(Scope::Control));
+
+        auth.add_token("secret".to_string(), Scope::Control);
+        auth.add_token("viewer".to_string(), Scope::Read);
+        auth.set_basic("user:pass").unwrap();
+
+        assert_eq!(auth.scope(&HeaderMap::new(), None), None);
+        assert_eq!(
+            auth.scope(&HeaderMap::new(), Some("viewer")),
+            Some(Scope::Read)
+        );
+        assert_eq!(auth.scope(&HeaderMap::new(), Some("nope")), None);
+
+        let bearer = headers(header::AUTHORIZATION, "Bearer secret");
+        assert_eq!(auth.scope(&bearer, None), Some(Scope::Control));
+
+        // dXNlcjpwYXNz is user:pass
+        let basic = headers(header::AUTHORIZATION, "Basic dXNlcjpwYXNz");
+        assert_eq!(auth.scope(&basic, None), Some(Scope::Control));
+
+        let basic = headers(header::AUTHORIZATION, "Basic dXNlcjpwYXN6");
+        assert_eq!(auth.scope(&basic, None), None);
+
+        // OnNlY3JldA== is :secret, an install ID
+        let install_id = headers(header::AUTHORIZATION, "Basic OnNlY3JldA==");
+        assert_eq!(auth.scope(&install_id, None), Some(Scope::Control));
+
+        // YWxpY2U6c2VjcmV0 is alice:secret, the install ID of a logged in user
+        let install_id = headers(header::AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0");
+        assert_eq!(auth.scope(&install_id, None), Some(Scope::Control));
+
+        assert!(auth.set_basic("user").is_err());
+    }
+
+    #[test]
+    fn test_generated_control_token() {
+        let mut auth = AuthConfig::default();
+        let token = generate_token();
+        auth.default_control_token(token.clone());
+
+        assert!(!auth.is_enabled());
+        assert_eq!(auth.scope(&HeaderMap::new(), None), Some(Scope::Read));
+        assert_eq!(
+            auth.scope(&HeaderMap::new(), Some("nope")),
+            Some(Scope::Read)
+        );
+        assert_eq!(
+            auth.scope(&HeaderMap::new(), Some(&token)),
+            Some(Scope::Control)
+        );
+
+        // Not generated when credentials are given
+        let mut auth = AuthConfig::default();
+        auth.add_token("secret".to_string(), Scope::Read);
+        auth.default_control_token(generate_token());
+        assert!(auth.generated.is_none());
+    }
+
+    #[test]
+    fn test_origins() {
+        let mut auth = AuthConfig::default();
+        let origin = headers(header::ORIGIN, "https://example.com");
+        assert!(auth.origin_allowed(&origin));
+
+        auth.allow_origin("http://localhost:8000/".to_string());
+        assert!(!auth.origin_allowed(&origin));
+        assert!(auth.origin_allowed(&headers(header::ORIGIN, "http://localhost:8000")));
+        assert!(auth.origin_allowed(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_non_loopback_addr() {
+        let mut auth = AuthConfig::default();
+        assert!(auth.check_addr("127.0.0.1:0".parse().unwrap()).is_ok());
+        assert!(auth.check_addr("[::1]:0".parse().unwrap()).is_ok());
+        assert!(auth.check_addr("0.0.0.0:9999".parse().unwrap()).is_err());
+
+        auth.add_token(generate_token(), Scope::Read);
+        assert!(auth.check_addr("0.0.0.0:9999".parse().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,417 @@
+/// Control socket: the STDIO API over a Unix or TCP socket
+///
+/// Each client sends commands and receives events as JSON lines, exactly like
+/// on STDIO, with its own subscription. Clients subscribe to events with
+/// `{ "type": "subscribe", "events": "init,output" }`, which (re)starts their
+/// event stream with the session's current state. Clients falling behind
+/// catch up with a redraw of the screen, like stream viewers.
+///
+/// Anything on the machine can connect to a TCP socket, including pages open
+/// in a browser, so TCP clients must send the token first. Clients speaking
+/// some other protocol are disconnected on their first line which isn't JSON,
+/// and clients sending lines longer than `MAX_LINE_LEN` are disconnected too.
+use super::{auth, stdio, Listener, Subscription};
+use crate::command::Command;
+use crate::redact::Redactor;
+use crate::session;
+use anyhow::{bail, Result};
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::{stream, Stream, StreamExt};
+use serde_json::json;
+use std::io;
+use std::pin::Pin;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+
+/// Longest line taken from a client, in bytes
+const MAX_LINE_LEN: usize = 1024 * 1024;
+
+type Events = Pin<Box<dyn Stream<Item = session::Event> + Send>>;
+
+enum Request {
+    Subscribe(Subscription),
+    Command(Command),
+}
+
+/// Starts accepting clients, which on TCP must first send `token`, sending
+/// them events redacted with `redactor`
+pub async fn start(
+    listener: impl Into<Listener>,
+    token: String,
+    redactor: Redactor,
+    clients_tx: mpsc::Sender<session::Client>,
+    command_tx: mpsc::Sender<Command>,
+) -> Result<BoxFuture<'static, io::Result<()>>> {
+    match listener.into() {
+        Listener::Tcp(listener) => {
+            let addr = listener.local_addr()?;
+
+            // Only Unix sockets can restrict who connects
+            if !addr.ip().is_loopback() {
+                bail!("refusing to expose the control socket on {addr}, use a Unix socket instead");
+            }
+
+            eprintln!("control socket listening on {addr}");
+            eprintln!("control socket token: {token}");
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+
+            Ok(async move {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    tokio::spawn(handle_client(
+                        stream,
+                        Some(token.clone()),
+                        redactor.clone(),
+                        clients_tx.clone(),
+                        command_tx.clone(),
+                    ));
+                }
+            }
+            .boxed())
+        }
+
+        Listener::Unix(listener) => {
+            if let Some(path) = listener.local_addr()?.as_pathname() {
+                eprintln!("control socket listening on unix:{}", path.display());
+            }
+
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::UnixListener::from_std(listener)?;
+
+            Ok(async move {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    tokio::spawn(handle_client(
+                        stream,
+                        None,
+                        redactor.clone(),
+                        clients_tx.clone(),
+                        command_tx.clone(),
+                    ));
+                }
+            }
+            .boxed())
+        }
+    }
+}
+
+async fn handle_client<S>(
+    stream: S,
+    token: Option<String>,
+    redactor: Redactor,
+    clients_tx: mpsc::Sender<session::Client>,
+    command_tx: mpsc::Sender<Command>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = Lines::new(reader);
+    let mut sub = Subscription::default();
+    let mut events: Events = Box::pin(stream::pending());
+
+    if let Some(token) = token {
+        let line = lines.next_line().await.ok().flatten().unwrap_or_default();
+
+        if !auth::constant_time_eq(line.trim().as_bytes(), token.as_bytes()) {
+            write_error(&mut writer, "invalid token").await?;
+
+            return Ok(());
+        }
+    }
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+
+                    Err(e) => {
+                        write_error(&mut writer, &e.to_string()).await?;
+                        break;
+                    }
+                };
+
+                let value = match serde_json::from_str(&line) {
+                    Ok(value) => value,
+
+                    Err(e) => {
+                        write_error(&mut writer, &e.to_string()).await?;
+                        break;
+                    }
+                };
+
+                match parse_request(value) {
+                    Ok(Request::Subscribe(new_sub)) => {
+                        sub = new_sub;
+                        let resync = session::resync_stream(&clients_tx).await?;
+                        events = Box::pin(redactor.stream(resync));
+                    }
+
+                    Ok(Request::Command(command)) => command_tx.send(command).await?,
+
+                    Err(e) => write_error(&mut writer, &e).await?,
+                }
+            }
+
+            event = events.next() => {
+                match event {
+                    Some(e) if sub.includes(&e) => {
+                        writer.write_all(format!("{}\n", e.to_json()).as_bytes()).await?;
+                    }
+
+                    Some(_) => (),
+
+                    None => break
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Lines sent by a client, up to `MAX_LINE_LEN` bytes long
+struct Lines<R> {
+    reader: BufReader<R>,
+    /// The line read so far
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Lines<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Returns the next line, or `None` at the end of the stream
+    ///
+    /// Cancel safe: what's read of a line is kept for the next call.
+    async fn next_line(&mut self) -> io::Result<Option<String>> {
+        let limit = (MAX_LINE_LEN + 1 - self.buf.len()) as u64;
+        let read = (&mut self.reader)
+            .take(limit)
+            .read_until(b'\n', &mut self.buf)
+            .await?;
+
+        if self.buf.last() != Some(&b'\n') {
+            if self.buf.len() > MAX_LINE_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+            }
+
+            if read == 0 && self.buf.is_empty() {
+                return Ok(None);
+            }
+        }
+
+        let mut line = std::mem::take(&mut self.buf);
+
+        if line.last() == Some(&b'\n') {
+            line.pop();
+
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+async fn write_error(writer: &mut (impl AsyncWrite + Unpin), message: &str) -> io::Result<()> {
+    let error = json!({ "type": "error", "data": { "message": message } });
+
+    writer.write_all(format!("{error}\n").as_bytes()).await
+}
+
+fn parse_request(value: serde_json::Value) -> Result<Request, String> {
+    if value["type"] == "subscribe" {
+        let events = value["events"].as_str().unwrap_or_default();
+
+        return Ok(Request::Subscribe(events.parse()?));
+    }
+
+    stdio::build_command(value).map(Request::Command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tokio::io::AsyncBufReadExt;
+
+    #[tokio::test]
+    async fn test_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let (command_tx, mut command_rx) = mpsc::channel(1);
+        let server = start(
+            listener,
+            "secret".to_string(),
+            Redactor::default(),
+            clients_tx,
+            command_tx,
+        )
+        .await
+        .unwrap();
+        tokio::spawn(server);
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = BufReader::new(reader).lines();
+
+        writer.write_all(b"secret\n").await.unwrap();
+        writer.write_all(b"{ \"type\": \"nope\" }\n").await.unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        let error: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(error["type"], "error");
+
+        writer
+            .write_all(b"{ \"type\": \"subscribe\", \"events\": \"init,output\" }\n")
+            .await
+            .unwrap();
+
+        let mut session = Session::new(10, 2, 0);
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        let line = lines.next_line().await.unwrap().unwrap();
+        let init: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(init["type"], "init");
+
+        session.resize(12, 3);
+        session.output("a".to_string());
+        let line = lines.next_line().await.unwrap().unwrap();
+        let output: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(output["type"], "output");
+        assert_eq!(output["data"]["seq"], "a");
+
+        writer
+            .write_all(b"{ \"type\": \"input\", \"payload\": \"ls\" }\n")
+            .await
+            .unwrap();
+
+        assert!(matches!(command_rx.recv().await, Some(Command::Input(_))));
+
+        // The stream ends with the session
+        drop(session);
+        assert!(lines.next_line().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lagging_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let (command_tx, _command_rx) = mpsc::channel(1);
+        let server = start(
+            listener,
+            "secret".to_string(),
+            Redactor::default(),
+            clients_tx,
+            command_tx,
+        )
+        .await
+        .unwrap();
+        tokio::spawn(server);
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = BufReader::new(reader).lines();
+
+        writer
+            .write_all(b"secret\n{ \"type\": \"subscribe\", \"events\": \"init,output\" }\n")
+            .await
+            .unwrap();
+
+        let mut session = Session::new(10, 2, 0);
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        let line = lines.next_line().await.unwrap().unwrap();
+        let init: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(init["type"], "init");
+
+        // More events than the client's queue holds, without letting it read
+        for i in 0..2000 {
+            session.output(format!("{}\r\n", i % 10));
+        }
+
+        // The client starts over with the current screen
+        let client = tokio::time::timeout(Duration::from_secs(5), clients_rx.recv());
+        client.await.unwrap().unwrap().accept(&mut session);
+        let line = lines.next_line().await.unwrap().unwrap();
+        let output: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(output["type"], "output");
+        assert!(output["data"]["seq"].as_str().unwrap().starts_with("\x1bc"));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (clients_tx, _) = mpsc::channel(1);
+        let (command_tx, mut command_rx) = mpsc::channel(1);
+        let server = start(
+            listener,
+            "secret".to_string(),
+            Redactor::default(),
+            clients_tx,
+            command_tx,
+        )
+        .await
+        .unwrap();
+        tokio::spawn(server);
+
+        let send = |data: Vec<u8>| async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (reader, mut writer) = tokio::io::split(stream);
+            let mut lines = BufReader::new(reader).lines();
+            writer.write_all(&data).await.unwrap();
+            let line = lines.next_line().await.unwrap().unwrap();
+            let error: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(error["type"], "error");
+
+            // Disconnected without running anything
+            assert!(lines.next_line().await.unwrap().is_none());
+        };
+
+        send(b"{ \"type\": \"input\", \"payload\": \"ls\" }\n".to_vec()).await;
+        send(b"nope\n{ \"type\": \"input\", \"payload\": \"ls\" }\n".to_vec()).await;
+
+        // Other protocols are cut off at their first line
+        send(
+            b"secret\nPOST / HTTP/1.1\r\n\r\n{ \"type\": \"input\", \"payload\": \"ls\" }\n"
+                .to_vec(),
+        )
+        .await;
+
+        // Lines never ending are cut off too, before and after the token
+        send(vec![b'x'; MAX_LINE_LEN + 1]).await;
+        send([b"secret\n".to_vec(), vec![b' '; MAX_LINE_LEN + 1]].concat()).await;
+
+        assert!(command_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_non_loopback_addr() {
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+        let (clients_tx, _) = mpsc::channel(1);
+        let (command_tx, _) = mpsc::channel(1);
+
+        assert!(start(
+            listener,
+            "secret".to_string(),
+            Redactor::default(),
+            clients_tx,
+            command_tx
+        )
+        .await
+        .is_err());
+    }
+}
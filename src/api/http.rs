@@ -1,50 +1,147 @@
-use super::Subscription;
+use super::auth::{self, AuthConfig, Scope};
+use super::unix;
+use super::{Listener, Subscription};
+use crate::command::Command;
+use crate::recording::asciicast_v3::ThemeConfig;
+use crate::redact::Redactor;
+use crate::render::export::{self, ExportFormat};
+use crate::render::{Frame, Hyperlink, Palette};
 use crate::session;
-use anyhow::Result;
+use crate::streaming::alis;
+use anyhow::{bail, Result};
 use axum::{
-    extract::{connect_info::ConnectInfo, ws, Query, State},
-    http::{header, StatusCode, Uri},
-    response::IntoResponse,
-    routing::get,
-    Router,
+    extract::{ws, Path, Query, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
+use futures_util::future::{BoxFuture, FutureExt};
 use futures_util::{sink, stream, StreamExt};
 use rust_embed::RustEmbed;
 use serde::Deserialize;
 use serde_json::json;
 use std::borrow::Cow;
-use std::future::{self, Future, IntoFuture};
+use std::future::{self, IntoFuture};
 use std::io;
-use std::net::{SocketAddr, TcpListener};
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
 
 #[derive(RustEmbed)]
 #[folder = "assets/"]
 struct Assets;
 
+/// Seconds `POST /api/run` waits for the command to finish by default
+const DEFAULT_RUN_TIMEOUT: u64 = 600;
+
+/// HTTP server options
+#[derive(Default)]
+pub struct Options {
+    pub theme: Option<ThemeConfig>,
+    pub auth: AuthConfig,
+    /// Serves HTTPS (and WSS) when set
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// Masks secrets in everything served
+    pub redactor: Redactor,
+}
+
 pub async fn start(
-    listener: TcpListener,
+    listener: impl Into<Listener>,
     clients_tx: mpsc::Sender<session::Client>,
-) -> Result<impl Future<Output = io::Result<()>>> {
-    listener.set_nonblocking(true)?;
-    let listener = tokio::net::TcpListener::from_std(listener)?;
-    let addr = listener.local_addr().unwrap();
-    eprintln!("HTTP server listening on {addr}");
-    eprintln!("live preview available at http://{addr}");
-
-    let app: Router<()> = Router::new()
+    command_tx: mpsc::Sender<Command>,
+    options: Options,
+) -> Result<BoxFuture<'static, io::Result<()>>> {
+    let Options {
+        theme,
+        auth,
+        tls,
+        redactor,
+    } = options;
+    let listener = listener.into();
+    let palette = Palette::from_theme(theme.as_ref())?;
+    let alis_theme = theme.as_ref().map(alis::Theme::from);
+
+    match &listener {
+        Listener::Tcp(listener) => {
+            let addr = listener.local_addr()?;
+            auth.check_addr(addr)?;
+            let scheme = if tls.is_some() { "https" } else { "http" };
+            eprintln!("HTTP server listening on {addr}");
+            auth.print_tokens();
+
+            match auth.preview_token() {
+                Some(token) => {
+                    eprintln!("live preview available at {scheme}://{addr}/?token={token}")
+                }
+                None => eprintln!("live preview available at {scheme}://{addr}"),
+            }
+        }
+
+        Listener::Unix(listener) => {
+            if tls.is_some() {
+                bail!("TLS isn't supported on Unix sockets");
+            }
+
+            if let Some(path) = listener.local_addr()?.as_pathname() {
+                eprintln!("HTTP server listening on unix:{}", path.display());
+            }
+
+            auth.print_tokens();
+        }
+    }
+
+    let auth = Arc::new(auth);
+
+    let read = Router::new()
+        .route("/", get(static_handler))
         .route("/ws/alis", get(alis_handler))
         .route("/ws/alis-v1", get(alis_v1_handler))
         .route("/ws/events", get(event_stream_handler))
+        .route("/api/:file", get(snapshot_handler))
+        .route_layer(middleware::from_fn_with_state(
+            auth.guard(Scope::Read),
+            auth::require,
+        ));
+
+    let control = Router::new()
+        .route("/api/run", post(run_handler))
+        .route_layer(middleware::from_fn_with_state(
+            auth.guard(Scope::Control),
+            auth::require,
+        ));
+
+    let app: Router<()> = read
+        .merge(control)
+        .route("/theme.css", get(theme_css_handler))
+        .layer(Extension(palette))
+        .layer(Extension(alis_theme))
+        .layer(Extension(command_tx))
+        .layer(Extension(redactor))
         .with_state(clients_tx)
         .fallback(static_handler);
 
-    Ok(axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .into_future())
+    match (listener, tls) {
+        (Listener::Tcp(listener), Some(config)) => {
+            listener.set_nonblocking(true)?;
+            let config = RustlsConfig::from_config(config);
+            let server =
+                axum_server::from_tcp_rustls(listener, config).serve(app.into_make_service());
+
+            Ok(server.boxed())
+        }
+
+        (Listener::Tcp(listener), None) => {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+
+            Ok(axum::serve(listener, app).into_future().boxed())
+        }
+
+        (Listener::Unix(listener), _) => Ok(unix::serve(listener, app).boxed()),
+    }
 }
 
 /// ALiS protocol handler (JSON format)
@@ -53,11 +150,11 @@ pub async fn start(
 /// It allows pointing asciinema player directly to ht to get a real-time terminal preview.
 async fn alis_handler(
     ws: ws::WebSocketUpgrade,
-    ConnectInfo(_addr): ConnectInfo<SocketAddr>,
     State(clients_tx): State<mpsc::Sender<session::Client>>,
+    Extension(redactor): Extension<Redactor>,
 ) -> impl IntoResponse {
     ws.on_upgrade(move |socket| async move {
-        let _ = handle_alis_socket(socket, clients_tx).await;
+        let _ = handle_alis_socket(socket, clients_tx, redactor).await;
     })
 }
 
@@ -67,24 +164,32 @@ async fn alis_handler(
 /// It sends the magic string and binary-encoded events.
 async fn alis_v1_handler(
     ws: ws::WebSocketUpgrade,
-    ConnectInfo(_addr): ConnectInfo<SocketAddr>,
     State(clients_tx): State<mpsc::Sender<session::Client>>,
+    Extension(theme): Extension<Option<alis::Theme>>,
+    Extension(redactor): Extension<Redactor>,
 ) -> impl IntoResponse {
-    ws.protocols(["v1.alis"]).on_upgrade(move |socket| async move {
-        let _ = crate::streaming::alis_local::handle_alis_binary_socket_stateful(socket, clients_tx).await;
-    })
+    ws.protocols(["v1.alis"])
+        .on_upgrade(move |socket| async move {
+            let _ = crate::streaming::alis_local::handle_alis_binary_socket_stateful(
+                socket, clients_tx, theme, redactor,
+            )
+            .await;
+        })
 }
 
 async fn handle_alis_socket(
     socket: ws::WebSocket,
     clients_tx: mpsc::Sender<session::Client>,
+    redactor: Redactor,
 ) -> Result<()> {
     let (sink, stream) = socket.split();
     let drainer = tokio::spawn(stream.map(Ok).forward(sink::drain()));
 
-    let result = session::stream(&clients_tx)
-        .await?
-        .filter_map(alis_message)
+    // Lagging behind is caught up with, so the socket is only closed (with a
+    // close frame) when the session ends
+    let result = redactor
+        .stream(session::resync_stream(&clients_tx).await?)
+        .filter_map(|event| future::ready(alis_message(event).map(Ok)))
         .chain(stream::once(future::ready(Ok(close_message()))))
         .forward(sink)
         .await;
@@ -95,34 +200,36 @@ async fn handle_alis_socket(
     Ok(())
 }
 
-async fn alis_message(
-    event: Result<session::Event, BroadcastStreamRecvError>,
-) -> Option<Result<ws::Message, axum::Error>> {
+fn alis_message(event: session::Event) -> Option<ws::Message> {
     use session::Event::*;
 
     match event {
-        Ok(Init(time, cols, rows, _pid, seq, _text)) => Some(Ok(json_message(json!({
+        Init(time, cols, rows, _pid, seq, _text, _) => Some(json_message(json!({
             "time": time,
             "cols": cols,
             "rows": rows,
             "init": seq,
-        })))),
+        }))),
 
-        Ok(Output(time, data)) => Some(Ok(json_message(json!([time, "o", data])))),
+        Output(time, data) => Some(json_message(json!([time, "o", data]))),
 
-        Ok(Resize(time, cols, rows)) => Some(Ok(json_message(json!([
-            time,
-            "r",
-            format!("{cols}x{rows}")
-        ])))),
-
-        Ok(Marker(time, label)) => Some(Ok(json_message(json!([time, "m", label])))),
+        Resize(time, cols, rows) => {
+            Some(json_message(json!([time, "r", format!("{cols}x{rows}")])))
+        }
 
-        Ok(Exit(time, status)) => Some(Ok(json_message(json!([time, "x", status.to_string()])))),
+        Marker(time, label) => Some(json_message(json!([time, "m", label]))),
 
-        Ok(Input(_, _)) | Ok(Snapshot(_, _, _, _)) => None,
+        Exit(time, status) => Some(json_message(json!([time, "x", status.to_string()]))),
 
-        Err(e) => Some(Err(axum::Error::new(e))),
+        Input(_, _)
+        | Snapshot(_, _, _, _, _)
+        | Export(_, _)
+        | Title(_, _)
+        | Cwd(_, _)
+        | Notification(_, _, _)
+        | CommandStarted(_, _)
+        | CommandFinished(_, _)
+        | Clipboard(_, _, _) => None,
     }
 }
 
@@ -131,6 +238,14 @@ struct EventsParams {
     sub: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+struct RunArgs {
+    command: String,
+    id: Option<String>,
+    /// Seconds to wait for the command to finish
+    timeout: Option<u64>,
+}
+
 /// Event stream handler
 ///
 /// This endpoint allows the client to subscribe to selected events and have them delivered as they occur.
@@ -139,13 +254,13 @@ struct EventsParams {
 async fn event_stream_handler(
     ws: ws::WebSocketUpgrade,
     Query(params): Query<EventsParams>,
-    ConnectInfo(_addr): ConnectInfo<SocketAddr>,
     State(clients_tx): State<mpsc::Sender<session::Client>>,
+    Extension(redactor): Extension<Redactor>,
 ) -> impl IntoResponse {
     let sub: Subscription = params.sub.unwrap_or_default().parse().unwrap_or_default();
 
     ws.on_upgrade(move |socket| async move {
-        let _ = handle_event_stream_socket(socket, clients_tx, sub).await;
+        let _ = handle_event_stream_socket(socket, clients_tx, sub, redactor).await;
     })
 }
 
@@ -153,13 +268,16 @@ async fn handle_event_stream_socket(
     socket: ws::WebSocket,
     clients_tx: mpsc::Sender<session::Client>,
     sub: Subscription,
+    redactor: Redactor,
 ) -> Result<()> {
     let (sink, stream) = socket.split();
     let drainer = tokio::spawn(stream.map(Ok).forward(sink::drain()));
 
-    let result = session::stream(&clients_tx)
-        .await?
-        .filter_map(move |e| event_stream_message(e, sub))
+    // Like the ALiS sockets, catching up after lagging behind, as the output
+    // is only redacted right when it isn't missing pieces
+    let result = redactor
+        .stream(session::resync_stream(&clients_tx).await?)
+        .filter_map(move |e| future::ready(event_stream_message(e, sub)))
         .chain(stream::once(future::ready(Ok(close_message()))))
         .forward(sink)
         .await;
@@ -170,19 +288,186 @@ async fn handle_event_stream_socket(
     Ok(())
 }
 
-async fn event_stream_message(
-    event: Result<session::Event, BroadcastStreamRecvError>,
+fn event_stream_message(
+    event: session::Event,
     sub: Subscription,
 ) -> Option<Result<ws::Message, axum::Error>> {
-    use session::Event::*;
+    sub.includes(&event)
+        .then(|| Ok(json_message(event.to_json())))
+}
 
-    match event {
-        Ok(e @ Init(_, _, _, _, _, _)) if sub.init => Some(Ok(json_message(e.to_json()))),
-        Ok(e @ Output(_, _)) if sub.output => Some(Ok(json_message(e.to_json()))),
-        Ok(e @ Resize(_, _, _)) if sub.resize => Some(Ok(json_message(e.to_json()))),
-        Ok(e @ Snapshot(_, _, _, _)) if sub.snapshot => Some(Ok(json_message(e.to_json()))),
-        Ok(_) => None,
-        Err(e) => Some(Err(axum::Error::new(e))),
+/// Run command handler
+///
+/// Types a command at the shell prompt, like the `runCommand` command of the
+/// STDIO API, taking e.g. `{ "command": "make test" }`, and responds once it
+/// finishes with the data of its `commandFinished` event.
+///
+/// The body must be sent as `application/json`, which browsers can't do
+/// across origins without a preflight request, so pages open in a browser
+/// can't type into the terminal.
+async fn run_handler(
+    State(clients_tx): State<mpsc::Sender<session::Client>>,
+    Extension(command_tx): Extension<mpsc::Sender<Command>>,
+    Extension(redactor): Extension<Redactor>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if !is_json(&headers) {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "expected Content-Type: application/json",
+        )
+            .into_response();
+    }
+
+    let args: RunArgs = match serde_json::from_str(&body) {
+        Ok(args) => args,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    // Each line would run as a separate command
+    if args.command.contains(['\n', '\r']) {
+        return (StatusCode::BAD_REQUEST, "runCommand takes a single line").into_response();
+    }
+
+    let id = args
+        .id
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+
+    // Subscribed before the command is typed, so its end isn't missed
+    let finished = |e: &session::Event| matches!(e, session::Event::CommandFinished(_, _));
+
+    let Ok(events) = session::filtered_stream(&clients_tx, finished).await else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "session ended").into_response();
+    };
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let command = Command::Run(args.command, Some(id.clone()), Some(reply_tx));
+
+    if command_tx.send(command).await.is_err() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "session ended").into_response();
+    }
+
+    match reply_rx.await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => return (StatusCode::CONFLICT, e).into_response(),
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, "session ended").into_response(),
+    }
+
+    let mut events = Box::pin(events);
+
+    let finished = async {
+        while let Some(event) = events.next().await {
+            if let Ok(session::Event::CommandFinished(_, command)) = event {
+                if command.id.as_ref() == Some(&id) {
+                    return Some(command);
+                }
+            }
+        }
+
+        None
+    };
+
+    let timeout = Duration::from_secs(args.timeout.unwrap_or(DEFAULT_RUN_TIMEOUT));
+
+    match tokio::time::timeout(timeout, finished).await {
+        Ok(Some(command)) => {
+            let command = redactor.redact_command(command);
+            let body = command.to_json().to_string();
+
+            ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+        }
+
+        Ok(None) => (StatusCode::SERVICE_UNAVAILABLE, "session ended").into_response(),
+        Err(_) => (StatusCode::GATEWAY_TIMEOUT, "command still running").into_response(),
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+}
+
+/// Snapshot export handler
+///
+/// Serves the current screen as `/api/snapshot.html`, `/api/snapshot.svg` or
+/// `/api/snapshot.ansi`.
+async fn snapshot_handler(
+    Path(file): Path<String>,
+    State(clients_tx): State<mpsc::Sender<session::Client>>,
+    Extension(palette): Extension<Palette>,
+    Extension(redactor): Extension<Redactor>,
+) -> Response {
+    let format = file
+        .strip_prefix("snapshot.")
+        .and_then(|ext| ext.parse::<ExportFormat>().ok());
+
+    let Some(format) = format else {
+        return (StatusCode::NOT_FOUND, "404").into_response();
+    };
+
+    match current_screen(&clients_tx, &redactor).await {
+        Ok((vt, links)) => {
+            let mut frame = Frame::capture(&vt, &palette);
+            frame.links = links;
+            let content = export::export(&frame, &palette, format);
+
+            ([(header::CONTENT_TYPE, format.content_type())], content).into_response()
+        }
+
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response(),
+    }
+}
+
+/// Live preview theme
+///
+/// Defines the colors of the session's theme as an asciinema player theme
+/// named `ht`, so the preview looks like the exports.
+async fn theme_css_handler(Extension(palette): Extension<Palette>) -> impl IntoResponse {
+    let mut css = String::from(".asciinema-player-theme-ht {\n");
+    css.push_str(&format!(
+        "  --term-color-foreground: {};\n",
+        palette.fg.hex()
+    ));
+    css.push_str(&format!(
+        "  --term-color-background: {};\n",
+        palette.bg.hex()
+    ));
+
+    for (i, color) in palette.colors().iter().enumerate() {
+        css.push_str(&format!("  --term-color-{i}: {};\n", color.hex()));
+    }
+
+    css.push_str("}\n");
+
+    ([(header::CONTENT_TYPE, "text/css")], css)
+}
+
+/// Recreates the session's terminal from the init event of a fresh
+/// subscription, along with the links on the screen
+async fn current_screen(
+    clients_tx: &mpsc::Sender<session::Client>,
+    redactor: &Redactor,
+) -> Result<(avt::Vt, Vec<Hyperlink>)> {
+    let mut events = session::stream(clients_tx).await?;
+
+    let init = match events.next().await {
+        Some(Ok(event)) => redactor.events().apply(event).pop(),
+        _ => None,
+    };
+
+    match init {
+        Some(session::Event::Init(_time, cols, rows, _pid, seq, _text, metadata)) => {
+            let mut vt = avt::Vt::builder().size(cols, rows).build();
+            vt.feed_str(&seq);
+
+            Ok((vt, metadata.links))
+        }
+
+        _ => bail!("session is not available"),
     }
 }
 
@@ -214,3 +499,331 @@ async fn static_handler(uri: Uri) -> impl IntoResponse {
         None => (StatusCode::NOT_FOUND, "404").into_response(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::tls;
+    use crate::session::Session;
+    use std::net::TcpListener;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[tokio::test]
+    async fn test_alis_json_restart_and_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let (command_tx, _) = mpsc::channel(1);
+        let server = start(listener, clients_tx, command_tx, Options::default());
+        tokio::spawn(server.await.unwrap());
+
+        let connecting = tokio::spawn(tokio_tungstenite::connect_async(format!(
+            "ws://{addr}/ws/alis"
+        )));
+
+        let mut session = Session::new(10, 2, 0);
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        let (mut ws_stream, _) = connecting.await.unwrap().unwrap();
+
+        let mut messages = Vec::new();
+        session.output("a".to_string());
+        session.restart(12, 3, "b".to_string());
+        drop(session);
+
+        while let Some(message) = ws_stream.next().await {
+            messages.push(message.unwrap());
+        }
+
+        let json: Vec<serde_json::Value> = messages[..3]
+            .iter()
+            .map(|message| serde_json::from_str(message.to_text().unwrap()).unwrap())
+            .collect();
+
+        assert_eq!(
+            (&json[0]["cols"], &json[0]["rows"]),
+            (&json!(10), &json!(2))
+        );
+        assert_eq!((&json[1][1], &json[1][2]), (&json!("o"), &json!("a")));
+
+        // A restart is a new init, with the screen drawn from scratch
+        assert_eq!(
+            (&json[2]["cols"], &json[2]["rows"]),
+            (&json!(12), &json!(3))
+        );
+        assert!(json[2]["init"].as_str().unwrap().contains('b'));
+
+        // The end of the session closes the socket with a close frame
+        let Message::Close(Some(frame)) = &messages[3] else {
+            panic!("expected close frame");
+        };
+
+        assert_eq!(frame.code, CloseCode::Normal);
+        assert_eq!(frame.reason, "ended");
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_redaction() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let (command_tx, _) = mpsc::channel(1);
+
+        let options = Options {
+            redactor: Redactor::new(&["secret".to_string()], &[], &[]).unwrap(),
+            ..Options::default()
+        };
+
+        let server = start(listener, clients_tx, command_tx, options);
+        tokio::spawn(server.await.unwrap());
+
+        let connecting = tokio::spawn(tokio_tungstenite::connect_async(format!(
+            "ws://{addr}/ws/events?sub=init,output,title"
+        )));
+
+        let mut session = Session::new(20, 2, 0);
+        session.output("a secret".to_string());
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        let (mut ws_stream, _) = connecting.await.unwrap().unwrap();
+
+        session.output("\x1b]2;my secret\x07".to_string());
+        session.output("another secret".to_string());
+        drop(session);
+
+        let mut messages = Vec::new();
+
+        while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            messages.push(text);
+        }
+
+        let all = messages.join("\n");
+        assert!(!all.contains("secret"), "leaked in {all}");
+        assert!(messages[0].contains("a ******"));
+        assert!(messages.iter().any(|m| m.contains("my ******")));
+
+        // Text held back is sent when the session ends
+        assert!(messages[messages.len() - 2].contains("another "));
+        assert!(messages[messages.len() - 1].contains("******"));
+    }
+
+    #[tokio::test]
+    async fn test_auth_scopes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (clients_tx, _clients_rx) = mpsc::channel(1);
+        let (command_tx, _) = mpsc::channel(1);
+        let mut auth = AuthConfig::default();
+        auth.add_token("secret".to_string(), Scope::Control);
+        auth.add_token("viewer".to_string(), Scope::Read);
+        auth.allow_origin("http://localhost:8000".to_string());
+
+        let options = Options {
+            auth,
+            ..Options::default()
+        };
+
+        tokio::spawn(
+            start(listener, clients_tx, command_tx, options)
+                .await
+                .unwrap(),
+        );
+
+        let client = reqwest::Client::new();
+        let get = |path: &str| client.get(format!("http://{addr}{path}")).send();
+
+        let response = get("/").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+
+        // Tokens are taken from the header, or from the query for clients
+        // which can't set headers
+        for token in ["viewer", "secret"] {
+            let response = client
+                .get(format!("http://{addr}/"))
+                .bearer_auth(token)
+                .send()
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        assert_eq!(
+            get("/?token=viewer").await.unwrap().status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            get("/?token=nope").await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // Only from the allowed origins, not only for WebSockets
+        let response = client
+            .get(format!("http://{addr}/?token=viewer"))
+            .header(header::ORIGIN, "https://example.com")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // Static assets need no token
+        assert_eq!(get("/theme.css").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            get("/asciinema-player.css").await.unwrap().status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_command() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let (command_tx, mut command_rx) = mpsc::channel(1);
+        let mut auth = AuthConfig::default();
+        auth.add_token("secret".to_string(), Scope::Control);
+        auth.add_token("viewer".to_string(), Scope::Read);
+
+        let options = Options {
+            auth,
+            ..Options::default()
+        };
+
+        tokio::spawn(
+            start(listener, clients_tx, command_tx, options)
+                .await
+                .unwrap(),
+        );
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{addr}/api/run");
+
+        let send = |token: &str, content_type: &str, body: &str| {
+            client
+                .post(&url)
+                .header(header::CONTENT_TYPE, content_type)
+                .bearer_auth(token)
+                .body(body.to_string())
+                .send()
+        };
+
+        let json = "application/json";
+        let command = r#"{ "command": "ls", "id": "1", "timeout": 1 }"#;
+
+        assert_eq!(
+            send("viewer", json, command).await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send("secret", "text/plain", command)
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            send("secret", json, r#"{ "command": "ls\nrm" }"#)
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let mut session = Session::new(10, 2, 0);
+        let response = tokio::spawn(send("secret", json, command));
+        clients_rx.recv().await.unwrap().accept(&mut session);
+
+        // Turned down when the command can't run
+        let Some(Command::Run(_, _, Some(reply_tx))) = command_rx.recv().await else {
+            panic!("expected runCommand");
+        };
+
+        reply_tx.send(Err("no prompt".to_string())).unwrap();
+        let response = response.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        // And given up on when it doesn't finish in time
+        let response = tokio::spawn(send("secret", json, command));
+        clients_rx.recv().await.unwrap().accept(&mut session);
+
+        let Some(Command::Run(_, _, Some(reply_tx))) = command_rx.recv().await else {
+            panic!("expected runCommand");
+        };
+
+        reply_tx.send(Ok(())).unwrap();
+        let response = response.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        // Otherwise the response tells how it went
+        let response = tokio::spawn(send("secret", json, command));
+        clients_rx.recv().await.unwrap().accept(&mut session);
+
+        let Some(Command::Run(command, id, Some(reply_tx))) = command_rx.recv().await else {
+            panic!("expected runCommand");
+        };
+
+        reply_tx.send(Ok(())).unwrap();
+        session.output("\x1b]133;A\x07$ \x1b]133;B\x07".to_string());
+        session.claim_prompt(id);
+        session.output(format!(
+            "{command}\r\n\x1b]133;C\x07out\r\n\x1b]133;D;3\x07"
+        ));
+
+        let response = response.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["id"], "1");
+        assert_eq!(body["command"], "ls");
+        assert_eq!(body["status"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_non_loopback_without_auth() {
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+        let (clients_tx, _) = mpsc::channel(1);
+        let (command_tx, _) = mpsc::channel(1);
+
+        let result = start(listener, clients_tx, command_tx, Options::default()).await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (clients_tx, _clients_rx) = mpsc::channel(1);
+        let (command_tx, _) = mpsc::channel(1);
+
+        let options = Options {
+            tls: Some(tls::self_signed(addr).unwrap()),
+            ..Options::default()
+        };
+
+        tokio::spawn(
+            start(listener, clients_tx, command_tx, options)
+                .await
+                .unwrap(),
+        );
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+
+        let response = client
+            .get(format!("https://localhost:{}/", addr.port()))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Plain HTTP isn't served
+        let response = reqwest::get(format!("http://{addr}/")).await;
+        assert!(response.is_err());
+    }
+}
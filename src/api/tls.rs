@@ -0,0 +1,97 @@
+/// TLS for the HTTP server
+use anyhow::{bail, Context, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::ServerConfig;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Loads a certificate chain and its private key from PEM files
+pub fn from_pem_files(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>> {
+    let file = File::open(cert_path)
+        .with_context(|| format!("can't open certificate file {}", cert_path.display()))?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid certificate file {}", cert_path.display()))?;
+
+    if certs.is_empty() {
+        bail!("no certificates in {}", cert_path.display());
+    }
+
+    let file = File::open(key_path)
+        .with_context(|| format!("can't open key file {}", key_path.display()))?;
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("invalid key file {}", key_path.display()))?
+        .with_context(|| format!("no private key in {}", key_path.display()))?;
+
+    server_config(certs, key)
+}
+
+/// Generates a self-signed certificate for localhost and the address the
+/// server listens on
+pub fn self_signed(addr: SocketAddr) -> Result<Arc<ServerConfig>> {
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+
+    if !addr.ip().is_unspecified() && !addr.ip().is_loopback() {
+        names.push(addr.ip().to_string());
+    }
+
+    let certified = rcgen::generate_simple_self_signed(names)
+        .context("failed to generate self-signed certificate")?;
+
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+    server_config(vec![certified.cert.der().clone()], key.into())
+}
+
+fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("invalid certificate or key")?;
+
+    // The server only speaks HTTP/1
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_pem_files() {
+        let dir = std::env::temp_dir().join(format!("ht-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+
+        assert!(from_pem_files(&cert_path, &key_path).is_ok());
+
+        // The certificate isn't a key, and the key isn't a certificate
+        assert!(from_pem_files(&cert_path, &cert_path).is_err());
+        assert!(from_pem_files(&key_path, &key_path).is_err());
+        assert!(from_pem_files(&dir.join("missing.pem"), &key_path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
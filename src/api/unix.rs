@@ -0,0 +1,159 @@
+/// Unix domain socket transport for the HTTP server
+use anyhow::{bail, Context, Result};
+use axum::{extract::Request, Router};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use tower::ServiceExt;
+
+/// Socket file of a listener, removed when dropped
+#[derive(Debug)]
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Binds a socket only accessible to the current user
+///
+/// A socket left behind by a process which didn't exit cleanly is replaced,
+/// while one which is still being listened on is an error.
+pub fn bind(path: &Path) -> Result<(UnixListener, SocketFile)> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and isn't a socket", path.display());
+        }
+
+        if UnixStream::connect(path).is_ok() {
+            bail!("{} is already in use", path.display());
+        }
+
+        fs::remove_file(path)
+            .with_context(|| format!("can't remove stale socket {}", path.display()))?;
+    }
+
+    // The socket is created with the umask's permissions, and changing them
+    // in place would leave a moment for others to connect, so it's created
+    // in a directory only the user can enter and then moved into place
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let dir = parent.join(format!(".ht{}", std::process::id()));
+
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("can't create {}", dir.display()))?;
+
+    let listener = bind_private(&dir, path);
+    let _ = fs::remove_dir_all(&dir);
+    let listener = listener.with_context(|| format!("can't bind to {}", path.display()))?;
+
+    Ok((listener, SocketFile(path.to_path_buf())))
+}
+
+fn bind_private(dir: &Path, path: &Path) -> io::Result<UnixListener> {
+    let temp_path = dir.join("s");
+    let listener = UnixListener::bind(&temp_path)?;
+    fs::set_permissions(&temp_path, Permissions::from_mode(0o600))?;
+    fs::rename(&temp_path, path)?;
+
+    Ok(listener)
+}
+
+/// Serves the router on the listener, one connection per task
+pub async fn serve(listener: UnixListener, app: Router) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |request: Request<Incoming>| {
+                app.clone().oneshot(request)
+            });
+
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::http;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ht-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn test_bind() {
+        let dir = temp_dir("bind");
+        let path = dir.join("ht.sock");
+
+        let (listener, socket_file) = bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Nothing but the socket is left in the directory
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Listened on
+        assert!(bind(&path).is_err());
+
+        // Left behind
+        drop(listener);
+        std::mem::forget(socket_file);
+        assert!(path.exists());
+        let (_listener, socket_file) = bind(&path).unwrap();
+
+        drop(socket_file);
+        assert!(!path.exists());
+
+        fs::write(&path, "").unwrap();
+        assert!(bind(&path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let dir = temp_dir("serve");
+        let path = dir.join("ht.sock");
+        let (listener, _socket_file) = bind(&path).unwrap();
+        let (clients_tx, _clients_rx) = mpsc::channel(1);
+        let (command_tx, _command_rx) = mpsc::channel(1);
+        let options = http::Options::default();
+        let server = http::start(listener, clients_tx, command_tx, options);
+        tokio::spawn(server.await.unwrap());
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+/// Interactive client of background sessions
+use crate::tty::{self, RawMode};
+use anyhow::{bail, Context, Result};
+use nix::libc;
+use nix::unistd;
+use serde_json::json;
+use std::io::{self, Read, Write};
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::thread;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+/// Ctrl-\
+const DETACH_KEY: u8 = 0x1c;
+
+/// How an attachment ended
+pub enum Detached {
+    /// The user pressed the detach key
+    ByUser,
+    /// The session is over
+    Ended,
+}
+
+/// Connects the terminal to the session's control socket: keystrokes are sent
+/// as input and output is written to the terminal, which takes over the
+/// session's size
+pub async fn run(path: &Path) -> Result<Detached> {
+    if !unistd::isatty(libc::STDIN_FILENO)? {
+        bail!("attaching requires a terminal");
+    }
+
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("can't connect to {}", path.display()))?;
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut window_changes = signal(SignalKind::window_change())?;
+    let (input_tx, mut input_rx) = mpsc::unbounded_channel();
+    let _raw_mode = RawMode::enable(io::stdin().as_fd(), false)?;
+
+    resize(&mut writer).await?;
+    send(
+        &mut writer,
+        json!({ "type": "subscribe", "events": "init,output" }),
+    )
+    .await?;
+    thread::spawn(|| read_stdin(input_tx));
+
+    let detached = loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    break Detached::Ended;
+                };
+
+                let event: serde_json::Value = serde_json::from_str(&line)?;
+                let seq = event["data"]["seq"].as_str().unwrap_or_default();
+
+                match event["type"].as_str() {
+                    // The screen as it is, from a clean terminal
+                    Some("init") => write_stdout(&format!("\x1bc{seq}"))?,
+                    Some("output") => write_stdout(seq)?,
+                    _ => (),
+                }
+            }
+
+            data = input_rx.recv() => {
+                let Some(data) = data else {
+                    break Detached::ByUser;
+                };
+
+                let (data, detach) = match data.iter().position(|b| *b == DETACH_KEY) {
+                    Some(i) => (&data[..i], true),
+                    None => (&data[..], false),
+                };
+
+                if !data.is_empty() {
+                    let payload = String::from_utf8_lossy(data);
+                    send(&mut writer, json!({ "type": "input", "payload": payload })).await?;
+                }
+
+                if detach {
+                    break Detached::ByUser;
+                }
+            }
+
+            _ = window_changes.recv() => {
+                resize(&mut writer).await?;
+            }
+        }
+    };
+
+    write_stdout("\x1bc")?;
+
+    Ok(detached)
+}
+
+async fn send(writer: &mut (impl AsyncWriteExt + Unpin), command: serde_json::Value) -> Result<()> {
+    writer.write_all(format!("{command}\n").as_bytes()).await?;
+
+    Ok(())
+}
+
+/// Resizes the session to the terminal's size, when known
+async fn resize(writer: &mut (impl AsyncWriteExt + Unpin)) -> Result<()> {
+    let Some((cols, rows)) = tty::size(io::stdout().as_fd()) else {
+        return Ok(());
+    };
+
+    send(
+        writer,
+        json!({ "type": "resize", "cols": cols, "rows": rows }),
+    )
+    .await
+}
+
+fn write_stdout(data: &str) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(data.as_bytes())?;
+    stdout.flush()
+}
+
+fn read_stdin(input_tx: mpsc::UnboundedSender<Vec<u8>>) -> Result<()> {
+    let mut buf = [0; 1024];
+    let mut incomplete = Vec::new();
+
+    loop {
+        let n = io::stdin().read(&mut buf)?;
+
+        if n == 0 {
+            return Ok(());
+        }
+
+        let mut data = std::mem::take(&mut incomplete);
+        data.extend_from_slice(&buf[..n]);
+        incomplete = tty::split_incomplete_utf8(&mut data);
+
+        if !data.is_empty() {
+            input_tx.send(data)?;
+        }
+    }
+}
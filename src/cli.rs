@@ -1,7 +1,16 @@
+use crate::api::auth::{self, AuthConfig, Scope};
+use crate::api::tls;
 use crate::api::Subscription;
+use crate::recording::asciicast::Format;
+use crate::recording::asciicast_v3::ThemeConfig;
+use crate::recording::output::FlushPolicy;
+use crate::redact::{Preset, Redactor};
+use crate::shell::Shell;
+use crate::theme;
 use anyhow::bail;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use nix::pty;
+use std::sync::{Arc, OnceLock};
 use std::{fmt::Display, net::SocketAddr, ops::Deref, path::PathBuf, str::FromStr};
 
 #[derive(Debug, Parser)]
@@ -19,13 +28,65 @@ pub struct Cli {
     #[arg(default_value = "bash", global = true)]
     pub shell_command: Vec<String>,
 
-    /// Enable HTTP server
+    /// Load shell integration into bash, zsh or fish, marking prompts and commands
+    #[arg(long, global = true)]
+    pub shell_integration: bool,
+
+    /// Answer OSC 52 clipboard reads with the clipboard buffer, filled by copies and setClipboard
+    #[arg(long, global = true)]
+    pub clipboard_read: bool,
+
+    /// Largest clipboard text kept (e.g., 64K, 1M)
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, default_value = "1M", global = true)]
+    pub clipboard_limit: u64,
+
+    /// Enable HTTP server, on a TCP address or a Unix socket (unix:PATH)
     #[arg(short, long, value_name = "LISTEN_ADDR", default_missing_value = "127.0.0.1:0", num_args = 0..=1, global = true)]
-    pub listen: Option<SocketAddr>,
+    pub listen: Option<ListenAddr>,
+
+    /// Accept STDIO API clients on a TCP address or a Unix socket (unix:PATH)
+    #[arg(long, value_name = "LISTEN_ADDR", global = true)]
+    pub control_socket: Option<ListenAddr>,
+
+    /// Run in the background, reachable with `ht attach NAME`
+    #[arg(long, requires = "name", conflicts_with = "control_socket")]
+    pub daemon: bool,
+
+    /// Name of the background session
+    #[arg(long, value_name = "NAME", requires = "daemon")]
+    pub name: Option<String>,
+
+    /// Mirror the session on the terminal ht runs in
+    #[arg(long, conflicts_with = "daemon")]
+    pub tee_tty: bool,
+
+    /// Let the keyboard take over the mirrored session with Ctrl-\, pausing STDIO commands
+    #[arg(long, requires = "tee_tty")]
+    pub takeover: bool,
 
     /// Subscribe to events
     #[arg(long, value_name = "EVENTS", global = true)]
     pub subscribe: Option<Subscription>,
+
+    /// Record the session to an asciicast file
+    #[arg(long, value_name = "PATH")]
+    pub record: Option<PathBuf>,
+
+    /// Stream the session to an asciinema server (e.g., https://asciinema.org)
+    #[arg(long, value_name = "URL")]
+    pub stream: Option<String>,
+
+    #[command(flatten)]
+    pub recording: RecordArgs,
+
+    #[command(flatten)]
+    pub streaming: StreamArgs,
+
+    #[command(flatten)]
+    pub options: SinkArgs,
+
+    #[command(flatten)]
+    pub server: ServerArgs,
 }
 
 #[derive(Debug, Subcommand)]
@@ -36,80 +97,143 @@ pub enum CliCommand {
         #[arg(short, long, value_name = "PATH")]
         out: PathBuf,
 
-        /// Append to existing recording
+        #[command(flatten)]
+        recording: RecordArgs,
+
+        #[command(flatten)]
+        options: SinkArgs,
+    },
+
+    /// Stream a terminal session to an asciinema server
+    Stream {
+        /// Server base URL (e.g., https://asciinema.org)
+        #[arg(short, long, value_name = "URL")]
+        server: String,
+
+        #[command(flatten)]
+        streaming: StreamArgs,
+
+        #[command(flatten)]
+        options: SinkArgs,
+    },
+
+    /// Replay an asciicast file into a headless terminal
+    Replay {
+        /// Recording file path
+        #[arg(value_name = "PATH")]
+        file: PathBuf,
+
+        /// Playback speed multiplier
+        #[arg(long, value_name = "FACTOR", default_value_t = 1.0)]
+        speed: f64,
+
+        /// Apply all events immediately, without delays
         #[arg(long)]
-        append: bool,
+        instant: bool,
 
-        /// Limit recorded idle time to max seconds
+        /// Limit replayed idle time to max seconds (defaults to the recording's limit)
         #[arg(long, value_name = "SECONDS")]
         idle_time_limit: Option<f64>,
 
-        /// Recording title
-        #[arg(long, value_name = "TITLE")]
-        title: Option<String>,
+        /// Take a snapshot right after each marker
+        #[arg(long)]
+        snapshot_at_markers: bool,
 
-        /// Capture input (off by default for privacy)
+        /// Keep running after the recording ends, until STDIN is closed
         #[arg(long)]
-        capture_input: bool,
+        hold: bool,
+    },
 
-        /// Terminal type (e.g., xterm-256color)
-        #[arg(long, value_name = "TYPE")]
-        term_type: Option<String>,
+    /// Mirror an ALiS live stream into a headless terminal
+    Watch {
+        /// ALiS WebSocket URL (e.g., ws://127.0.0.1:8080/ws/alis-v1)
+        #[arg(value_name = "URL")]
+        url: String,
+    },
 
-        /// Theme: fg color (e.g., #ffffff)
-        #[arg(long, value_name = "COLOR")]
-        theme_fg: Option<String>,
+    /// Attach the terminal to a background session (detach with Ctrl-\)
+    Attach {
+        /// Session name
+        #[arg(value_name = "NAME")]
+        name: String,
+    },
 
-        /// Theme: bg color (e.g., #000000)
-        #[arg(long, value_name = "COLOR")]
-        theme_bg: Option<String>,
+    /// List background sessions
+    Ls,
 
-        /// Environment variables to capture (comma-separated, e.g., SHELL,TERM)
-        #[arg(long, value_name = "VARS")]
-        capture_env: Option<String>,
+    /// End a background session
+    Kill {
+        /// Session name
+        #[arg(value_name = "NAME")]
+        name: String,
     },
 
-    /// Stream a terminal session to an asciinema server
-    Stream {
-        /// Server base URL (e.g., https://asciinema.org)
-        #[arg(short, long, value_name = "URL")]
-        server: String,
+    /// Print the shell integration snippet, to source from a shell's startup file
+    ShellIntegration {
+        /// Shell (bash, zsh or fish)
+        #[arg(value_name = "SHELL")]
+        shell: Shell,
+    },
 
-        /// Path to install-id file
-        #[arg(long, value_name = "PATH")]
-        install_id_path: Option<PathBuf>,
+    /// Relay ALiS streams from producers (e.g. ht stream) to any number of viewers
+    Relay,
 
-        /// Install ID value (alternative to --install-id-path)
-        #[arg(long, value_name = "UUID")]
-        install_id_value: Option<String>,
+    /// Render an asciicast file to an animated GIF, APNG or SVG
+    Render {
+        /// Recording file path
+        #[arg(value_name = "PATH")]
+        file: PathBuf,
 
-        /// Stream title
-        #[arg(long, value_name = "TITLE")]
-        title: Option<String>,
+        /// Output file path
+        #[arg(short, long, value_name = "PATH")]
+        out: PathBuf,
 
-        /// Stream visibility (public, unlisted, private)
-        #[arg(long, value_name = "VISIBILITY")]
-        visibility: Option<String>,
+        /// Output format (gif, apng or svg), detected from the output file extension by default
+        #[arg(long, value_name = "FORMAT")]
+        format: Option<String>,
 
-        /// Protocol to use (alis or v3)
-        #[arg(long, value_name = "PROTOCOL", default_value = "alis")]
-        protocol: String,
+        /// Playback speed multiplier
+        #[arg(long, value_name = "FACTOR", default_value_t = 1.0)]
+        speed: f64,
 
-        /// Capture input (off by default for privacy)
-        #[arg(long)]
-        capture_input: bool,
+        /// Frame rate
+        #[arg(long, value_name = "FPS", default_value_t = 30.0)]
+        fps: f64,
 
-        /// Terminal type (e.g., xterm-256color)
-        #[arg(long, value_name = "TYPE")]
-        term_type: Option<String>,
+        /// Limit idle time to max seconds (defaults to the recording's limit)
+        #[arg(long, value_name = "SECONDS")]
+        idle_time_limit: Option<f64>,
 
-        /// Theme: fg color (e.g., #ffffff)
-        #[arg(long, value_name = "COLOR")]
-        theme_fg: Option<String>,
+        /// How long to show the last frame for
+        #[arg(long, value_name = "SECONDS", default_value_t = 3.0)]
+        last_frame_duration: f64,
 
-        /// Theme: bg color (e.g., #000000)
-        #[arg(long, value_name = "COLOR")]
-        theme_bg: Option<String>,
+        /// Pause for this long at each marker
+        #[arg(long, value_name = "SECONDS", default_value_t = 0.0)]
+        marker_pause: f64,
+
+        /// Render only the chapter starting at the marker with this label
+        #[arg(long, value_name = "LABEL")]
+        chapter: Option<String>,
+
+        /// Font size in pixels
+        #[arg(long, value_name = "PX", default_value_t = 14.0, value_parser = parse_font_size)]
+        font_size: f64,
+    },
+
+    /// Convert an asciicast file between v2 and v3
+    Convert {
+        /// Input file path
+        #[arg(value_name = "INPUT")]
+        input: PathBuf,
+
+        /// Output file path
+        #[arg(value_name = "OUTPUT")]
+        output: PathBuf,
+
+        /// Output format (v2 or v3), defaults to the other version than the input's
+        #[arg(long, value_name = "FORMAT")]
+        format: Option<Format>,
     },
 }
 
@@ -119,6 +243,246 @@ impl Cli {
     }
 }
 
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Recording")]
+pub struct RecordArgs {
+    /// Recording format (v2 or v3)
+    #[arg(long, value_name = "FORMAT", default_value = "v3")]
+    pub format: Format,
+
+    /// Append to existing recording
+    #[arg(long)]
+    pub append: bool,
+
+    /// Limit recorded idle time to max seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub idle_time_limit: Option<f64>,
+
+    /// Environment variables to capture (comma-separated, e.g., SHELL,TERM)
+    #[arg(long, value_name = "VARS")]
+    pub capture_env: Option<String>,
+
+    /// Rotate into numbered segments after this much data (e.g., 500K, 100M, 1G)
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    pub rotate_size: Option<u64>,
+
+    /// Rotate into numbered segments after this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub rotate_time: Option<f64>,
+
+    /// When to flush the file: after every event, on markers, or every N seconds
+    #[arg(long, value_name = "event|marker|SECONDS", default_value = "event")]
+    pub flush: FlushPolicy,
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Streaming")]
+pub struct StreamArgs {
+    /// Path to install-id file
+    #[arg(long, value_name = "PATH")]
+    pub install_id_path: Option<PathBuf>,
+
+    /// Install ID value (alternative to --install-id-path)
+    #[arg(long, value_name = "UUID")]
+    pub install_id_value: Option<String>,
+
+    /// Stream visibility (public, unlisted, private)
+    #[arg(long, value_name = "VISIBILITY")]
+    pub visibility: Option<String>,
+
+    /// Protocol to use (alis or v3)
+    #[arg(long, value_name = "PROTOCOL", default_value = "alis")]
+    pub protocol: String,
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "HTTP server")]
+pub struct ServerArgs {
+    /// Require this bearer token, granting full control (generated if no value is given)
+    #[arg(long, value_name = "TOKEN", num_args = 0..=1, global = true)]
+    auth_token: Option<Option<String>>,
+
+    /// Also accept this bearer token, granting read-only access (generated if no value is given)
+    #[arg(long, value_name = "TOKEN", num_args = 0..=1, global = true)]
+    read_token: Option<Option<String>>,
+
+    /// Require basic auth with these credentials, granting full control
+    #[arg(long, value_name = "USER:PASSWORD", global = true)]
+    basic_auth: Option<String>,
+
+    /// Only allow requests from browser pages of this origin (can be repeated)
+    #[arg(long, value_name = "ORIGIN", global = true)]
+    allow_origin: Vec<String>,
+
+    /// Serve HTTPS with this certificate (PEM, may include the chain)
+    #[arg(long, value_name = "PATH", requires = "tls_key", global = true)]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key of the certificate (PEM)
+    #[arg(long, value_name = "PATH", requires = "tls_cert", global = true)]
+    tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS with a generated self-signed certificate
+    #[arg(long, conflicts_with = "tls_cert", global = true)]
+    tls_self_signed: bool,
+
+    /// Control token, resolved once for the HTTP server and the control socket
+    #[arg(skip)]
+    control_token: OnceLock<String>,
+}
+
+/// Options shared by the recording and the stream
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Recording and streaming")]
+pub struct SinkArgs {
+    /// Recording and stream title
+    #[arg(long, value_name = "TITLE")]
+    pub title: Option<String>,
+
+    /// Capture input (off by default for privacy)
+    #[arg(long)]
+    pub capture_input: bool,
+
+    /// Terminal type (e.g., xterm-256color)
+    #[arg(long, value_name = "TYPE")]
+    pub term_type: Option<String>,
+
+    /// Theme: built-in (asciinema, dracula, monokai, nord, solarized-dark, solarized-light, tango) or path to a theme file
+    #[arg(long, value_name = "NAME|PATH")]
+    pub theme: Option<String>,
+
+    /// Theme: fg color (e.g., #ffffff)
+    #[arg(long, value_name = "COLOR")]
+    pub theme_fg: Option<String>,
+
+    /// Theme: bg color (e.g., #000000)
+    #[arg(long, value_name = "COLOR")]
+    pub theme_bg: Option<String>,
+
+    /// Theme: 8 or 16 colors (colon-separated, e.g., #000000:#dd3c69:...)
+    #[arg(long, value_name = "COLORS")]
+    pub theme_palette: Option<String>,
+
+    #[command(flatten)]
+    pub redact: RedactArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct RedactArgs {
+    /// Mask text matching this regex, or just its first capture group (can be repeated)
+    #[arg(long, value_name = "REGEX")]
+    redact: Vec<String>,
+
+    /// Mask common secret formats (comma-separated: aws, github, slack, jwt, api-key, password)
+    #[arg(long, value_name = "PRESETS", value_delimiter = ',')]
+    redact_preset: Vec<Preset>,
+
+    /// Mask values of these environment variables (comma-separated)
+    #[arg(long, value_name = "VARS", value_delimiter = ',')]
+    redact_env: Vec<String>,
+}
+
+impl SinkArgs {
+    pub fn theme(&self) -> anyhow::Result<Option<ThemeConfig>> {
+        theme::from_options(
+            self.theme.as_deref(),
+            self.theme_fg.as_deref(),
+            self.theme_bg.as_deref(),
+            self.theme_palette.as_deref(),
+        )
+    }
+}
+
+impl ServerArgs {
+    /// Returns the token granting control, the one given with `--auth-token`
+    /// or else one generated the first time, so that the HTTP server and the
+    /// control socket share it
+    pub fn control_token(&self) -> String {
+        self.control_token
+            .get_or_init(|| {
+                self.auth_token
+                    .clone()
+                    .flatten()
+                    .unwrap_or_else(auth::generate_token)
+            })
+            .clone()
+    }
+
+    pub fn auth(&self, addr: &ListenAddr) -> anyhow::Result<AuthConfig> {
+        let mut auth = AuthConfig::default();
+
+        if self.auth_token.is_some() {
+            auth.add_token(self.control_token(), Scope::Control);
+        }
+
+        if let Some(token) = &self.read_token {
+            let token = token.clone().unwrap_or_else(auth::generate_token);
+            auth.add_token(token, Scope::Read);
+        }
+
+        if let Some(credentials) = &self.basic_auth {
+            auth.set_basic(credentials)?;
+        }
+
+        for origin in &self.allow_origin {
+            auth.allow_origin(origin.clone());
+        }
+
+        // Unix sockets are only accessible to the user, and browsers can't
+        // reach them
+        if let ListenAddr::Tcp(_) = addr {
+            auth.default_control_token(self.control_token());
+        }
+
+        Ok(auth)
+    }
+
+    pub fn tls(&self, addr: &ListenAddr) -> anyhow::Result<Option<Arc<rustls::ServerConfig>>> {
+        let enabled = self.tls_cert.is_some() || self.tls_self_signed;
+
+        let addr = match addr {
+            ListenAddr::Tcp(addr) => *addr,
+            ListenAddr::Unix(_) if enabled => bail!("TLS isn't supported on Unix sockets"),
+            ListenAddr::Unix(_) => return Ok(None),
+        };
+
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            return tls::from_pem_files(cert, key).map(Some);
+        }
+
+        if self.tls_self_signed {
+            eprintln!("using a self-signed certificate, which clients have to be told to trust");
+            return tls::self_signed(addr).map(Some);
+        }
+
+        Ok(None)
+    }
+}
+
+impl RedactArgs {
+    pub fn redactor(&self) -> anyhow::Result<Redactor> {
+        Redactor::new(&self.redact, &self.redact_preset, &self.redact_env)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => bail!("missing socket path in {s}"),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => Ok(ListenAddr::Tcp(s.parse()?)),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Size(pty::Winsize);
 
@@ -171,3 +535,48 @@ impl Display for Size {
         write!(f, "{}x{}", self.0.ws_col, self.0.ws_row)
     }
 }
+
+pub fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size unit: {unit}")),
+    };
+
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("size too large: {s}")),
+        _ => Err(format!("invalid size: {s}")),
+    }
+}
+
+fn parse_font_size(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(size) if size > 0.0 && size.is_finite() => Ok(size),
+        _ => Err(format!("invalid font size: {s}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_control_token() {
+        let cli = Cli::try_parse_from(["ht", "--auth-token"]).unwrap();
+        let addr = ListenAddr::Tcp(([127, 0, 0, 1], 0).into());
+        let auth = cli.server.auth(&addr).unwrap();
+
+        let token = cli.server.control_token();
+
+        assert_eq!(auth.preview_token(), Some(token.as_str()));
+    }
+}
@@ -1,9 +1,28 @@
+use crate::recording::asciicast_v3::RecorderConfig;
+use crate::render::export::ExportFormat;
+use std::path::PathBuf;
+use tokio::sync::oneshot;
+
 #[derive(Debug)]
 pub enum Command {
     Input(Vec<InputSeq>),
     Snapshot,
     Resize(usize, usize),
     Marker(String),
+    Export(ExportFormat),
+    /// Types a command at the shell prompt, with an optional id, telling
+    /// through the reply channel, if any, whether the command was queued
+    Run(
+        String,
+        Option<String>,
+        Option<oneshot::Sender<Result<(), String>>>,
+    ),
+    /// Fills the clipboard buffer read with OSC 52
+    SetClipboard(String),
+    StartRecording(Box<RecorderConfig>),
+    StopRecording(PathBuf),
+    PauseRecording(PathBuf),
+    ResumeRecording(PathBuf),
 }
 
 #[derive(Debug, PartialEq)]
@@ -0,0 +1,276 @@
+/// Background sessions, reachable through control sockets in a per-user
+/// directory
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait::{self, WaitPidFlag, WaitStatus};
+use nix::unistd::{self, ForkResult, Pid};
+use std::env;
+use std::fs::{self, DirBuilder, File};
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a process ignoring a signal gets before a stronger one
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// State of a running session, as of its init event
+#[derive(Debug)]
+pub struct Info {
+    pub cols: u64,
+    pub rows: u64,
+    pub pid: i32,
+}
+
+/// Returns the directory of the current user's sessions
+pub fn dir() -> Result<PathBuf> {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("ht"),
+        None => env::temp_dir().join(format!("ht-{}", unistd::getuid())),
+    };
+
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("can't create {}", dir.display()))?;
+
+    // Anyone could have created it in a shared temp dir
+    if fs::metadata(&dir)?.uid() != unistd::getuid().as_raw() {
+        bail!("{} isn't owned by the current user", dir.display());
+    }
+
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+
+    Ok(dir)
+}
+
+/// Returns the control socket path of a session
+pub fn socket_path(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        bail!("invalid session name: {name:?}");
+    }
+
+    Ok(dir()?.join(format!("{name}.sock")))
+}
+
+/// Forks into the background, returning the control socket path to listen on
+/// in the daemon
+///
+/// The parent waits for the daemon to listen before exiting, so the session
+/// can be attached to right away. This must be called before starting any
+/// threads.
+pub fn daemonize(name: &str) -> Result<PathBuf> {
+    let path = socket_path(name)?;
+
+    if UnixStream::connect(&path).is_ok() {
+        bail!("session {name} already exists");
+    }
+
+    let log_path = path.with_extension("log");
+    let log = File::create(&log_path)
+        .with_context(|| format!("can't create log file {}", log_path.display()))?;
+
+    match unsafe { unistd::fork() }? {
+        ForkResult::Parent { child } => {
+            wait_for_daemon(child, &path, &log_path)?;
+            eprintln!("session {name} started, attach with: ht attach {name}");
+            std::process::exit(0);
+        }
+
+        ForkResult::Child => {
+            unistd::setsid()?;
+            let null = File::open("/dev/null")?;
+            unistd::dup2(null.as_raw_fd(), 0)?;
+            unistd::dup2(log.as_raw_fd(), 1)?;
+            unistd::dup2(log.as_raw_fd(), 2)?;
+
+            Ok(path)
+        }
+    }
+}
+
+fn wait_for_daemon(child: Pid, path: &Path, log_path: &Path) -> Result<()> {
+    let start = Instant::now();
+
+    while UnixStream::connect(path).is_err() {
+        let status = wait::waitpid(child, Some(WaitPidFlag::WNOHANG))?;
+
+        if status != WaitStatus::StillAlive || start.elapsed() > TIMEOUT {
+            bail!("session failed to start, see {}", log_path.display());
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Ok(())
+}
+
+/// Asks a session for its state
+pub fn query(path: &Path) -> Result<Info> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.write_all(b"{ \"type\": \"subscribe\", \"events\": \"init\" }\n")?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let init: serde_json::Value = serde_json::from_str(&line)?;
+    let data = &init["data"];
+
+    match (
+        data["cols"].as_u64(),
+        data["rows"].as_u64(),
+        data["pid"].as_i64(),
+    ) {
+        (Some(cols), Some(rows), Some(pid)) => Ok(Info {
+            cols,
+            rows,
+            pid: pid as i32,
+        }),
+
+        _ => bail!("unexpected reply: {line}"),
+    }
+}
+
+/// Lists the running sessions
+pub fn list() -> Result<Vec<(String, Info)>> {
+    let mut sessions = Vec::new();
+
+    for entry in fs::read_dir(dir()?)? {
+        let path = entry?.path();
+
+        if path.extension().is_some_and(|ext| ext == "sock") {
+            // Sockets of sessions which didn't exit cleanly are skipped
+            if let Ok(info) = query(&path) {
+                let name = path.file_stem().unwrap().to_string_lossy().to_string();
+                sessions.push((name, info));
+            }
+        }
+    }
+
+    sessions.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok(sessions)
+}
+
+/// Ends a session by hanging up its process, like closing a terminal window
+pub fn kill(name: &str) -> Result<()> {
+    let path = socket_path(name)?;
+    let info = query(&path).with_context(|| format!("no session named {name}"))?;
+
+    if !end(Pid::from_raw(info.pid), &path)? {
+        bail!("session {name} didn't exit");
+    }
+
+    Ok(())
+}
+
+/// Signals the process until the session's socket is gone, terminating and
+/// then killing it when it ignores the previous signal for a while
+fn end(pid: Pid, path: &Path) -> Result<bool> {
+    let signals = [
+        (Signal::SIGHUP, KILL_GRACE),
+        (Signal::SIGTERM, KILL_GRACE),
+        (Signal::SIGKILL, TIMEOUT),
+    ];
+
+    for (sig, grace) in signals {
+        match signal::kill(pid, sig) {
+            // It may have exited meanwhile, with the session on its way out
+            Ok(()) | Err(Errno::ESRCH) => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        let start = Instant::now();
+
+        while path.exists() && start.elapsed() < grace {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        if !path.exists() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{control, unix};
+    use crate::redact::Redactor;
+    use crate::session::Session;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_socket_path() {
+        assert!(socket_path("").is_err());
+        assert!(socket_path("../foo").is_err());
+        assert!(socket_path(".foo").is_err());
+        assert_eq!(socket_path("foo").unwrap(), dir().unwrap().join("foo.sock"));
+    }
+
+    #[test]
+    fn test_end_escalates() {
+        let dir = env::temp_dir().join(format!("ht-daemon-end-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("foo.sock");
+        fs::write(&path, "").unwrap();
+
+        // A program ignoring hangups and terminations, whose session goes
+        // away with it
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "trap '' HUP TERM; while :; do sleep 0.01; done"])
+            .spawn()
+            .unwrap();
+
+        let pid = Pid::from_raw(child.id() as i32);
+        thread::sleep(Duration::from_millis(100));
+        let socket_path = path.clone();
+
+        let session = thread::spawn(move || {
+            child.wait().unwrap();
+            fs::remove_file(socket_path).unwrap();
+        });
+
+        assert!(end(pid, &path).unwrap());
+        session.join().unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let dir = env::temp_dir().join(format!("ht-daemon-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("foo.sock");
+        let (listener, _socket_file) = unix::bind(&path).unwrap();
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let (command_tx, _command_rx) = mpsc::channel(1);
+        let server = control::start(
+            listener,
+            String::new(),
+            Redactor::default(),
+            clients_tx,
+            command_tx,
+        );
+        tokio::spawn(server.await.unwrap());
+
+        let query_path = path.clone();
+        let info = tokio::task::spawn_blocking(move || query(&query_path));
+        let mut session = Session::new(10, 2, 1234);
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        let info = info.await.unwrap().unwrap();
+
+        assert_eq!((info.cols, info.rows, info.pid), (10, 2, 1234));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,115 +1,241 @@
 mod api;
+mod attach;
 mod cli;
 mod command;
+mod daemon;
 mod locale;
+mod mirror;
 mod nbio;
 mod pty;
 mod recording;
+mod redact;
+mod render;
+mod replay;
+mod scan;
 mod session;
+mod shell;
 mod streaming;
+mod theme;
+mod tty;
 
 use anyhow::{Context, Result};
-use cli::{Cli, CliCommand};
+use cli::{Cli, CliCommand, ListenAddr, RecordArgs, SinkArgs, StreamArgs};
 use command::Command;
+use futures_util::{stream, Stream, StreamExt};
+use recording::asciicast::{Asciicast, Encoder, EventData, Format};
 use recording::asciicast_v3::{AsciicastV3Recorder, RecorderConfig, ThemeConfig};
+use recording::manager::Recordings;
+use redact::Redactor;
+use render::{Palette, RenderConfig};
+use replay::{Player, PlayerConfig};
 use session::Session;
-use std::net::{SocketAddr, TcpListener};
-use streaming::asciinema_server::{AsciinemaServerStreamer, StreamProtocol, StreamerConfig};
-use tokio::sync::{mpsc, oneshot};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::future;
+use std::io::{BufWriter, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use streaming::alis::AlisEvent;
+use streaming::alis_client;
+use streaming::asciinema_server::{
+    AsciinemaServerStreamer, ReconnectConfig, StreamProtocol, StreamerConfig,
+};
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::task::JoinHandle;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     locale::check_utf8_locale()?;
-    let cli = Cli::new();
+    let mut cli = Cli::new();
 
+    // Forking has to happen before the runtime starts its threads
+    if let Some(name) = &cli.name {
+        let path = daemon::daemonize(name)?;
+        cli.control_socket = Some(ListenAddr::Unix(path));
+    }
+
+    tokio::runtime::Runtime::new()?.block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> Result<()> {
     match &cli.command {
         Some(CliCommand::Record {
             out,
-            append,
-            idle_time_limit,
-            title,
-            capture_input,
-            term_type,
-            theme_fg,
-            theme_bg,
-            capture_env,
+            recording,
+            options,
         }) => {
-            run_record_mode(
-                &cli,
-                out.clone(),
-                *append,
-                *idle_time_limit,
-                title.clone(),
-                *capture_input,
-                term_type.clone(),
-                theme_fg.clone(),
-                theme_bg.clone(),
-                capture_env.clone(),
-            )
-            .await
+            let sinks = SinkConfig::new(&cli, Some((out, recording)), None, options)?;
+
+            run_session_mode(&cli, sinks).await
         }
 
         Some(CliCommand::Stream {
             server,
-            install_id_path,
-            install_id_value,
-            title,
-            visibility,
-            protocol,
-            capture_input,
-            term_type,
-            theme_fg,
-            theme_bg,
+            streaming,
+            options,
+        }) => {
+            let sinks = SinkConfig::new(&cli, None, Some((server, streaming)), options)?;
+
+            run_session_mode(&cli, sinks).await
+        }
+
+        Some(CliCommand::Replay {
+            file,
+            speed,
+            instant,
+            idle_time_limit,
+            snapshot_at_markers,
+            hold,
         }) => {
-            run_stream_mode(
+            run_replay_mode(
                 &cli,
-                server.clone(),
-                install_id_path.clone(),
-                install_id_value.clone(),
-                title.clone(),
-                visibility.clone(),
-                protocol.clone(),
-                *capture_input,
-                term_type.clone(),
-                theme_fg.clone(),
-                theme_bg.clone(),
+                file.clone(),
+                *speed,
+                *instant,
+                *idle_time_limit,
+                *snapshot_at_markers,
+                *hold,
             )
             .await
         }
 
-        None => run_normal_mode(&cli).await,
+        Some(CliCommand::Watch { url }) => run_watch_mode(&cli, url).await,
+
+        Some(CliCommand::Attach { name }) => run_attach_mode(name).await,
+
+        Some(CliCommand::Ls) => run_ls_mode(),
+
+        Some(CliCommand::Kill { name }) => daemon::kill(name),
+
+        Some(CliCommand::ShellIntegration { shell }) => {
+            print!("{}", shell.snippet());
+
+            Ok(())
+        }
+
+        Some(CliCommand::Relay) => run_relay_mode(&cli).await,
+
+        Some(CliCommand::Render {
+            file,
+            out,
+            format,
+            speed,
+            fps,
+            idle_time_limit,
+            last_frame_duration,
+            marker_pause,
+            chapter,
+            font_size,
+        }) => {
+            let config = RenderConfig {
+                speed: *speed,
+                fps: *fps,
+                idle_time_limit: *idle_time_limit,
+                last_frame_duration: *last_frame_duration,
+                marker_pause: *marker_pause,
+                chapter: chapter.clone(),
+            };
+
+            run_render_mode(file, out, format.as_deref(), config, *font_size)
+        }
+
+        Some(CliCommand::Convert {
+            input,
+            output,
+            format,
+        }) => run_convert_mode(input, output, *format),
+
+        None => {
+            let sinks = SinkConfig::new(
+                &cli,
+                cli.record.as_deref().map(|path| (path, &cli.recording)),
+                cli.stream.as_deref().map(|url| (url, &cli.streaming)),
+                &cli.options,
+            )?;
+
+            run_session_mode(&cli, sinks).await
+        }
     }
 }
 
-async fn run_record_mode(
-    cli: &Cli,
-    output_path: std::path::PathBuf,
-    append: bool,
-    idle_time_limit: Option<f64>,
-    title: Option<String>,
+/// Recording and streaming requested on the command line
+struct SinkConfig {
+    recording: Option<RecorderConfig>,
+    stream: Option<StreamerConfig>,
+    theme: Option<ThemeConfig>,
     capture_input: bool,
-    term_type: Option<String>,
-    theme_fg: Option<String>,
-    theme_bg: Option<String>,
-    capture_env: Option<String>,
-) -> Result<()> {
-    let (input_tx, input_rx) = mpsc::channel(1024);
-    let (output_tx, output_rx) = mpsc::channel(1024);
-    let (command_tx, command_rx) = mpsc::channel(1024);
-    let (clients_tx, clients_rx) = mpsc::channel(1);
+    /// Applied to the HTTP server and the control socket as well
+    redactor: Redactor,
+}
+
+impl SinkConfig {
+    fn new(
+        cli: &Cli,
+        recording: Option<(&Path, &RecordArgs)>,
+        stream: Option<(&str, &StreamArgs)>,
+        options: &SinkArgs,
+    ) -> Result<Self> {
+        let theme = options.theme()?;
+        let capture_input = options.capture_input && (recording.is_some() || stream.is_some());
+
+        let redactor = options.redact.redactor()?;
+        let served = cli.listen.is_some() || cli.control_socket.is_some();
+
+        if !redactor.is_empty() && recording.is_none() && stream.is_none() && !served {
+            anyhow::bail!("redaction needs --record, --stream, --listen or --control-socket");
+        }
 
-    let theme = if let (Some(fg), Some(bg)) = (theme_fg, theme_bg) {
-        Some(ThemeConfig {
-            fg,
-            bg,
-            palette: None,
+        let recording = match recording {
+            Some((path, args)) => Some(recorder_config(
+                cli,
+                path,
+                args,
+                options,
+                theme.clone(),
+                redactor.clone(),
+            )?),
+
+            None => None,
+        };
+
+        let stream = match stream {
+            Some((url, args)) => Some(streamer_config(
+                url,
+                args,
+                options,
+                theme.as_ref(),
+                redactor.clone(),
+            )?),
+            None => None,
+        };
+
+        Ok(Self {
+            recording,
+            stream,
+            theme,
+            capture_input,
+            redactor,
         })
-    } else {
-        None
+    }
+}
+
+fn recorder_config(
+    cli: &Cli,
+    output_path: &Path,
+    args: &RecordArgs,
+    options: &SinkArgs,
+    theme: Option<ThemeConfig>,
+    redactor: Redactor,
+) -> Result<RecorderConfig> {
+    let rotate_time = match args.rotate_time {
+        Some(secs) if secs > 0.0 && secs.is_finite() => Some(Duration::from_secs_f64(secs)),
+        Some(secs) => anyhow::bail!("invalid rotation time: {}", secs),
+        None => None,
     };
 
-    let capture_env_list = capture_env
+    let capture_env_list = args
+        .capture_env
+        .as_ref()
         .map(|s| s.split(',').map(String::from).collect())
         .unwrap_or_default();
 
@@ -119,46 +245,153 @@ async fn run_record_mode(
         Some(cli.shell_command.join(" "))
     };
 
-    let recorder_config = RecorderConfig {
-        output_path,
-        append,
-        idle_time_limit,
-        title,
+    Ok(RecorderConfig {
+        output_path: output_path.to_path_buf(),
+        format: args.format,
+        append: args.append,
+        idle_time_limit: args.idle_time_limit,
+        title: options.title.clone(),
         command: command_str,
         capture_env: capture_env_list,
         theme,
-        term_type,
-        capture_input,
+        term_type: options.term_type.clone(),
+        capture_input: options.capture_input,
+        rotate_size: args.rotate_size,
+        rotate_time,
+        flush: args.flush,
+        redactor,
+    })
+}
+
+fn streamer_config(
+    server_url: &str,
+    args: &StreamArgs,
+    options: &SinkArgs,
+    theme: Option<&ThemeConfig>,
+    redactor: Redactor,
+) -> Result<StreamerConfig> {
+    let protocol = match args.protocol.as_str() {
+        "alis" => StreamProtocol::Alis,
+        "v3" => StreamProtocol::AsciicastV3,
+        _ => anyhow::bail!("invalid protocol: {}", args.protocol),
     };
 
-    let mut recorder = AsciicastV3Recorder::new(recorder_config)?;
-    let clients_tx_clone = clients_tx.clone();
+    Ok(StreamerConfig {
+        server_url: server_url.to_string(),
+        install_id: args.install_id_value.clone(),
+        install_id_path: args.install_id_path.clone(),
+        title: options.title.clone(),
+        visibility: args.visibility.clone(),
+        protocol,
+        capture_input: options.capture_input,
+        theme: theme.map(streaming::alis::Theme::from),
+        term_type: options.term_type.clone(),
+        redactor,
+        reconnect: ReconnectConfig::default(),
+    })
+}
 
-    // Create a channel to signal when the recorder is subscribed and ready
-    let (ready_tx, ready_rx) = oneshot::channel();
+/// A consumer of the session's events running next to it, like the recorder
+struct Sink {
+    name: &'static str,
+    handle: JoinHandle<Result<()>>,
+    ready_rx: oneshot::Receiver<()>,
+}
 
-    // Create session early so recorder can subscribe before PTY starts
+async fn run_session_mode(cli: &Cli, config: SinkConfig) -> Result<()> {
+    let (input_tx, input_rx) = mpsc::channel(1024);
+    let (output_tx, output_rx) = mpsc::channel(1024);
+    let (command_tx, command_rx) = mpsc::channel(1024);
+    let (clients_tx, mut clients_rx) = mpsc::channel(1);
+    let mut sinks = Vec::new();
+
+    // Create session early so sinks can subscribe before PTY starts
     // PID is set to 0 initially; it's only used for the Init event metadata
     let mut session = build_session(&cli.size, 0);
+    session.set_palette(Palette::from_theme(config.theme.as_ref())?);
+    session.set_clipboard_access(cli.clipboard_read, cli.clipboard_limit as usize);
 
-    let recorder_handle = tokio::spawn(async move {
-        recorder.run(&clients_tx_clone, Some(ready_tx)).await
-    });
+    if let Some(recorder_config) = config.recording {
+        let mut recorder = AsciicastV3Recorder::new(recorder_config)?;
+        let clients_tx = clients_tx.clone();
+        let (ready_tx, ready_rx) = oneshot::channel();
+
+        let handle = tokio::spawn(async move { recorder.run(&clients_tx, Some(ready_tx)).await });
 
-    start_http_api(cli.listen, clients_tx.clone()).await?;
-    let api = start_stdio_api(command_tx, clients_tx, cli.subscribe.unwrap_or_default());
+        sinks.push(Sink {
+            name: "recorder",
+            handle,
+            ready_rx,
+        });
+    }
+
+    if let Some(streamer_config) = config.stream {
+        let mut streamer = AsciinemaServerStreamer::new(streamer_config);
+        let clients_tx = clients_tx.clone();
+        let (ready_tx, ready_rx) = oneshot::channel();
+
+        let handle = tokio::spawn(async move { streamer.run(&clients_tx, Some(ready_tx)).await });
 
-    // Handle the recorder's subscription request before starting PTY
-    // This ensures the recorder is subscribed and won't miss any events
-    let mut clients_rx = clients_rx;
-    if let Some(client) = clients_rx.recv().await {
-        client.accept(session.subscribe());
+        sinks.push(Sink {
+            name: "streamer",
+            handle,
+            ready_rx,
+        });
     }
 
-    // Wait for recorder to signal it's ready (subscription complete)
-    ready_rx.await.context("recorder failed to signal ready")?;
+    let (paused_tx, paused_rx) = watch::channel(false);
+
+    if cli.tee_tty {
+        let tty = mirror::open_tty().context("--tee-tty needs a terminal")?;
+        let clients_tx = clients_tx.clone();
+        let (ready_tx, ready_rx) = oneshot::channel();
+
+        let takeover = cli.takeover.then(|| mirror::Takeover {
+            command_tx: command_tx.clone(),
+            paused_tx,
+        });
+
+        let handle = tokio::spawn(mirror::run(tty, clients_tx, takeover, ready_tx));
+
+        sinks.push(Sink {
+            name: "mirror",
+            handle,
+            ready_rx,
+        });
+    }
+
+    let socket_files = [
+        start_http_api(
+            cli,
+            clients_tx.clone(),
+            command_tx.clone(),
+            config.theme,
+            config.redactor.clone(),
+        )
+        .await?,
+        start_control_api(cli, clients_tx.clone(), command_tx.clone(), config.redactor).await?,
+    ];
+
+    // A daemon's STDIN is closed, it's controlled through its control socket
+    let api = if cli.daemon {
+        tokio::spawn(future::pending())
+    } else {
+        let sub = cli.subscribe.unwrap_or_default();
+
+        start_stdio_api(command_tx, clients_tx, sub, paused_rx)
+    };
+
+    // Handle the sinks' subscription requests before starting PTY
+    // This ensures the sinks are subscribed and won't miss any events
+    wait_for_sinks(&mut sinks, &mut clients_rx, &mut session).await?;
+
+    let command = if cli.shell_integration {
+        shell::inject(&cli.shell_command)?
+    } else {
+        cli.shell_command.clone()
+    };
 
-    let (pid, pty) = start_pty(&cli.shell_command, &cli.size, input_rx, output_tx)?;
+    let (pid, resizer, pty) = start_pty(&command, &cli.size, input_rx, output_tx)?;
 
     // Update session with actual PID
     session.set_pid(pid);
@@ -171,134 +404,317 @@ async fn run_record_mode(
         session,
         api,
         pty,
-        capture_input,
+        resizer,
+        config.capture_input,
     )
     .await?;
 
-    recorder_handle.await??;
+    // Let every sink finish, even if one of them failed
+    let mut result = Ok(());
+
+    for sink in sinks {
+        let sink_result = sink.handle.await?;
+
+        if result.is_ok() {
+            result = sink_result.with_context(|| format!("{} failed", sink.name));
+        }
+    }
+
+    result?;
+    drop(socket_files);
 
     std::process::exit(exit_status);
 }
 
-async fn run_stream_mode(
+/// Accepts subscriptions until every sink has signalled it's ready
+///
+/// Other clients, like the STDIO API, may subscribe in the meantime, so
+/// they're accepted too.
+async fn wait_for_sinks(
+    sinks: &mut [Sink],
+    clients_rx: &mut mpsc::Receiver<session::Client>,
+    session: &mut Session,
+) -> Result<()> {
+    for sink in sinks.iter_mut() {
+        loop {
+            tokio::select! {
+                ready = &mut sink.ready_rx => {
+                    if ready.is_err() {
+                        // The sink quit before subscribing, so it has the reason
+                        return match (&mut sink.handle).await? {
+                            Err(e) => Err(e.context(format!("{} failed to start", sink.name))),
+                            Ok(()) => anyhow::bail!("{} failed to signal ready", sink.name),
+                        };
+                    }
+
+                    break;
+                }
+
+                Some(client) = clients_rx.recv() => {
+                    client.accept(session);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_replay_mode(
     cli: &Cli,
-    server_url: String,
-    install_id_path: Option<std::path::PathBuf>,
-    install_id_value: Option<String>,
-    title: Option<String>,
-    visibility: Option<String>,
-    protocol_str: String,
-    capture_input: bool,
-    term_type: Option<String>,
-    theme_fg: Option<String>,
-    theme_bg: Option<String>,
+    path: PathBuf,
+    speed: f64,
+    instant: bool,
+    idle_time_limit: Option<f64>,
+    snapshot_at_markers: bool,
+    hold: bool,
 ) -> Result<()> {
-    let (input_tx, input_rx) = mpsc::channel(1024);
-    let (output_tx, output_rx) = mpsc::channel(1024);
+    if speed <= 0.0 || !speed.is_finite() {
+        anyhow::bail!("invalid speed: {}", speed);
+    }
+
+    if let Some(limit) = idle_time_limit.filter(|limit| *limit <= 0.0 || !limit.is_finite()) {
+        anyhow::bail!("invalid idle time limit: {}", limit);
+    }
+
+    let cast = Asciicast::open(&path)?;
+    let idle_time_limit = idle_time_limit.or(cast.header.idle_time_limit);
+
+    if let Some(limit) = idle_time_limit.filter(|limit| *limit <= 0.0 || !limit.is_finite()) {
+        anyhow::bail!("invalid idle time limit in {:?}: {}", path, limit);
+    }
+
     let (command_tx, command_rx) = mpsc::channel(1024);
     let (clients_tx, clients_rx) = mpsc::channel(1);
 
-    let protocol = match protocol_str.as_str() {
-        "alis" => StreamProtocol::Alis,
-        "v3" => StreamProtocol::AsciicastV3,
-        _ => anyhow::bail!("invalid protocol: {}", protocol_str),
+    let player_config = PlayerConfig {
+        speed,
+        idle_time_limit,
+        instant,
     };
 
-    let theme = if let (Some(fg), Some(bg)) = (theme_fg, theme_bg) {
-        Some(streaming::alis::Theme {
-            fg,
-            bg,
-            palette: Vec::new(),
-        })
-    } else {
-        None
-    };
+    let (cols, rows) = (cast.header.cols, cast.header.rows);
+    let mut session = Session::new(cols, rows, 0);
+    session.set_palette(Palette::from_theme(cast.header.theme.as_ref())?);
+
+    let theme = cast.header.theme.clone();
+    let redactor = cli.options.redact.redactor()?;
+    let socket_files = [
+        start_http_api(
+            cli,
+            clients_tx.clone(),
+            command_tx.clone(),
+            theme,
+            redactor.clone(),
+        )
+        .await?,
+        start_control_api(cli, clients_tx.clone(), command_tx.clone(), redactor).await?,
+    ];
+
+    let sub = cli.subscribe.unwrap_or_default();
+    let api = start_stdio_api(command_tx, clients_tx, sub, watch::channel(false).1);
+    eprintln!("replaying {:?} in terminal of size {}x{}", path, cols, rows);
+    let player = Player::new(cast.events, player_config);
+
+    let events = stream::unfold(player, |mut player| async move {
+        player
+            .next()
+            .await
+            .map(|event| (Playback::Event(event), player))
+    });
 
-    let streamer_config = StreamerConfig {
-        server_url,
-        install_id: install_id_value,
-        install_id_path,
-        title,
-        visibility,
-        protocol,
-        capture_input,
+    run_replay_loop(
+        events,
+        "replay",
+        command_rx,
+        clients_rx,
+        session,
+        api,
+        snapshot_at_markers,
+        hold,
+    )
+    .await?;
+
+    drop(socket_files);
+    std::process::exit(0);
+}
+
+async fn run_watch_mode(cli: &Cli, url: &str) -> Result<()> {
+    let mut events = Box::pin(alis_client::connect(url).await?);
+
+    let Some(Ok(AlisEvent::Init {
+        cols,
+        rows,
         theme,
-        term_type,
+        init_data,
+        ..
+    })) = events.next().await
+    else {
+        anyhow::bail!("{url} didn't start with an init event");
     };
 
-    let mut streamer = AsciinemaServerStreamer::new(streamer_config);
-    let clients_tx_clone = clients_tx.clone();
+    let theme = theme.map(|theme| ThemeConfig {
+        fg: theme.fg,
+        bg: theme.bg,
+        palette: Some(theme.palette.join(":")),
+    });
+
+    let (cols, rows) = (cols as usize, rows as usize);
+    let (command_tx, command_rx) = mpsc::channel(1024);
+    let (clients_tx, clients_rx) = mpsc::channel(1);
+    let mut session = Session::new(cols, rows, 0);
+    session.set_palette(Palette::from_theme(theme.as_ref())?);
+    session.output(init_data);
+
+    let redactor = cli.options.redact.redactor()?;
+    let socket_files = [
+        start_http_api(
+            cli,
+            clients_tx.clone(),
+            command_tx.clone(),
+            theme,
+            redactor.clone(),
+        )
+        .await?,
+        start_control_api(cli, clients_tx.clone(), command_tx.clone(), redactor).await?,
+    ];
+
+    let sub = cli.subscribe.unwrap_or_default();
+    let api = start_stdio_api(command_tx, clients_tx, sub, watch::channel(false).1);
+    eprintln!("watching {} in terminal of size {}x{}", url, cols, rows);
+    let events = events.flat_map(|event| stream::iter(watched_events(event)));
+
+    run_replay_loop(
+        events, "watch", command_rx, clients_rx, session, api, false, false,
+    )
+    .await?;
 
-    // Create a channel to signal when the streamer is subscribed and ready
-    let (ready_tx, ready_rx) = oneshot::channel();
+    drop(socket_files);
+    std::process::exit(0);
+}
 
-    // Create session early so streamer can subscribe before PTY starts
-    // PID is set to 0 initially; it's only used for the Init event metadata
-    let mut session = build_session(&cli.size, 0);
+async fn run_attach_mode(name: &str) -> Result<()> {
+    let path = daemon::socket_path(name)?;
 
-    let streamer_handle = tokio::spawn(async move {
-        streamer.run(&clients_tx_clone, Some(ready_tx)).await
-    });
+    match attach::run(&path).await? {
+        attach::Detached::ByUser => eprintln!("detached from session {name}"),
+        attach::Detached::Ended => eprintln!("session {name} ended"),
+    }
+
+    Ok(())
+}
 
-    start_http_api(cli.listen, clients_tx.clone()).await?;
-    let api = start_stdio_api(command_tx, clients_tx, cli.subscribe.unwrap_or_default());
+fn run_ls_mode() -> Result<()> {
+    let sessions = daemon::list()?;
 
-    // Handle the streamer's subscription request before starting PTY
-    // This ensures the streamer is subscribed and won't miss any events
-    let mut clients_rx = clients_rx;
-    if let Some(client) = clients_rx.recv().await {
-        client.accept(session.subscribe());
+    if sessions.is_empty() {
+        eprintln!("no sessions");
     }
 
-    // Wait for streamer to signal it's ready (subscription complete)
-    ready_rx.await.context("streamer failed to signal ready")?;
+    for (name, info) in sessions {
+        println!("{name}\t{}x{}\tpid {}", info.cols, info.rows, info.pid);
+    }
 
-    let (pid, pty) = start_pty(&cli.shell_command, &cli.size, input_rx, output_tx)?;
+    Ok(())
+}
 
-    // Update session with actual PID
-    session.set_pid(pid);
+async fn run_relay_mode(cli: &Cli) -> Result<()> {
+    let addr = match &cli.listen {
+        Some(ListenAddr::Tcp(addr)) => *addr,
+        Some(ListenAddr::Unix(_)) => anyhow::bail!("the relay can only listen on a TCP address"),
+        None => ([127, 0, 0, 1], 8080).into(),
+    };
 
-    let exit_status = run_event_loop(
-        output_rx,
-        input_tx,
-        command_rx,
-        clients_rx,
-        session,
-        api,
-        pty,
-        capture_input,
-    )
-    .await?;
+    let auth = cli.server.auth(&ListenAddr::Tcp(addr))?;
+    let listener = TcpListener::bind(addr).context("cannot start relay listener")?;
+    streaming::relay::start(listener, auth).await?.await?;
 
-    streamer_handle.await??;
+    Ok(())
+}
 
-    std::process::exit(exit_status);
+/// Translates the events of a watched stream into the session's playback,
+/// skipping the ones which don't affect it
+fn watched_events(event: Result<AlisEvent>) -> Vec<Playback> {
+    let event = match event {
+        // The producer started over, e.g. after a restart or a reconnect
+        Ok(AlisEvent::Init {
+            cols,
+            rows,
+            init_data,
+            ..
+        }) => return vec![Playback::Restart(cols as usize, rows as usize, init_data)],
+
+        Ok(AlisEvent::Output { data, .. }) => EventData::Output(data),
+        Ok(AlisEvent::Input { data, .. }) => EventData::Input(data),
+        Ok(AlisEvent::Resize { cols, rows, .. }) => EventData::Resize(cols as usize, rows as usize),
+        Ok(AlisEvent::Marker { label, .. }) => EventData::Marker(label),
+        Ok(AlisEvent::Exit { status, .. }) => EventData::Exit(status),
+
+        Ok(AlisEvent::Eot { .. }) => {
+            eprintln!("stream ended");
+            return vec![];
+        }
+
+        Err(e) => {
+            eprintln!("skipping invalid ALiS message: {e:#}");
+            return vec![];
+        }
+    };
+
+    vec![Playback::Event(event)]
 }
 
-async fn run_normal_mode(cli: &Cli) -> Result<()> {
-    let (input_tx, input_rx) = mpsc::channel(1024);
-    let (output_tx, output_rx) = mpsc::channel(1024);
-    let (command_tx, command_rx) = mpsc::channel(1024);
-    let (clients_tx, clients_rx) = mpsc::channel(1);
+fn run_render_mode(
+    path: &Path,
+    out: &Path,
+    format: Option<&str>,
+    config: RenderConfig,
+    font_size: f64,
+) -> Result<()> {
+    let cast = Asciicast::open(path)?;
 
-    start_http_api(cli.listen, clients_tx.clone()).await?;
-    let api = start_stdio_api(command_tx, clients_tx, cli.subscribe.unwrap_or_default());
-    let (pid, pty) = start_pty(&cli.shell_command, &cli.size, input_rx, output_tx)?;
-    let session = build_session(&cli.size, pid);
+    render::render_file(&cast, out, format, &config, font_size)
+}
 
-    let exit_status = run_event_loop(
-        output_rx,
-        input_tx,
-        command_rx,
-        clients_rx,
-        session,
-        api,
-        pty,
-        false,
-    )
-    .await?;
+fn run_convert_mode(input: &Path, output: &Path, format: Option<Format>) -> Result<()> {
+    let cast = Asciicast::open(input)?;
 
-    std::process::exit(exit_status);
+    let format = format.unwrap_or(match cast.header.format {
+        Format::V2 => Format::V3,
+        Format::V3 => Format::V2,
+    });
+
+    let file = File::create(output).context("failed to create output file")?;
+    let mut writer = BufWriter::new(file);
+    let mut encoder = Encoder::new(format);
+    let mut dropped = 0;
+
+    writeln!(writer, "{}", encoder.header(&cast.header))?;
+
+    for entry in &cast.events {
+        match encoder.event(entry.interval, &entry.data) {
+            Some(event) => writeln!(writer, "{}", event)?,
+            None => dropped += 1,
+        }
+    }
+
+    writer.flush()?;
+
+    eprintln!(
+        "converted {} events from asciicast {} to {}",
+        cast.events.len() - dropped,
+        cast.header.format,
+        format
+    );
+
+    if dropped > 0 {
+        eprintln!(
+            "dropped {} exit event(s), which asciicast v2 doesn't support",
+            dropped
+        );
+    }
+
+    Ok(())
 }
 
 fn build_session(size: &cli::Size, pid: i32) -> Session {
@@ -309,8 +725,9 @@ fn start_stdio_api(
     command_tx: mpsc::Sender<Command>,
     clients_tx: mpsc::Sender<session::Client>,
     sub: api::Subscription,
+    paused: watch::Receiver<bool>,
 ) -> JoinHandle<Result<()>> {
-    tokio::spawn(api::stdio::start(command_tx, clients_tx, sub))
+    tokio::spawn(api::stdio::start(command_tx, clients_tx, sub, paused))
 }
 
 fn start_pty(
@@ -318,30 +735,76 @@ fn start_pty(
     size: &cli::Size,
     input_rx: mpsc::Receiver<Vec<u8>>,
     output_tx: mpsc::Sender<Vec<u8>>,
-) -> Result<(i32, JoinHandle<Result<i32>>)> {
+) -> Result<(i32, pty::Resizer, JoinHandle<Result<i32>>)> {
     let command_vec: Vec<String> = if command.is_empty() {
         vec!["bash".to_string()]
     } else {
         command.to_vec()
     };
     eprintln!("launching {:?} in terminal of size {}", command_vec, size);
-    let (pid, fut) = pty::spawn(&command_vec, size, input_rx, output_tx)?;
+    let (pid, resizer, fut) = pty::spawn(&command_vec, size, input_rx, output_tx)?;
 
-    Ok((pid, tokio::spawn(fut)))
+    Ok((pid, resizer, tokio::spawn(fut)))
 }
 
+/// Starts the HTTP server if enabled, returning the socket file to remove on
+/// exit when listening on a Unix socket
 async fn start_http_api(
-    listen_addr: Option<SocketAddr>,
+    cli: &Cli,
     clients_tx: mpsc::Sender<session::Client>,
-) -> Result<()> {
-    if let Some(addr) = listen_addr {
-        let listener = TcpListener::bind(addr).context("cannot start HTTP listener")?;
-        tokio::spawn(api::http::start(listener, clients_tx).await?);
-    }
+    command_tx: mpsc::Sender<Command>,
+    theme: Option<ThemeConfig>,
+    redactor: Redactor,
+) -> Result<Option<api::unix::SocketFile>> {
+    let Some(addr) = &cli.listen else {
+        return Ok(None);
+    };
 
-    Ok(())
+    let options = api::http::Options {
+        theme,
+        auth: cli.server.auth(addr)?,
+        tls: cli.server.tls(addr)?,
+        redactor,
+    };
+
+    let (listener, socket_file) = bind(addr).context("cannot start HTTP listener")?;
+    tokio::spawn(api::http::start(listener, clients_tx, command_tx, options).await?);
+
+    Ok(socket_file)
+}
+
+/// Starts the control socket if enabled, like the HTTP server
+async fn start_control_api(
+    cli: &Cli,
+    clients_tx: mpsc::Sender<session::Client>,
+    command_tx: mpsc::Sender<Command>,
+    redactor: Redactor,
+) -> Result<Option<api::unix::SocketFile>> {
+    let Some(addr) = &cli.control_socket else {
+        return Ok(None);
+    };
+
+    let (listener, socket_file) = bind(addr).context("cannot start control socket")?;
+    let token = cli.server.control_token();
+    let server = api::control::start(listener, token, redactor, clients_tx, command_tx);
+    tokio::spawn(server.await?);
+
+    Ok(socket_file)
+}
+
+fn bind(addr: &ListenAddr) -> Result<(api::Listener, Option<api::unix::SocketFile>)> {
+    match addr {
+        ListenAddr::Tcp(addr) => Ok((TcpListener::bind(addr)?.into(), None)),
+
+        ListenAddr::Unix(path) => {
+            let (listener, socket_file) = api::unix::bind(path)?;
+
+            Ok((listener.into(), Some(socket_file)))
+        }
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_event_loop(
     mut output_rx: mpsc::Receiver<Vec<u8>>,
     input_tx: mpsc::Sender<Vec<u8>>,
@@ -350,17 +813,29 @@ async fn run_event_loop(
     mut session: Session,
     mut api_handle: JoinHandle<Result<()>>,
     mut pty_handle: JoinHandle<Result<i32>>,
+    resizer: pty::Resizer,
     capture_input: bool,
 ) -> Result<i32> {
     let mut serving = true;
     let mut exit_status = 0;
+    let mut recordings = Recordings::default();
+    let mut runs = VecDeque::new();
 
     loop {
         tokio::select! {
             result = output_rx.recv() => {
                 match result {
                     Some(data) => {
-                        session.output(String::from_utf8_lossy(&data).to_string());
+                        let replies = session.output(String::from_utf8_lossy(&data).to_string());
+
+                        // Answers the program's terminal queries, as there's
+                        // no real terminal to do it
+                        if !replies.is_empty() {
+                            input_tx.send(replies.into_bytes()).await?;
+                        }
+
+                        let capture = capture_input || recordings.capture_input();
+                        run_next_command(&mut runs, &mut session, &input_tx, capture).await?;
                     },
 
                     None => {
@@ -376,7 +851,7 @@ async fn run_event_loop(
                         let data = command::seqs_to_bytes(&seqs, session.cursor_key_app_mode());
 
                         // Emit Input event if capturing
-                        if capture_input {
+                        if capture_input || recordings.capture_input() {
                             session.input(String::from_utf8_lossy(&data).to_string());
                         }
 
@@ -387,14 +862,59 @@ async fn run_event_loop(
                         session.snapshot();
                     }
 
+                    Some(Command::Run(command, id, reply_tx)) => {
+                        let unmarked = "runCommand waits for a marked shell prompt, see --shell-integration";
+                        let integrated = session.shell_integrated();
+
+                        if !integrated {
+                            eprintln!("{unmarked}");
+                        }
+
+                        match reply_tx {
+                            // Requests waiting for the command are turned
+                            // down rather than left hanging
+                            Some(reply_tx) if !integrated => {
+                                let _ = reply_tx.send(Err(unmarked.to_string()));
+                            }
+
+                            reply_tx => {
+                                if let Some(reply_tx) = reply_tx {
+                                    let _ = reply_tx.send(Ok(()));
+                                }
+
+                                runs.push_back((command, id));
+                                let capture = capture_input || recordings.capture_input();
+                                run_next_command(&mut runs, &mut session, &input_tx, capture).await?;
+                            }
+                        }
+                    }
+
+                    Some(Command::SetClipboard(text)) => {
+                        if let Err(e) = session.set_clipboard(text) {
+                            eprintln!("setClipboard error: {e}");
+                        }
+                    }
+
                     Some(Command::Resize(cols, rows)) => {
                         session.resize(cols, rows);
+
+                        if let Err(e) = resizer.resize(cols, rows) {
+                            eprintln!("pty resize error: {e}");
+                        }
                     }
 
                     Some(Command::Marker(label)) => {
                         session.marker(label);
                     }
 
+                    Some(Command::Export(format)) => {
+                        session.export(format);
+                    }
+
+                    Some(command) => {
+                        handle_recording_command(command, &mut recordings, &mut session).await;
+                    }
+
                     None => {
                         eprintln!("stdin closed, shutting down...");
                         break;
@@ -405,7 +925,7 @@ async fn run_event_loop(
             client = clients_rx.recv(), if serving => {
                 match client {
                     Some(client) => {
-                        client.accept(session.subscribe());
+                        client.accept(&mut session);
                     }
 
                     None => {
@@ -420,6 +940,11 @@ async fn run_event_loop(
             }
 
             result = &mut pty_handle => {
+                // The process is gone, but its last output may still be queued
+                while let Ok(data) = output_rx.try_recv() {
+                    session.output(String::from_utf8_lossy(&data).to_string());
+                }
+
                 match result {
                     Ok(Ok(status)) => {
                         exit_status = status;
@@ -444,8 +969,200 @@ async fn run_event_loop(
         }
     }
 
-    // Give events a moment to propagate
+    // Ending the session ends the subscribers' streams. Give them a moment
+    // to deliver the remaining events and tell viewers the stream ended
+    drop(session);
     tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    recordings.finish().await;
 
     Ok(exit_status)
 }
+
+/// Types the next command queued by `runCommand` once the shell waits at a
+/// prompt
+async fn run_next_command(
+    runs: &mut VecDeque<(String, Option<String>)>,
+    session: &mut Session,
+    input_tx: &mpsc::Sender<Vec<u8>>,
+    capture_input: bool,
+) -> Result<()> {
+    if !session.at_prompt() {
+        return Ok(());
+    }
+
+    if let Some((command, id)) = runs.pop_front() {
+        let data = format!("{command}\r");
+        session.claim_prompt(id);
+
+        if capture_input {
+            session.input(data.clone());
+        }
+
+        input_tx.send(data.into_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// What replay and watch modes apply to the session
+enum Playback {
+    Event(EventData),
+    /// The watched stream started over, with the given size and screen
+    Restart(usize, usize, String),
+}
+
+/// Applies the events of a recording or a watched stream to the session
+/// while serving the APIs
+#[allow(clippy::too_many_arguments)]
+async fn run_replay_loop(
+    events: impl Stream<Item = Playback>,
+    mode: &str,
+    mut command_rx: mpsc::Receiver<Command>,
+    mut clients_rx: mpsc::Receiver<session::Client>,
+    mut session: Session,
+    mut api_handle: JoinHandle<Result<()>>,
+    snapshot_at_markers: bool,
+    hold: bool,
+) -> Result<()> {
+    tokio::pin!(events);
+    let mut serving = true;
+    let mut finished = false;
+    let mut recordings = Recordings::default();
+
+    loop {
+        tokio::select! {
+            event = events.next(), if !finished => {
+                match event {
+                    Some(Playback::Event(EventData::Output(data))) => {
+                        session.output(data);
+                    }
+
+                    Some(Playback::Event(EventData::Input(data))) => {
+                        session.input(data);
+                    }
+
+                    Some(Playback::Event(EventData::Resize(cols, rows))) => {
+                        session.resize(cols, rows);
+                    }
+
+                    Some(Playback::Event(EventData::Marker(label))) => {
+                        session.marker(label);
+
+                        if snapshot_at_markers {
+                            session.snapshot();
+                        }
+                    }
+
+                    Some(Playback::Event(EventData::Exit(status))) => {
+                        session.exit(status);
+                    }
+
+                    Some(Playback::Restart(cols, rows, data)) => {
+                        session.restart(cols, rows, data);
+                    }
+
+                    None => {
+                        eprintln!("{mode} finished");
+                        finished = true;
+
+                        if !hold {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            command = command_rx.recv() => {
+                match command {
+                    Some(Command::Input(_)) => {
+                        eprintln!("input is ignored in {mode} mode");
+                    }
+
+                    Some(Command::Run(_, _, reply_tx)) => {
+                        let message = format!("runCommand is ignored in {mode} mode");
+
+                        match reply_tx {
+                            Some(reply_tx) => {
+                                let _ = reply_tx.send(Err(message));
+                            }
+
+                            None => eprintln!("{message}"),
+                        }
+                    }
+
+                    Some(Command::SetClipboard(_)) => {
+                        eprintln!("setClipboard is ignored in {mode} mode");
+                    }
+
+                    Some(Command::Snapshot) => {
+                        session.snapshot();
+                    }
+
+                    Some(Command::Resize(cols, rows)) => {
+                        session.resize(cols, rows);
+                    }
+
+                    Some(Command::Marker(label)) => {
+                        session.marker(label);
+                    }
+
+                    Some(Command::Export(format)) => {
+                        session.export(format);
+                    }
+
+                    Some(command) => {
+                        handle_recording_command(command, &mut recordings, &mut session).await;
+                    }
+
+                    None => {
+                        eprintln!("stdin closed, shutting down...");
+                        break;
+                    }
+                }
+            }
+
+            client = clients_rx.recv(), if serving => {
+                match client {
+                    Some(client) => {
+                        client.accept(&mut session);
+                    }
+
+                    None => {
+                        serving = false;
+                    }
+                }
+            }
+
+            _ = &mut api_handle => {
+                eprintln!("stdin closed, shutting down...");
+                break;
+            }
+        }
+    }
+
+    // Ending the session ends the subscribers' streams. Give them a moment
+    // to deliver the remaining events and tell viewers the stream ended
+    drop(session);
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    recordings.finish().await;
+
+    Ok(())
+}
+
+async fn handle_recording_command(
+    command: Command,
+    recordings: &mut Recordings,
+    session: &mut Session,
+) {
+    let result = match command {
+        Command::StartRecording(config) => recordings.start(*config, session.subscribe_lossless()),
+        Command::StopRecording(path) => recordings.stop(&path).await,
+        Command::PauseRecording(path) => recordings.pause(&path).await,
+        Command::ResumeRecording(path) => recordings.resume(&path).await,
+        _ => Ok(()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("recording error: {e:#}");
+    }
+}
@@ -0,0 +1,177 @@
+/// Mirror of the session on the invoking terminal (--tee-tty)
+use crate::command::{Command, InputSeq};
+use crate::session::{self, Event};
+use crate::tty::{self, RawMode};
+use anyhow::Result;
+use futures_util::StreamExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::AsFd;
+use std::thread;
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// Ctrl-\
+const TAKEOVER_KEY: u8 = 0x1c;
+
+/// Lets a human type into the session, pausing the STDIO API meanwhile
+pub struct Takeover {
+    pub command_tx: mpsc::Sender<Command>,
+    pub paused_tx: watch::Sender<bool>,
+}
+
+/// Opens the controlling terminal, which stays available when STDIN and
+/// STDOUT are used by the STDIO API
+pub fn open_tty() -> Result<File> {
+    Ok(OpenOptions::new().read(true).write(true).open("/dev/tty")?)
+}
+
+/// Renders the session's screen to the terminal as it changes, and with
+/// `takeover`, toggles between watching and typing with Ctrl-\
+pub async fn run(
+    mut tty: File,
+    clients_tx: mpsc::Sender<session::Client>,
+    takeover: Option<Takeover>,
+    ready_tx: oneshot::Sender<()>,
+) -> Result<()> {
+    let (keys_tx, mut keys_rx) = mpsc::unbounded_channel();
+
+    // Keys aren't echoed over the mirrored screen, but Ctrl-C still works
+    // until the keyboard is taken over
+    let raw_mode = match &takeover {
+        Some(_) => {
+            let reader = tty.try_clone()?;
+            thread::spawn(move || read_keys(reader, keys_tx));
+
+            Some(RawMode::enable(tty.as_fd(), true)?)
+        }
+
+        None => None,
+    };
+
+    let mut events = Box::pin(session::resync_stream(&clients_tx).await?);
+    let _ = ready_tx.send(());
+    let mut taken_over = false;
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                match event {
+                    Some(Event::Init(_, cols, rows, _, seq, _, _)) => {
+                        // Asks the terminal to take the session's size, which
+                        // not every terminal does
+                        write!(tty, "\x1b[8;{rows};{cols}t\x1bc{seq}")?;
+                    }
+
+                    Some(Event::Output(_, data)) => {
+                        tty.write_all(data.as_bytes())?;
+                    }
+
+                    Some(Event::Resize(_, _, _)) => {
+                        // Starts over with the screen at the new size
+                        events = Box::pin(session::resync_stream(&clients_tx).await?);
+                    }
+
+                    Some(_) => (),
+
+                    None => break,
+                }
+            }
+
+            Some(data) = keys_rx.recv() => {
+                let Some(takeover) = &takeover else {
+                    continue;
+                };
+
+                // Keys typed between toggles go to the session only while
+                // it's taken over
+                for (i, keys) in data.split(|b| *b == TAKEOVER_KEY).enumerate() {
+                    if i > 0 {
+                        taken_over = !taken_over;
+                        takeover.paused_tx.send_replace(taken_over);
+
+                        if let Some(raw_mode) = &raw_mode {
+                            raw_mode.set_signals(!taken_over)?;
+                        }
+
+                        if taken_over {
+                            eprintln!("keyboard taken over, STDIO commands paused (Ctrl-\\ to hand back)");
+                        } else {
+                            eprintln!("keyboard handed back, STDIO commands resumed");
+                        }
+                    }
+
+                    if taken_over && !keys.is_empty() {
+                        let seq = InputSeq::Standard(String::from_utf8_lossy(keys).to_string());
+                        takeover.command_tx.send(Command::Input(vec![seq])).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    // Resets the terminal, as `ht attach` does when detaching
+    tty.write_all(b"\x1bc")?;
+
+    Ok(())
+}
+
+fn read_keys(mut tty: File, keys_tx: mpsc::UnboundedSender<Vec<u8>>) -> Result<()> {
+    let mut buf = [0; 1024];
+    let mut incomplete = Vec::new();
+
+    loop {
+        let n = tty.read(&mut buf)?;
+
+        if n == 0 {
+            return Ok(());
+        }
+
+        let mut data = std::mem::take(&mut incomplete);
+        data.extend_from_slice(&buf[..n]);
+        incomplete = tty::split_incomplete_utf8(&mut data);
+
+        if !data.is_empty() {
+            keys_tx.send(data)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+
+    #[tokio::test]
+    async fn test_mirror() {
+        let path = std::env::temp_dir().join(format!("ht-mirror-{}", std::process::id()));
+        let tty = File::create(&path).unwrap();
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let handle = tokio::spawn(run(tty, clients_tx, None, ready_tx));
+
+        let mut session = Session::new(10, 2, 0);
+        session.output("a".to_string());
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        ready_rx.await.unwrap();
+        session.output("b".to_string());
+
+        // The screen is redrawn at the new size
+        session.resize(12, 3);
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        session.output("c".to_string());
+        drop(session);
+        handle.await.unwrap().unwrap();
+
+        let mirrored = std::fs::read_to_string(&path).unwrap();
+        let screens: Vec<&str> = mirrored.split("\x1bc").collect();
+        assert_eq!(screens.len(), 4);
+        assert!(screens[0].ends_with("\x1b[8;2;10t"));
+        assert!(screens[1].contains('a') && screens[1].ends_with("b\x1b[8;3;12t"));
+        assert!(screens[2].contains("ab") && screens[2].ends_with('c'));
+
+        // The terminal is reset when the session ends
+        assert_eq!(screens[3], "");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -10,22 +10,41 @@ use std::ffi::{CString, NulError};
 use std::fs::File;
 use std::future::Future;
 use std::io;
-use std::os::fd::FromRawFd;
 use std::os::fd::{AsRawFd, OwnedFd};
 use tokio::io::unix::AsyncFd;
 use tokio::sync::mpsc;
 
+/// Resizes the pty, which lets the child know with SIGWINCH
+pub struct Resizer(OwnedFd);
+
+impl Resizer {
+    pub fn resize(&self, cols: usize, rows: usize) -> io::Result<()> {
+        let winsize = pty::Winsize {
+            ws_col: cols as u16,
+            ws_row: rows as u16,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+
+        match unsafe { libc::ioctl(self.0.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
 pub fn spawn(
     command: &[String],
     winsize: &pty::Winsize,
     input_rx: mpsc::Receiver<Vec<u8>>,
     output_tx: mpsc::Sender<Vec<u8>>,
-) -> Result<(i32, impl Future<Output = Result<i32>>)> {
+) -> Result<(i32, Resizer, impl Future<Output = Result<i32>>)> {
     let result = unsafe { pty::forkpty(Some(winsize), None) }?;
 
     match result.fork_result {
         ForkResult::Parent { child } => Ok((
             child.as_raw(),
+            Resizer(result.master.try_clone()?),
             drive_child(child, result.master, input_rx, output_tx),
         )),
 
@@ -71,7 +90,8 @@ async fn do_drive_child(
     let mut buf = [0u8; READ_BUF_SIZE];
     let mut input: Vec<u8> = Vec::with_capacity(READ_BUF_SIZE);
     nbio::set_non_blocking(&master.as_raw_fd())?;
-    let mut master_file = unsafe { File::from_raw_fd(master.as_raw_fd()) };
+    // A duplicate descriptor, so the file and AsyncFd each close their own
+    let mut master_file = File::from(master.try_clone()?);
     let master_fd = AsyncFd::new(master)?;
 
     loop {
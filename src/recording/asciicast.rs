@@ -0,0 +1,629 @@
+/// asciicast file format
+///
+/// Reads asciicast v2 and v3 files into memory so they can be replayed or
+/// converted, and encodes headers and events in either version.
+///
+/// v3 events carry the interval since the previous event, while v2 events
+/// carry the time since the start of the recording. Both are represented
+/// with intervals in memory, and converted at microsecond precision so
+/// round trips between the versions are lossless.
+///
+/// Specifications:
+/// - https://docs.asciinema.org/manual/asciicast/v2/
+/// - https://docs.asciinema.org/manual/asciicast/v3/
+use super::asciicast_v3::ThemeConfig;
+use super::output;
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::BufRead;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    V2,
+    V3,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v2" | "2" => Ok(Format::V2),
+            "v3" | "3" => Ok(Format::V3),
+            _ => Err(format!("invalid asciicast format: {s} (use v2 or v3)")),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::V2 => f.write_str("v2"),
+            Format::V3 => f.write_str("v3"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub format: Format,
+    pub cols: usize,
+    pub rows: usize,
+    pub term_type: Option<String>,
+    pub theme: Option<ThemeConfig>,
+    pub timestamp: Option<u64>,
+    pub idle_time_limit: Option<f64>,
+    pub command: Option<String>,
+    pub title: Option<String>,
+    pub env: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventData {
+    Output(String),
+    Input(String),
+    Resize(usize, usize),
+    Marker(String),
+    Exit(i32),
+}
+
+/// A single event, with `interval` being the time in seconds since the
+/// previous event
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub interval: f64,
+    pub data: EventData,
+}
+
+#[derive(Debug, Clone)]
+pub struct Asciicast {
+    pub header: Header,
+    pub events: Vec<Entry>,
+}
+
+impl Asciicast {
+    /// Reads a recording file, which may be gzip or zstd compressed
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::parse(output::open(path)?)
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> Result<Self> {
+        let mut lines = reader.lines().enumerate();
+
+        let header = match lines.next() {
+            Some((_, line)) => parse_header(&line?)?,
+            None => bail!("empty recording: missing header"),
+        };
+
+        let mut events = Vec::new();
+        let mut last_time = 0;
+
+        for (i, line) in lines {
+            let line = line?;
+            let line = line.trim();
+
+            // Blank lines and comment lines are allowed by the spec
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(mut entry) =
+                parse_event(line).with_context(|| format!("invalid event on line {}", i + 1))?
+            {
+                if header.format == Format::V2 {
+                    // Out of order timestamps are treated as simultaneous
+                    let time = micros(entry.interval);
+                    entry.interval = seconds(time.saturating_sub(last_time));
+                    last_time = last_time.max(time);
+                }
+
+                events.push(entry);
+            }
+        }
+
+        Ok(Self { header, events })
+    }
+
+    /// Total time of the recording, in seconds
+    pub fn duration(&self) -> f64 {
+        self.events.iter().map(|e| e.interval).sum()
+    }
+
+    /// Terminal size at the end of the recording
+    pub fn final_size(&self) -> (usize, usize) {
+        self.events
+            .iter()
+            .fold((self.header.cols, self.header.rows), |size, e| {
+                match e.data {
+                    EventData::Resize(cols, rows) => (cols, rows),
+                    _ => size,
+                }
+            })
+    }
+}
+
+fn parse_header(line: &str) -> Result<Header> {
+    let value: Value = serde_json::from_str(line).context("invalid header JSON")?;
+
+    match value["version"].as_u64() {
+        Some(2) => parse_v2_header(&value),
+        Some(3) => parse_v3_header(&value),
+        Some(v) => bail!("unsupported asciicast version: {}", v),
+        None => bail!("missing asciicast version in header"),
+    }
+}
+
+fn parse_v2_header(value: &Value) -> Result<Header> {
+    let cols = value["width"].as_u64().context("missing width in header")? as usize;
+
+    let rows = value["height"]
+        .as_u64()
+        .context("missing height in header")? as usize;
+
+    check_size(cols, rows)?;
+
+    let mut env = parse_env(&value["env"]);
+
+    // v2 keeps the terminal type in the env, v3 has a dedicated field for it
+    let term_type = env.remove("TERM");
+
+    Ok(Header {
+        format: Format::V2,
+        cols,
+        rows,
+        term_type,
+        theme: parse_theme(&value["theme"]),
+        timestamp: value["timestamp"].as_u64(),
+        idle_time_limit: value["idle_time_limit"].as_f64(),
+        command: value["command"].as_str().map(String::from),
+        title: value["title"].as_str().map(String::from),
+        env,
+    })
+}
+
+fn parse_v3_header(value: &Value) -> Result<Header> {
+    let term = &value["term"];
+
+    let cols = term["cols"]
+        .as_u64()
+        .context("missing term.cols in header")? as usize;
+
+    let rows = term["rows"]
+        .as_u64()
+        .context("missing term.rows in header")? as usize;
+
+    check_size(cols, rows)?;
+
+    Ok(Header {
+        format: Format::V3,
+        cols,
+        rows,
+        term_type: term["type"].as_str().map(String::from),
+        theme: parse_theme(&term["theme"]),
+        timestamp: value["timestamp"].as_u64(),
+        idle_time_limit: value["idle_time_limit"].as_f64(),
+        command: value["command"].as_str().map(String::from),
+        title: value["title"].as_str().map(String::from),
+        env: parse_env(&value["env"]),
+    })
+}
+
+fn parse_theme(theme: &Value) -> Option<ThemeConfig> {
+    match (theme["fg"].as_str(), theme["bg"].as_str()) {
+        (Some(fg), Some(bg)) => Some(ThemeConfig {
+            fg: fg.to_string(),
+            bg: bg.to_string(),
+            palette: theme["palette"].as_str().map(String::from),
+        }),
+
+        _ => None,
+    }
+}
+
+fn parse_env(env: &Value) -> HashMap<String, String> {
+    env.as_object()
+        .map(|env| {
+            env.iter()
+                .filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Parses an event line, returning `None` for event codes we don't know
+/// about (the spec requires players to ignore them)
+///
+/// The first element is returned as the interval as is, it's up to the
+/// caller to turn v2 timestamps into intervals.
+fn parse_event(line: &str) -> Result<Option<Entry>> {
+    let value: Value = serde_json::from_str(line)?;
+
+    let arr = match value.as_array() {
+        Some(arr) if arr.len() == 3 => arr,
+        _ => bail!("event must be a 3-element array"),
+    };
+
+    let interval = arr[0].as_f64().context("event interval must be a number")?;
+
+    if interval < 0.0 || !interval.is_finite() {
+        bail!("event interval must be non-negative, got {}", interval);
+    }
+
+    let code = arr[1].as_str().context("event code must be a string")?;
+    let data = &arr[2];
+
+    let data = match code {
+        "o" => EventData::Output(string_data(data)?),
+        "i" => EventData::Input(string_data(data)?),
+        "m" => EventData::Marker(string_data(data)?),
+        "r" => {
+            let size = string_data(data)?;
+            let (cols, rows) = parse_size(&size)?;
+            EventData::Resize(cols, rows)
+        }
+        "x" => EventData::Exit(exit_status(data)?),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Entry { interval, data }))
+}
+
+fn string_data(data: &Value) -> Result<String> {
+    data.as_str()
+        .map(String::from)
+        .context("event data must be a string")
+}
+
+fn parse_size(s: &str) -> Result<(usize, usize)> {
+    let (cols, rows) = match s.split_once('x') {
+        Some((cols, rows)) => (cols.parse()?, rows.parse()?),
+        None => bail!("invalid resize data: {}", s),
+    };
+
+    check_size(cols, rows)?;
+
+    Ok((cols, rows))
+}
+
+/// A terminal needs at least one column and one row
+fn check_size(cols: usize, rows: usize) -> Result<()> {
+    if cols == 0 || rows == 0 {
+        bail!("invalid terminal size: {}x{}", cols, rows);
+    }
+
+    Ok(())
+}
+
+/// Exit status is a number per spec, but older writers (including ours)
+/// emitted it as a string, so accept both
+fn exit_status(data: &Value) -> Result<i32> {
+    if let Some(status) = data.as_i64() {
+        return Ok(status as i32);
+    }
+
+    data.as_str()
+        .and_then(|s| s.parse().ok())
+        .context("exit status must be a number")
+}
+
+/// Serializes headers and events in the given format
+///
+/// For v2 the encoder keeps track of the time elapsed since the start of the
+/// recording, so events must be passed in order.
+pub struct Encoder {
+    format: Format,
+    time: u64,
+}
+
+impl Encoder {
+    pub fn new(format: Format) -> Self {
+        Self { format, time: 0 }
+    }
+
+    /// Creates an encoder continuing a recording that's `elapsed` seconds long
+    pub fn continuing(format: Format, elapsed: f64) -> Self {
+        Self {
+            format,
+            time: micros(elapsed),
+        }
+    }
+
+    pub fn header(&self, header: &Header) -> Value {
+        let mut value = match self.format {
+            Format::V2 => {
+                let mut value = json!({
+                    "version": 2,
+                    "width": header.cols,
+                    "height": header.rows,
+                });
+
+                if let Some(theme) = &header.theme {
+                    value["theme"] = theme_json(theme);
+                }
+
+                value
+            }
+
+            Format::V3 => {
+                let mut value = json!({
+                    "version": 3,
+                    "term": {
+                        "cols": header.cols,
+                        "rows": header.rows,
+                    }
+                });
+
+                if let Some(term_type) = &header.term_type {
+                    value["term"]["type"] = json!(term_type);
+                }
+
+                if let Some(theme) = &header.theme {
+                    value["term"]["theme"] = theme_json(theme);
+                }
+
+                value
+            }
+        };
+
+        if let Some(timestamp) = header.timestamp {
+            value["timestamp"] = json!(timestamp);
+        }
+
+        if let Some(idle_time_limit) = header.idle_time_limit {
+            value["idle_time_limit"] = json!(idle_time_limit);
+        }
+
+        if let Some(command) = &header.command {
+            value["command"] = json!(command);
+        }
+
+        if let Some(title) = &header.title {
+            value["title"] = json!(title);
+        }
+
+        let mut env: serde_json::Map<String, Value> = header
+            .env
+            .iter()
+            .map(|(k, v)| (k.clone(), json!(v)))
+            .collect();
+
+        if let (Format::V2, Some(term_type)) = (self.format, &header.term_type) {
+            env.insert("TERM".to_string(), json!(term_type));
+        }
+
+        if !env.is_empty() {
+            value["env"] = Value::Object(env);
+        }
+
+        value
+    }
+
+    /// Returns `None` for events the format can't represent, i.e. exit events
+    /// in v2. Their interval still counts towards later events' timestamps.
+    pub fn event(&mut self, interval: f64, data: &EventData) -> Option<Value> {
+        let time = match self.format {
+            Format::V2 => {
+                self.time += micros(interval);
+                json!(seconds(self.time))
+            }
+
+            Format::V3 => json!(interval),
+        };
+
+        let event = match data {
+            EventData::Output(data) => json!([time, "o", data]),
+            EventData::Input(data) => json!([time, "i", data]),
+            EventData::Resize(cols, rows) => json!([time, "r", format!("{cols}x{rows}")]),
+            EventData::Marker(label) => json!([time, "m", label]),
+            EventData::Exit(status) if self.format == Format::V3 => json!([time, "x", status]),
+            EventData::Exit(_) => return None,
+        };
+
+        Some(event)
+    }
+}
+
+fn theme_json(theme: &ThemeConfig) -> Value {
+    let mut value = json!({
+        "fg": theme.fg,
+        "bg": theme.bg,
+    });
+
+    if let Some(palette) = &theme.palette {
+        value["palette"] = json!(palette);
+    }
+
+    value
+}
+
+fn micros(seconds: f64) -> u64 {
+    (seconds * 1_000_000.0).round() as u64
+}
+
+fn seconds(micros: u64) -> f64 {
+    micros as f64 / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_parse_golden_minimal() {
+        let golden_path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/golden_minimal.cast");
+
+        let cast = Asciicast::open(&golden_path).unwrap();
+
+        assert_eq!(cast.header.cols, 80);
+        assert_eq!(cast.header.rows, 24);
+        assert_eq!(cast.header.term_type.as_deref(), Some("xterm-256color"));
+        assert_eq!(cast.header.timestamp, Some(1504467315));
+        assert_eq!(cast.events.len(), 7);
+
+        assert_eq!(cast.events[2].interval, 3.5);
+        assert_eq!(
+            cast.events[2].data,
+            EventData::Marker("checkpoint".to_string())
+        );
+        assert_eq!(cast.events[4].data, EventData::Resize(90, 30));
+        assert_eq!(cast.events[6].data, EventData::Exit(0));
+    }
+
+    #[test]
+    fn test_parse_skips_unknown_codes_and_comments() {
+        let input = "{\"version\":3,\"term\":{\"cols\":10,\"rows\":5}}\n\
+                     # a comment\n\
+                     \n\
+                     [0.5,\"o\",\"a\"]\n\
+                     [0.1,\"z\",\"future event\"]\n\
+                     [0.2,\"x\",\"1\"]\n";
+
+        let cast = Asciicast::parse(Cursor::new(input)).unwrap();
+
+        assert_eq!(
+            cast.events,
+            vec![
+                Entry {
+                    interval: 0.5,
+                    data: EventData::Output("a".to_string())
+                },
+                Entry {
+                    interval: 0.2,
+                    data: EventData::Exit(1)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_other_versions() {
+        let input = "{\"version\":1,\"width\":80,\"height\":24}\n";
+        assert!(Asciicast::parse(Cursor::new(input)).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_empty_size() {
+        let input = "{\"version\":3,\"term\":{\"cols\":0,\"rows\":0}}\n";
+        assert!(Asciicast::parse(Cursor::new(input)).is_err());
+
+        let input = "{\"version\":3,\"term\":{\"cols\":10,\"rows\":5}}\n\
+                     [0.1,\"r\",\"0x0\"]\n";
+        assert!(Asciicast::parse(Cursor::new(input)).is_err());
+
+        let input = "{\"version\":2,\"width\":80,\"height\":0}\n";
+        assert!(Asciicast::parse(Cursor::new(input)).is_err());
+    }
+
+    #[test]
+    fn test_duration_and_final_size() {
+        let input = "{\"version\":3,\"term\":{\"cols\":10,\"rows\":5}}\n\
+                     [0.5,\"o\",\"a\"]\n\
+                     [0.25,\"r\",\"20x6\"]\n\
+                     [0.25,\"o\",\"b\"]\n";
+
+        let cast = Asciicast::parse(Cursor::new(input)).unwrap();
+
+        assert_eq!(cast.duration(), 1.0);
+        assert_eq!(cast.final_size(), (20, 6));
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let input = "{\"version\":2,\"width\":80,\"height\":24,\"idle_time_limit\":1.5,\
+                     \"env\":{\"TERM\":\"xterm\",\"SHELL\":\"/bin/sh\"},\
+                     \"theme\":{\"fg\":\"#ffffff\",\"bg\":\"#000000\"}}\n\
+                     [0.5,\"o\",\"a\"]\n\
+                     [1.25,\"m\",\"mark\"]\n\
+                     [1.0,\"r\",\"90x30\"]\n\
+                     [4.0,\"i\",\"b\"]\n";
+
+        let cast = Asciicast::parse(Cursor::new(input)).unwrap();
+
+        assert_eq!(cast.header.format, Format::V2);
+        assert_eq!((cast.header.cols, cast.header.rows), (80, 24));
+        assert_eq!(cast.header.term_type.as_deref(), Some("xterm"));
+        assert_eq!(cast.header.env.get("TERM"), None);
+        assert_eq!(cast.header.idle_time_limit, Some(1.5));
+        assert_eq!(cast.header.theme.unwrap().fg, "#ffffff");
+
+        let intervals: Vec<_> = cast.events.iter().map(|e| e.interval).collect();
+
+        assert_eq!(intervals, vec![0.5, 0.75, 0.0, 2.75]);
+        assert_eq!(cast.events[2].data, EventData::Resize(90, 30));
+    }
+
+    #[test]
+    fn test_encode_v2() {
+        let cast = Asciicast::open(
+            &std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("testdata/golden_minimal.cast"),
+        )
+        .unwrap();
+
+        let mut encoder = Encoder::new(Format::V2);
+        let header = encoder.header(&cast.header);
+
+        assert_eq!(
+            header,
+            json!({
+                "version": 2,
+                "width": 80,
+                "height": 24,
+                "timestamp": 1504467315,
+                "env": {"TERM": "xterm-256color"},
+            })
+        );
+
+        let events: Vec<_> = cast
+            .events
+            .iter()
+            .filter_map(|e| encoder.event(e.interval, &e.data))
+            .collect();
+
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[2], json!([4.749, "m", "checkpoint"]));
+        assert_eq!(events[4], json!([6.942, "r", "90x30"]));
+        assert_eq!(events[5], json!([8.483, "o", "Bye!"]));
+    }
+
+    #[test]
+    fn test_v2_round_trip() {
+        let input = "{\"version\":3,\"term\":{\"cols\":10,\"rows\":5,\"type\":\"xterm\"},\
+                     \"idle_time_limit\":2.0,\"title\":\"t\"}\n\
+                     [0.1,\"o\",\"a\"]\n\
+                     [0.2,\"m\",\"\"]\n\
+                     [0.3,\"r\",\"20x6\"]\n\
+                     [0.123456,\"i\",\"b\"]\n";
+
+        let cast = Asciicast::parse(Cursor::new(input)).unwrap();
+        let mut encoder = Encoder::new(Format::V2);
+        let mut v2 = format!("{}\n", encoder.header(&cast.header));
+
+        for e in &cast.events {
+            v2.push_str(&format!(
+                "{}\n",
+                encoder.event(e.interval, &e.data).unwrap()
+            ));
+        }
+
+        let converted = Asciicast::parse(Cursor::new(v2)).unwrap();
+
+        assert_eq!(converted.header.format, Format::V2);
+        assert_eq!(converted.header.term_type.as_deref(), Some("xterm"));
+        assert_eq!(converted.header.idle_time_limit, Some(2.0));
+        assert_eq!(converted.header.title.as_deref(), Some("t"));
+        assert_eq!(converted.events, cast.events);
+    }
+
+    #[test]
+    fn test_parse_rejects_negative_interval() {
+        let input = "{\"version\":3,\"term\":{\"cols\":10,\"rows\":5}}\n[-1.0,\"o\",\"a\"]\n";
+        assert!(Asciicast::parse(Cursor::new(input)).is_err());
+    }
+}
@@ -1,16 +1,22 @@
+use super::asciicast::{Asciicast, Encoder, EventData, Format, Header};
+use super::output::{self, FlushPolicy, OutputFile};
+use crate::redact::{EventFilter, Redactor};
 use crate::session::Event;
-use anyhow::{Context, Result};
-use serde_json::json;
-use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Write};
+use anyhow::{bail, Context, Result};
+use futures_util::FutureExt;
+use std::future;
+use std::io::Write;
 use std::path::PathBuf;
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::{mpsc, oneshot};
-use tokio_stream::StreamExt;
+use tokio::time::Interval;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::{Stream, StreamExt};
 
 #[derive(Debug, Clone)]
 pub struct RecorderConfig {
     pub output_path: PathBuf,
+    pub format: Format,
     pub append: bool,
     pub idle_time_limit: Option<f64>,
     pub title: Option<String>,
@@ -19,6 +25,12 @@ pub struct RecorderConfig {
     pub theme: Option<ThemeConfig>,
     pub term_type: Option<String>,
     pub capture_input: bool,
+    /// Start a new segment once this many (uncompressed) bytes were written
+    pub rotate_size: Option<u64>,
+    /// Start a new segment once the current one is this old
+    pub rotate_time: Option<Duration>,
+    pub flush: FlushPolicy,
+    pub redactor: Redactor,
 }
 
 #[derive(Debug, Clone)]
@@ -28,105 +40,303 @@ pub struct ThemeConfig {
     pub palette: Option<String>,
 }
 
+/// Records the session to an asciicast file, v3 unless configured otherwise
 pub struct AsciicastV3Recorder {
-    writer: BufWriter<File>,
+    writer: OutputFile,
+    encoder: Encoder,
     config: RecorderConfig,
     last_event_time: Option<Instant>,
     start_time: Instant,
     header_written: bool,
+    /// Current segment number, 0 when rotation is disabled
+    segment: usize,
+    segment_start: Instant,
+    /// Terminal state, tracked when rotating so every segment can start
+    /// with the screen as it was
+    vt: Option<avt::Vt>,
+    /// Where the appended part joins the existing recording
+    seam: Option<Seam>,
+    /// Whether the recording was started in the middle of a session, and
+    /// so has to start with the screen as it is
+    mid_session: bool,
+    paused: Option<Pause>,
+    redaction: EventFilter,
 }
 
+/// Requests for a recording running alongside the session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Pause,
+    Resume,
+    Stop,
+}
+
+#[derive(Debug)]
+struct Pause {
+    /// Terminal size when the recording was paused
+    size: (usize, usize),
+    /// Whether the screen changed since
+    dirty: bool,
+}
+
+/// End of an existing recording that's being appended to
+#[derive(Debug)]
+struct Seam {
+    /// Terminal size at the end of the existing recording
+    size: (usize, usize),
+    /// Time between the end of the existing recording and now, capped at
+    /// the idle time limit
+    gap: f64,
+}
+
+/// Label of the marker inserted where an appended recording continues
+const SEAM_MARKER: &str = "append";
+
 impl AsciicastV3Recorder {
     pub fn new(config: RecorderConfig) -> Result<Self> {
-        let file = if config.append {
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&config.output_path)
-                .context("failed to open recording file")?
+        let rotating = config.rotate_size.is_some() || config.rotate_time.is_some();
+
+        if rotating && config.append {
+            bail!("appending can't be combined with rotation");
+        }
+
+        let existing = if config.append {
+            read_existing(&config)?
+        } else {
+            None
+        };
+
+        // v2 timestamps are relative to the start of the recording, so the
+        // appended part has to continue from the end of the existing one
+        let encoder = match &existing {
+            Some(cast) => Encoder::continuing(config.format, cast.duration()),
+            None => Encoder::new(config.format),
+        };
+
+        let seam = existing.map(|cast| {
+            let end = cast.header.timestamp.unwrap_or(0) as f64 + cast.duration();
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64();
+
+            // Without an idle time limit the gap is left out, as it could
+            // be days long
+            let gap = config
+                .idle_time_limit
+                .or(cast.header.idle_time_limit)
+                .map_or(0.0, |limit| (now - end).clamp(0.0, limit));
+
+            Seam {
+                size: cast.final_size(),
+                gap,
+            }
+        });
+
+        let (writer, segment) = if rotating {
+            let path = output::segment_path(&config.output_path, 1);
+
+            (OutputFile::create(&path, false)?, 1)
         } else {
-            File::create(&config.output_path).context("failed to create recording file")?
+            (OutputFile::create(&config.output_path, config.append)?, 0)
         };
 
         Ok(Self {
-            writer: BufWriter::new(file),
+            writer,
+            encoder,
+            redaction: config.redactor.events(),
             config,
             last_event_time: None,
             start_time: Instant::now(),
             header_written: false,
+            segment,
+            segment_start: Instant::now(),
+            vt: None,
+            seam,
+            mid_session: false,
+            paused: None,
         })
     }
 
+    /// Starts the recording with the current screen contents, taken from the
+    /// init event, instead of an empty terminal
+    pub fn mid_session(mut self) -> Self {
+        self.mid_session = true;
+
+        self
+    }
+
     pub async fn run(
         &mut self,
         clients_tx: &mpsc::Sender<crate::session::Client>,
         ready_tx: Option<oneshot::Sender<()>>,
     ) -> Result<()> {
-        let mut events = crate::session::stream(clients_tx).await?;
+        let events = crate::session::lossless_stream(clients_tx).await?;
 
         // Signal that we're subscribed and ready to receive events
         if let Some(tx) = ready_tx {
             let _ = tx.send(());
         }
 
-        while let Some(event_result) = events.next().await {
-            match event_result {
-                Ok(event) => {
-                    self.handle_event(event)?;
+        let (_control_tx, control_rx) = mpsc::channel(1);
+
+        self.record_events(events, control_rx).await
+    }
+
+    /// Records `events` until they end or the recording gets stopped
+    pub async fn record_events(
+        &mut self,
+        events: impl Stream<Item = Result<Event, BroadcastStreamRecvError>>,
+        mut control_rx: mpsc::Receiver<(Control, oneshot::Sender<()>)>,
+    ) -> Result<()> {
+        tokio::pin!(events);
+        let mut controlled = true;
+
+        let mut flush_interval = match self.config.flush {
+            FlushPolicy::Interval(period) => Some(tokio::time::interval(period)),
+            _ => None,
+        };
+
+        loop {
+            tokio::select! {
+                event_result = events.next() => {
+                    match event_result {
+                        Some(Ok(event)) => {
+                            self.handle_event(event)?;
+                        }
+                        Some(Err(e)) => {
+                            // Recorders subscribe losslessly, so this means
+                            // the events came from elsewhere
+                            bail!("recording is incomplete: {e}");
+                        }
+                        None => break,
+                    }
+                }
+
+                control = control_rx.recv(), if controlled => {
+                    let Some((control, done_tx)) = control else {
+                        controlled = false;
+                        continue;
+                    };
+
+                    // The session waits for the request to be done, so all
+                    // events sent before it are already queued, and belong to
+                    // the recording as it was
+                    while let Some(Some(event)) = events.next().now_or_never() {
+                        if let Ok(event) = event {
+                            self.handle_event(event)?;
+                        }
+                    }
+
+                    self.record_held()?;
+
+                    match control {
+                        Control::Pause => self.pause()?,
+                        Control::Resume => self.resume()?,
+                        Control::Stop => break,
+                    }
+
+                    let _ = done_tx.send(());
                 }
-                Err(_) => {
-                    // Lagged behind, continue
-                    continue;
+
+                _ = tick(&mut flush_interval) => {
+                    self.flush()?;
                 }
             }
         }
 
-        self.flush()?;
+        self.record_held()?;
+        self.writer.finish()?;
         Ok(())
     }
 
     fn handle_event(&mut self, event: Event) -> Result<()> {
+        for event in self.redaction.apply(event) {
+            self.record_event(event)?;
+        }
+
+        Ok(())
+    }
+
+    /// Records the output and input held back by the redaction
+    fn record_held(&mut self) -> Result<()> {
+        for event in self.redaction.flush() {
+            self.record_event(event)?;
+        }
+
+        Ok(())
+    }
+
+    fn record_event(&mut self, event: Event) -> Result<()> {
         match event {
-            Event::Init(time, cols, rows, _pid, _seq, _text) => {
+            // The session started over, so the screen is redrawn from scratch
+            Event::Init(_time, cols, rows, _pid, seq, _text, _) if self.header_written => {
+                let interval = self.calculate_interval();
+                self.record(interval, EventData::Resize(cols, rows))?;
+                self.record(0.0, EventData::Output(format!("\x1bc{seq}")))?;
+            }
+
+            Event::Init(time, cols, rows, _pid, seq, _text, _) => {
                 self.start_time = Instant::now();
                 self.last_event_time = Some(self.start_time);
 
-                if !self.header_written || !self.config.append {
-                    self.write_header(cols, rows, time)?;
+                if self.segment > 0 || self.mid_session {
+                    let mut vt = avt::Vt::builder().size(cols, rows).build();
+                    vt.feed_str(&seq);
+                    self.vt = Some(vt);
+                }
+
+                if !self.header_written {
+                    match self.seam.take() {
+                        Some(seam) => self.write_seam(seam, cols, rows)?,
+                        None => self.write_header(cols, rows, time)?,
+                    }
+
                     self.header_written = true;
                 }
 
-                // Do NOT emit init seq - recording starts from first real output
+                // Recordings started along with the session begin with the
+                // first real output, the init seq only matters mid-session
+                if self.mid_session && !seq.is_empty() {
+                    self.write_event(0.0, EventData::Output(seq))?;
+                }
             }
 
             Event::Output(_time, data) => {
                 let interval = self.calculate_interval();
-                self.write_event(interval, "o", &data)?;
+                self.record(interval, EventData::Output(data))?;
             }
 
             Event::Resize(_time, cols, rows) => {
                 let interval = self.calculate_interval();
-                let data = format!("{}x{}", cols, rows);
-                self.write_event(interval, "r", &data)?;
+                self.record(interval, EventData::Resize(cols, rows))?;
             }
 
             Event::Marker(_time, label) => {
                 let interval = self.calculate_interval();
-                self.write_event(interval, "m", &label)?;
+                self.record(interval, EventData::Marker(label))?;
             }
 
             Event::Input(_time, data) if self.config.capture_input => {
                 let interval = self.calculate_interval();
-                self.write_event(interval, "i", &data)?;
+                self.record(interval, EventData::Input(data))?;
             }
 
             Event::Exit(_time, status) => {
                 let interval = self.calculate_interval();
-                self.write_event_with_number(interval, "x", status)?;
+                self.record(interval, EventData::Exit(status))?;
             }
 
-            Event::Snapshot(_, _, _, _) | Event::Input(_, _) => {
-                // Ignore snapshots and input if not capturing
+            Event::Snapshot(_, _, _, _, _)
+            | Event::Input(_, _)
+            | Event::Export(_, _)
+            | Event::Title(_, _)
+            | Event::Cwd(_, _)
+            | Event::Notification(_, _, _)
+            | Event::CommandStarted(_, _)
+            | Event::CommandFinished(_, _)
+            | Event::Clipboard(_, _, _) => {
+                // Ignore snapshots, metadata and input if not capturing
             }
         }
 
@@ -134,78 +344,183 @@ impl AsciicastV3Recorder {
     }
 
     fn write_header(&mut self, cols: usize, rows: usize, _timestamp: f64) -> Result<()> {
-        let mut header = json!({
-            "version": 3,
-            "term": {
-                "cols": cols,
-                "rows": rows,
+        // Use actual Unix timestamp instead of event time
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let env = self
+            .config
+            .capture_env
+            .iter()
+            .filter_map(|key| std::env::var(key).ok().map(|val| (key.clone(), val)))
+            .collect();
+
+        let header = Header {
+            format: self.config.format,
+            cols,
+            rows,
+            term_type: self.config.term_type.clone(),
+            theme: self.config.theme.clone(),
+            timestamp: Some(timestamp),
+            idle_time_limit: self.config.idle_time_limit,
+            command: self.config.command.clone(),
+            title: self.config.title.clone(),
+            env,
+        };
+
+        writeln!(self.writer, "{}", self.encoder.header(&header))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Continues an existing recording, instead of starting with a header
+    fn write_seam(&mut self, seam: Seam, cols: usize, rows: usize) -> Result<()> {
+        self.write_event(seam.gap, EventData::Marker(SEAM_MARKER.to_string()))?;
+
+        if seam.size != (cols, rows) {
+            self.write_event(0.0, EventData::Resize(cols, rows))?;
+        }
+
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    fn record(&mut self, interval: f64, data: EventData) -> Result<()> {
+        if let Some(pause) = &mut self.paused {
+            if let Some(vt) = &mut self.vt {
+                match data {
+                    EventData::Output(data) => {
+                        vt.feed_str(&data);
+                        pause.dirty = true;
+                    }
+
+                    EventData::Resize(cols, rows) => {
+                        vt.resize(cols, rows);
+                        pause.dirty = true;
+                    }
+
+                    _ => (),
+                }
             }
-        });
 
-        if let Some(term_type) = &self.config.term_type {
-            header["term"]["type"] = json!(term_type);
+            return Ok(());
         }
 
-        if let Some(theme) = &self.config.theme {
-            let mut theme_obj = json!({
-                "fg": theme.fg,
-                "bg": theme.bg,
-            });
-            if let Some(palette) = &theme.palette {
-                theme_obj["palette"] = json!(palette);
+        if self.rotation_due() {
+            self.rotate()?;
+        }
+
+        if let Some(vt) = &mut self.vt {
+            match &data {
+                EventData::Output(data) => {
+                    vt.feed_str(data);
+                }
+
+                EventData::Resize(cols, rows) => {
+                    vt.resize(*cols, *rows);
+                }
+
+                _ => (),
             }
-            header["term"]["theme"] = theme_obj;
         }
 
-        // Use actual Unix timestamp instead of event time
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        header["timestamp"] = json!(timestamp);
+        self.write_event(interval, data)
+    }
 
-        if let Some(idle_limit) = self.config.idle_time_limit {
-            header["idle_time_limit"] = json!(idle_limit);
+    fn write_event(&mut self, interval: f64, data: EventData) -> Result<()> {
+        let flush = match self.config.flush {
+            FlushPolicy::Event => true,
+            FlushPolicy::Marker => matches!(data, EventData::Marker(_) | EventData::Exit(_)),
+            FlushPolicy::Interval(_) => matches!(data, EventData::Exit(_)),
+        };
+
+        if let Some(event) = self.encoder.event(interval, &data) {
+            writeln!(self.writer, "{}", event)?;
         }
 
-        if let Some(command) = &self.config.command {
-            header["command"] = json!(command);
+        if flush {
+            self.writer.flush()?;
         }
 
-        if let Some(title) = &self.config.title {
-            header["title"] = json!(title);
+        Ok(())
+    }
+
+    fn rotation_due(&self) -> bool {
+        if self.segment == 0 {
+            return false;
         }
 
-        if !self.config.capture_env.is_empty() {
-            let env_map: serde_json::Map<String, serde_json::Value> = self
-                .config
-                .capture_env
-                .iter()
-                .filter_map(|key| std::env::var(key).ok().map(|val| (key.clone(), json!(val))))
-                .collect();
-            header["env"] = serde_json::Value::Object(env_map);
+        let size_reached = self
+            .config
+            .rotate_size
+            .is_some_and(|size| self.writer.written() >= size);
+
+        let time_reached = self
+            .config
+            .rotate_time
+            .is_some_and(|time| self.segment_start.elapsed() >= time);
+
+        size_reached || time_reached
+    }
+
+    /// Continues the recording in the next segment, which gets its own header
+    /// and starts with the current screen contents, so it can be played on
+    /// its own
+    fn rotate(&mut self) -> Result<()> {
+        self.writer.finish()?;
+        self.segment += 1;
+        let path = output::segment_path(&self.config.output_path, self.segment);
+        self.writer = OutputFile::create(&path, false)?;
+        self.encoder = Encoder::new(self.config.format);
+        self.segment_start = Instant::now();
+
+        let (size, seq) = match &self.vt {
+            Some(vt) => (vt.size(), vt.dump()),
+            None => ((80, 24), String::new()),
+        };
+
+        self.write_header(size.0, size.1, 0.0)?;
+
+        if !seq.is_empty() {
+            self.write_event(0.0, EventData::Output(seq))?;
         }
 
-        writeln!(self.writer, "{}", header)?;
-        self.writer.flush()?;
         Ok(())
     }
 
-    fn write_event(&mut self, interval: f64, code: &str, data: &str) -> Result<()> {
-        let event = json!([interval, code, data]);
-        writeln!(self.writer, "{}", event)?;
+    fn pause(&mut self) -> Result<()> {
+        if self.paused.is_none() {
+            let size = self.vt.as_ref().map_or((0, 0), |vt| vt.size());
+            self.paused = Some(Pause { size, dirty: false });
+            self.flush()?;
+        }
 
-        // Flush frequently to avoid data loss on crash
-        self.writer.flush()?;
         Ok(())
     }
 
-    fn write_event_with_number(&mut self, interval: f64, code: &str, data: i32) -> Result<()> {
-        let event = json!([interval, code, data]);
-        writeln!(self.writer, "{}", event)?;
+    /// Continues a paused recording as if no time passed, catching up with
+    /// the screen if it changed in the meantime
+    fn resume(&mut self) -> Result<()> {
+        let Some(pause) = self.paused.take() else {
+            return Ok(());
+        };
+
+        self.last_event_time = Some(Instant::now());
+
+        if let (true, Some(vt)) = (pause.dirty, &self.vt) {
+            let size = vt.size();
+            let seq = format!("\x1bc{}", vt.dump());
+
+            if size != pause.size {
+                self.write_event(0.0, EventData::Resize(size.0, size.1))?;
+            }
+
+            self.write_event(0.0, EventData::Output(seq))?;
+        }
 
-        // Flush frequently to avoid data loss on crash
-        self.writer.flush()?;
         Ok(())
     }
 
@@ -233,19 +548,74 @@ impl AsciicastV3Recorder {
     }
 }
 
+/// Reads the recording being appended to, returning `None` when there's
+/// nothing to continue
+fn read_existing(config: &RecorderConfig) -> Result<Option<Asciicast>> {
+    let path = &config.output_path;
+
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.len() > 0 => (),
+        _ => return Ok(None),
+    }
+
+    let cast = Asciicast::open(path)
+        .with_context(|| format!("can't append to {}: not a valid recording", path.display()))?;
+
+    if cast.header.format != config.format {
+        bail!(
+            "can't append to {}: it's an asciicast {} recording, not {}",
+            path.display(),
+            cast.header.format,
+            config.format
+        );
+    }
+
+    Ok(Some(cast))
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+
+        None => future::pending().await,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::session::Metadata;
+    use std::fs::File;
     use std::io::BufRead;
 
+    /// Default recorder config, for tests to adjust with struct updates
+    pub(super) fn config(output_path: PathBuf) -> RecorderConfig {
+        RecorderConfig {
+            output_path,
+            format: Format::V3,
+            append: false,
+            idle_time_limit: None,
+            title: None,
+            command: None,
+            capture_env: vec![],
+            theme: None,
+            term_type: None,
+            capture_input: false,
+            rotate_size: None,
+            rotate_time: None,
+            flush: FlushPolicy::Event,
+            redactor: Redactor::default(),
+        }
+    }
+
     #[test]
     fn test_header_generation() {
         let temp_dir = std::env::temp_dir();
         let test_file = temp_dir.join(format!("test_asciicast_{}.cast", uuid::Uuid::new_v4()));
 
         let config = RecorderConfig {
-            output_path: test_file.clone(),
-            append: false,
             idle_time_limit: Some(2.0),
             title: Some("Test Recording".to_string()),
             command: Some("bash".to_string()),
@@ -256,7 +626,7 @@ mod tests {
                 palette: None,
             }),
             term_type: Some("xterm-256color".to_string()),
-            capture_input: false,
+            ..config(test_file.clone())
         };
 
         let mut recorder = AsciicastV3Recorder::new(config).unwrap();
@@ -284,23 +654,17 @@ mod tests {
         let temp_dir = std::env::temp_dir();
         let test_file = temp_dir.join(format!("test_events_{}.cast", uuid::Uuid::new_v4()));
 
-        let config = RecorderConfig {
-            output_path: test_file.clone(),
-            append: false,
-            idle_time_limit: None,
-            title: None,
-            command: None,
-            capture_env: vec![],
-            theme: None,
-            term_type: None,
-            capture_input: false,
-        };
-
-        let mut recorder = AsciicastV3Recorder::new(config).unwrap();
+        let mut recorder = AsciicastV3Recorder::new(config(test_file.clone())).unwrap();
         recorder.write_header(80, 24, 0.0).unwrap();
-        recorder.write_event(0.5, "o", "hello\n").unwrap();
-        recorder.write_event(1.0, "r", "100x30").unwrap();
-        recorder.write_event(0.1, "m", "checkpoint").unwrap();
+        recorder
+            .write_event(0.5, EventData::Output("hello\n".to_string()))
+            .unwrap();
+        recorder
+            .write_event(1.0, EventData::Resize(100, 30))
+            .unwrap();
+        recorder
+            .write_event(0.1, EventData::Marker("checkpoint".to_string()))
+            .unwrap();
         recorder.flush().unwrap();
 
         let file = File::open(&test_file).unwrap();
@@ -326,6 +690,361 @@ mod tests {
 
         std::fs::remove_file(test_file).ok();
     }
+
+    #[test]
+    fn test_v2_output() {
+        let temp_dir = std::env::temp_dir();
+        let test_file = temp_dir.join(format!("test_v2_{}.cast", uuid::Uuid::new_v4()));
+
+        let config = RecorderConfig {
+            format: Format::V2,
+            idle_time_limit: Some(1.0),
+            term_type: Some("xterm-256color".to_string()),
+            ..config(test_file.clone())
+        };
+
+        let mut recorder = AsciicastV3Recorder::new(config).unwrap();
+        recorder.write_header(80, 24, 0.0).unwrap();
+        recorder
+            .write_event(0.5, EventData::Output("hello\n".to_string()))
+            .unwrap();
+        recorder
+            .write_event(1.0, EventData::Resize(100, 30))
+            .unwrap();
+        recorder.write_event(0.25, EventData::Exit(0)).unwrap();
+        recorder
+            .write_event(0.25, EventData::Marker("after".to_string()))
+            .unwrap();
+        recorder.flush().unwrap();
+
+        let file = File::open(&test_file).unwrap();
+        let reader = std::io::BufReader::new(file);
+        let lines: Vec<serde_json::Value> = reader
+            .lines()
+            .map(|l| serde_json::from_str(&l.unwrap()).unwrap())
+            .collect();
+
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 24);
+        assert_eq!(lines[0]["idle_time_limit"], 1.0);
+        assert_eq!(lines[0]["env"]["TERM"], "xterm-256color");
+
+        // Exit events can't be represented in v2, but their time still counts
+        assert_eq!(
+            lines[1..],
+            [
+                serde_json::json!([0.5, "o", "hello\n"]),
+                serde_json::json!([1.5, "r", "100x30"]),
+                serde_json::json!([2.0, "m", "after"]),
+            ]
+        );
+
+        std::fs::remove_file(test_file).ok();
+    }
+
+    /// Records a session with the given size and output
+    fn record_session(config: RecorderConfig, cols: usize, rows: usize, output: &str) {
+        let mut recorder = AsciicastV3Recorder::new(config).unwrap();
+
+        recorder
+            .handle_event(Event::Init(
+                0.0,
+                cols,
+                rows,
+                0,
+                "".to_string(),
+                "".to_string(),
+                Metadata::default(),
+            ))
+            .unwrap();
+        recorder
+            .handle_event(Event::Output(0.0, output.to_string()))
+            .unwrap();
+        recorder.writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_append() {
+        let test_file =
+            std::env::temp_dir().join(format!("test_append_{}.cast", uuid::Uuid::new_v4()));
+
+        record_session(config(test_file.clone()), 80, 24, "first");
+
+        let config = RecorderConfig {
+            append: true,
+            ..config(test_file.clone())
+        };
+
+        record_session(config, 100, 30, "second");
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&test_file)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        // Single header, then a marker at the seam followed by the new size
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0]["version"], 3);
+        assert_eq!(lines[1][2], "first");
+        assert_eq!(lines[2][1], "m");
+        assert_eq!(lines[2][2], SEAM_MARKER);
+        assert_eq!(lines[3][1], "r");
+        assert_eq!(lines[3][2], "100x30");
+        assert_eq!(lines[4][2], "second");
+
+        std::fs::remove_file(test_file).ok();
+    }
+
+    #[test]
+    fn test_append_same_size() {
+        let test_file =
+            std::env::temp_dir().join(format!("test_append_{}.cast.gz", uuid::Uuid::new_v4()));
+
+        record_session(config(test_file.clone()), 80, 24, "first");
+
+        let config = RecorderConfig {
+            append: true,
+            ..config(test_file.clone())
+        };
+
+        record_session(config, 80, 24, "second");
+
+        let cast = Asciicast::open(&test_file).unwrap();
+
+        assert_eq!(
+            cast.events
+                .iter()
+                .map(|e| e.data.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                EventData::Output("first".to_string()),
+                EventData::Marker(SEAM_MARKER.to_string()),
+                EventData::Output("second".to_string()),
+            ]
+        );
+
+        std::fs::remove_file(test_file).ok();
+    }
+
+    #[test]
+    fn test_v2_append() {
+        let test_file =
+            std::env::temp_dir().join(format!("test_append_v2_{}.cast", uuid::Uuid::new_v4()));
+
+        std::fs::write(
+            &test_file,
+            "{\"version\":2,\"width\":80,\"height\":24,\"timestamp\":1000,\"idle_time_limit\":2.0}\n\
+             [1.5,\"o\",\"first\"]\n",
+        )
+        .unwrap();
+
+        let config = RecorderConfig {
+            format: Format::V2,
+            append: true,
+            ..config(test_file.clone())
+        };
+
+        record_session(config, 80, 24, "second");
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&test_file)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        // The seam gap is capped at the idle time limit of the existing
+        // recording, and timestamps continue from where it ended
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[2], serde_json::json!([3.5, "m", SEAM_MARKER]));
+        assert_eq!(lines[3][1], "o");
+        assert!(lines[3][0].as_f64().unwrap() >= 3.5);
+
+        std::fs::remove_file(test_file).ok();
+    }
+
+    #[test]
+    fn test_append_format_mismatch() {
+        let test_file =
+            std::env::temp_dir().join(format!("test_append_{}.cast", uuid::Uuid::new_v4()));
+
+        record_session(config(test_file.clone()), 80, 24, "first");
+
+        let v2 = RecorderConfig {
+            format: Format::V2,
+            append: true,
+            ..config(test_file.clone())
+        };
+
+        assert!(AsciicastV3Recorder::new(v2).is_err());
+
+        std::fs::write(&test_file, "not a recording\n").unwrap();
+
+        let v3 = RecorderConfig {
+            append: true,
+            ..config(test_file.clone())
+        };
+
+        assert!(AsciicastV3Recorder::new(v3).is_err());
+
+        std::fs::remove_file(test_file).ok();
+    }
+
+    #[test]
+    fn test_session_restart() {
+        let test_file =
+            std::env::temp_dir().join(format!("test_restart_{}.cast", uuid::Uuid::new_v4()));
+        let mut recorder = AsciicastV3Recorder::new(config(test_file.clone())).unwrap();
+
+        recorder
+            .handle_event(Event::Init(
+                0.0,
+                10,
+                2,
+                0,
+                "".to_string(),
+                "".to_string(),
+                Metadata::default(),
+            ))
+            .unwrap();
+        recorder
+            .handle_event(Event::Output(0.0, "a".to_string()))
+            .unwrap();
+        recorder
+            .handle_event(Event::Init(
+                1.0,
+                12,
+                3,
+                0,
+                "b".to_string(),
+                "".to_string(),
+                Metadata::default(),
+            ))
+            .unwrap();
+        recorder.writer.finish().unwrap();
+
+        let cast = Asciicast::open(&test_file).unwrap();
+
+        assert_eq!((cast.header.cols, cast.header.rows), (10, 2));
+        assert_eq!(
+            cast.events
+                .iter()
+                .map(|e| e.data.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                EventData::Output("a".to_string()),
+                EventData::Resize(12, 3),
+                EventData::Output("\x1bcb".to_string()),
+            ]
+        );
+
+        std::fs::remove_file(test_file).ok();
+    }
+
+    #[test]
+    fn test_size_rotation() {
+        let dir = std::env::temp_dir().join(format!("test_rotation_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+
+        let config = RecorderConfig {
+            rotate_size: Some(1),
+            ..config(dir.join("session.cast"))
+        };
+
+        let mut recorder = AsciicastV3Recorder::new(config).unwrap();
+
+        recorder
+            .handle_event(Event::Init(
+                0.0,
+                10,
+                2,
+                0,
+                "x".to_string(),
+                "".to_string(),
+                Metadata::default(),
+            ))
+            .unwrap();
+        recorder
+            .handle_event(Event::Output(0.0, "\x1b[31ma".to_string()))
+            .unwrap();
+        recorder.handle_event(Event::Resize(0.0, 12, 3)).unwrap();
+        recorder.writer.finish().unwrap();
+
+        let first = Asciicast::open(&dir.join("session.001.cast")).unwrap();
+        let second = Asciicast::open(&dir.join("session.002.cast")).unwrap();
+        let third = Asciicast::open(&dir.join("session.003.cast")).unwrap();
+
+        assert_eq!((first.header.cols, first.header.rows), (10, 2));
+        assert_eq!(first.events.len(), 0);
+
+        // Every segment starts with the screen as it was
+        assert_eq!(second.events.len(), 2);
+        assert_eq!(second.events[0].interval, 0.0);
+        assert!(matches!(&second.events[0].data, EventData::Output(seq) if seq.contains('x')));
+        assert!(matches!(&second.events[1].data, EventData::Output(data) if data == "\x1b[31ma"));
+
+        assert_eq!((third.header.cols, third.header.rows), (10, 2));
+        let EventData::Output(seq) = &third.events[0].data else {
+            panic!("segment should start with the screen contents");
+        };
+
+        let mut vt = avt::Vt::new(10, 2);
+        vt.feed_str(seq);
+
+        assert_eq!(vt.view()[0].text().trim_end(), "xa");
+        assert_eq!(third.events[1].data, EventData::Resize(12, 3));
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_compressed_output() {
+        let test_file =
+            std::env::temp_dir().join(format!("test_gz_{}.cast.gz", uuid::Uuid::new_v4()));
+
+        let config = RecorderConfig {
+            flush: FlushPolicy::Marker,
+            ..config(test_file.clone())
+        };
+
+        let mut recorder = AsciicastV3Recorder::new(config).unwrap();
+
+        recorder
+            .handle_event(Event::Init(
+                0.0,
+                80,
+                24,
+                0,
+                "".to_string(),
+                "".to_string(),
+                Metadata::default(),
+            ))
+            .unwrap();
+        recorder
+            .handle_event(Event::Output(0.0, "hello".to_string()))
+            .unwrap();
+        recorder
+            .handle_event(Event::Marker(0.0, "done".to_string()))
+            .unwrap();
+        recorder.writer.finish().unwrap();
+
+        let cast = Asciicast::open(&test_file).unwrap();
+
+        assert_eq!(cast.header.cols, 80);
+        assert_eq!(
+            cast.events
+                .iter()
+                .map(|e| e.data.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                EventData::Output("hello".to_string()),
+                EventData::Marker("done".to_string())
+            ]
+        );
+
+        std::fs::remove_file(test_file).ok();
+    }
 }
 
 #[cfg(test)]
@@ -2,8 +2,11 @@
 ///
 /// These tests validate our implementation against the official asciicast v3
 /// specification and known-good examples.
+use super::tests::config;
 use super::*;
+use crate::session::Metadata;
 use serde_json::Value;
+use std::fs::File;
 use std::io::{BufRead, BufReader};
 
 #[test]
@@ -121,19 +124,7 @@ fn test_our_writer_no_spurious_init_output() {
     let temp_dir = std::env::temp_dir();
     let test_file = temp_dir.join(format!("test_no_init_{}.cast", uuid::Uuid::new_v4()));
 
-    let config = RecorderConfig {
-        output_path: test_file.clone(),
-        append: false,
-        idle_time_limit: None,
-        title: None,
-        command: None,
-        capture_env: vec![],
-        theme: None,
-        term_type: None,
-        capture_input: false,
-    };
-
-    let mut recorder = AsciicastV3Recorder::new(config).unwrap();
+    let mut recorder = AsciicastV3Recorder::new(config(test_file.clone())).unwrap();
 
     // Simulate Init event
     recorder
@@ -144,6 +135,7 @@ fn test_our_writer_no_spurious_init_output() {
             1234,
             "initial state".to_string(),
             "text view".to_string(),
+            Metadata::default(),
         ))
         .unwrap();
 
@@ -190,23 +182,19 @@ fn test_interval_monotonicity() {
     let temp_dir = std::env::temp_dir();
     let test_file = temp_dir.join(format!("test_monotonic_{}.cast", uuid::Uuid::new_v4()));
 
-    let config = RecorderConfig {
-        output_path: test_file.clone(),
-        append: false,
-        idle_time_limit: None,
-        title: None,
-        command: None,
-        capture_env: vec![],
-        theme: None,
-        term_type: None,
-        capture_input: false,
-    };
-
-    let mut recorder = AsciicastV3Recorder::new(config).unwrap();
+    let mut recorder = AsciicastV3Recorder::new(config(test_file.clone())).unwrap();
 
     // Simulate rapid events
     recorder
-        .handle_event(Event::Init(0.0, 80, 24, 1234, "".to_string(), "".to_string()))
+        .handle_event(Event::Init(
+            0.0,
+            80,
+            24,
+            1234,
+            "".to_string(),
+            "".to_string(),
+            Metadata::default(),
+        ))
         .unwrap();
 
     for i in 0..100 {
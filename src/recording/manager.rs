@@ -0,0 +1,206 @@
+/// Recordings started and stopped while the session is running
+use super::asciicast_v3::{AsciicastV3Recorder, Control, RecorderConfig};
+use crate::session::Subscription;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// Active recordings, keyed by their output path
+#[derive(Default)]
+pub struct Recordings {
+    active: HashMap<PathBuf, Recording>,
+}
+
+struct Recording {
+    control_tx: mpsc::Sender<(Control, oneshot::Sender<()>)>,
+    capture_input: bool,
+    handle: JoinHandle<()>,
+}
+
+impl Recordings {
+    /// Starts recording the session to `config.output_path`, beginning with
+    /// the screen from the subscription's init event
+    pub fn start(&mut self, config: RecorderConfig, subscription: Subscription) -> Result<()> {
+        self.active
+            .retain(|_, recording| !recording.handle.is_finished());
+        let path = config.output_path.clone();
+
+        if self.active.contains_key(&path) {
+            bail!("already recording to {}", path.display());
+        }
+
+        let capture_input = config.capture_input;
+        let mut recorder = AsciicastV3Recorder::new(config)?.mid_session();
+        let (control_tx, control_rx) = mpsc::channel(16);
+        let events = subscription.into_stream();
+        let display_path = path.display().to_string();
+
+        let handle = tokio::spawn(async move {
+            if let Err(e) = recorder.record_events(events, control_rx).await {
+                eprintln!("recording to {display_path} failed: {e:#}");
+            }
+        });
+
+        eprintln!("recording to {}", path.display());
+
+        self.active.insert(
+            path,
+            Recording {
+                control_tx,
+                capture_input,
+                handle,
+            },
+        );
+
+        Ok(())
+    }
+
+    pub async fn stop(&mut self, path: &Path) -> Result<()> {
+        self.control(path, Control::Stop).await?;
+
+        if let Some(recording) = self.active.remove(path) {
+            let _ = recording.handle.await;
+            eprintln!("recording to {} stopped", path.display());
+        }
+
+        Ok(())
+    }
+
+    pub async fn pause(&mut self, path: &Path) -> Result<()> {
+        self.control(path, Control::Pause).await
+    }
+
+    pub async fn resume(&mut self, path: &Path) -> Result<()> {
+        self.control(path, Control::Resume).await
+    }
+
+    /// Whether any of the recordings wants input events
+    pub fn capture_input(&self) -> bool {
+        self.active
+            .values()
+            .any(|recording| recording.capture_input)
+    }
+
+    /// Waits for all recordings to write out the remaining events, which
+    /// they do once the session is gone
+    pub async fn finish(self) {
+        for (_, recording) in self.active {
+            let _ = recording.handle.await;
+        }
+    }
+
+    /// Sends `control` to the recording and waits for it to be applied, so
+    /// it takes effect exactly between the events sent before and after
+    async fn control(&self, path: &Path, control: Control) -> Result<()> {
+        let Some(recording) = self.active.get(path) else {
+            bail!("not recording to {}", path.display());
+        };
+
+        let (done_tx, done_rx) = oneshot::channel();
+
+        if recording.control_tx.send((control, done_tx)).await.is_err() {
+            bail!("recording to {} has ended", path.display());
+        }
+
+        // Stopping ends the recording without confirmation
+        let _ = done_rx.await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::asciicast::{Asciicast, EventData, Format};
+    use crate::recording::output::FlushPolicy;
+    use crate::redact::Redactor;
+    use crate::session::Session;
+
+    fn config(output_path: PathBuf) -> RecorderConfig {
+        RecorderConfig {
+            output_path,
+            format: Format::V3,
+            append: false,
+            idle_time_limit: None,
+            title: None,
+            command: None,
+            capture_env: vec![],
+            theme: None,
+            term_type: None,
+            capture_input: false,
+            rotate_size: None,
+            rotate_time: None,
+            flush: FlushPolicy::Event,
+            redactor: Redactor::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_start_pause_resume_stop() {
+        let dir = std::env::temp_dir().join(format!("test_recordings_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let first = dir.join("first.cast");
+        let second = dir.join("second.cast");
+
+        let mut session = Session::new(10, 2, 0);
+        let mut recordings = Recordings::default();
+
+        session.output("before".to_string());
+        recordings
+            .start(config(first.clone()), session.subscribe_lossless())
+            .unwrap();
+        assert!(recordings
+            .start(config(first.clone()), session.subscribe_lossless())
+            .is_err());
+
+        // Controls apply after the events already sent
+        session.output("\r\nduring".to_string());
+        recordings.pause(&first).await.unwrap();
+
+        session.output("\x1b[Hpaused".to_string());
+        recordings
+            .start(config(second.clone()), session.subscribe_lossless())
+            .unwrap();
+
+        recordings.resume(&first).await.unwrap();
+        session.output("!".to_string());
+        recordings.stop(&first).await.unwrap();
+        assert!(recordings.pause(&first).await.is_err());
+
+        drop(session);
+        recordings.finish().await;
+
+        let cast = Asciicast::open(&first).unwrap();
+        let outputs: Vec<_> = cast
+            .events
+            .iter()
+            .filter_map(|e| match &e.data {
+                EventData::Output(data) => Some(data.clone()),
+                _ => None,
+            })
+            .collect();
+
+        // Starts with the screen as it was, then the screen is redrawn on
+        // resume, since it changed while paused
+        assert_eq!(outputs.len(), 4);
+        assert!(outputs[0].contains("before"));
+        assert_eq!(outputs[1], "\r\nduring");
+        assert!(outputs[2].starts_with("\x1bc") && outputs[2].contains("paused"));
+        assert_eq!(outputs[3], "!");
+
+        let cast = Asciicast::open(&second).unwrap();
+        let EventData::Output(seq) = &cast.events[0].data else {
+            panic!("recording should start with the screen contents");
+        };
+
+        let mut vt = avt::Vt::new(10, 2);
+        vt.feed_str(seq);
+        assert_eq!(vt.view()[0].text(), "paused    ");
+        assert_eq!(cast.events[1].data, EventData::Output("!".to_string()));
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
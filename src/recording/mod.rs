@@ -1 +1,4 @@
+pub mod asciicast;
 pub mod asciicast_v3;
+pub mod manager;
+pub mod output;
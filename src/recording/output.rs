@@ -0,0 +1,255 @@
+/// Recording output files
+///
+/// Handles compression (picked from the file extension), numbered segment
+/// paths for rotated recordings and the flush policy.
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// When buffered events are written out to the file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlushPolicy {
+    /// After every event, so nothing is lost on crash
+    Event,
+    /// Periodically
+    Interval(Duration),
+    /// After every marker
+    Marker,
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "event" => Ok(FlushPolicy::Event),
+            "marker" => Ok(FlushPolicy::Marker),
+            _ => {
+                let secs: f64 = s.trim_end_matches('s').parse().map_err(|_| {
+                    format!("invalid flush policy: {s} (use event, marker or SECONDS)")
+                })?;
+
+                if secs <= 0.0 || !secs.is_finite() {
+                    return Err(format!("invalid flush interval: {s}"));
+                }
+
+                Ok(FlushPolicy::Interval(Duration::from_secs_f64(secs)))
+            }
+        }
+    }
+}
+
+/// Opens a recording file for reading, decompressing it if needed
+pub fn open(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file =
+        File::open(path).with_context(|| format!("failed to open recording {}", path.display()))?;
+
+    let reader: Box<dyn BufRead> = match Compression::from_path(path) {
+        Compression::None => Box::new(BufReader::new(file)),
+        Compression::Gzip => Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(file))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::new(file)?)),
+    };
+
+    Ok(reader)
+}
+
+/// A recording file, optionally compressed
+pub struct OutputFile {
+    inner: Inner,
+    written: u64,
+}
+
+enum Inner {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl OutputFile {
+    /// Creates (or appends to) the file at `path`
+    ///
+    /// Appending to compressed files adds a new gzip member or zstd frame,
+    /// and decoders read those back as one continuous stream.
+    pub fn create(path: &Path, append: bool) -> Result<Self> {
+        let file = if append {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .context("failed to open recording file")?
+        } else {
+            File::create(path).context("failed to create recording file")?
+        };
+
+        let writer = BufWriter::new(file);
+
+        let inner = match Compression::from_path(path) {
+            Compression::None => Inner::Plain(writer),
+            Compression::Gzip => {
+                Inner::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+            }
+            Compression::Zstd => Inner::Zstd(zstd::Encoder::new(writer, 0)?),
+        };
+
+        Ok(Self { inner, written: 0 })
+    }
+
+    /// Number of (uncompressed) bytes written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Writes out the compression trailer, if any, and flushes the file
+    ///
+    /// Nothing may be written to the file afterwards.
+    pub fn finish(&mut self) -> Result<()> {
+        match &mut self.inner {
+            Inner::Plain(writer) => writer.flush()?,
+
+            Inner::Gzip(encoder) => {
+                encoder.try_finish()?;
+                encoder.get_mut().flush()?;
+            }
+
+            Inner::Zstd(encoder) => {
+                encoder.do_finish()?;
+                encoder.get_mut().flush()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match &mut self.inner {
+            Inner::Plain(writer) => writer.write(buf)?,
+            Inner::Gzip(encoder) => encoder.write(buf)?,
+            Inner::Zstd(encoder) => encoder.write(buf)?,
+        };
+
+        self.written += n as u64;
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Plain(writer) => writer.flush(),
+            Inner::Gzip(encoder) => encoder.flush(),
+            Inner::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Path of the `n`-th segment of a rotated recording, e.g. `session.003.cast.gz`
+/// for `session.cast.gz`
+pub fn segment_path(path: &Path, n: usize) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let compression_ext_len = match Compression::from_path(path) {
+        Compression::None => 0,
+        Compression::Gzip => ".gz".len(),
+        Compression::Zstd => ".zst".len(),
+    };
+
+    let (name, compression_ext) = name.split_at(name.len() - compression_ext_len);
+
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{ext}")),
+        _ => (name, String::new()),
+    };
+
+    path.with_file_name(format!("{stem}.{n:03}{ext}{compression_ext}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_segment_path() {
+        assert_eq!(
+            segment_path(Path::new("/tmp/session.cast"), 1),
+            PathBuf::from("/tmp/session.001.cast")
+        );
+        assert_eq!(
+            segment_path(Path::new("session.cast.gz"), 12),
+            PathBuf::from("session.012.cast.gz")
+        );
+        assert_eq!(
+            segment_path(Path::new("build.log.cast.zst"), 2),
+            PathBuf::from("build.log.002.cast.zst")
+        );
+        assert_eq!(
+            segment_path(Path::new("session"), 3),
+            PathBuf::from("session.003")
+        );
+    }
+
+    #[test]
+    fn test_flush_policy_from_str() {
+        assert_eq!("event".parse(), Ok(FlushPolicy::Event));
+        assert_eq!("marker".parse(), Ok(FlushPolicy::Marker));
+        assert_eq!(
+            "2.5s".parse(),
+            Ok(FlushPolicy::Interval(Duration::from_millis(2500)))
+        );
+        assert_eq!(
+            "10".parse(),
+            Ok(FlushPolicy::Interval(Duration::from_secs(10)))
+        );
+        assert!("0".parse::<FlushPolicy>().is_err());
+        assert!("sometimes".parse::<FlushPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_compressed_append() {
+        for ext in ["gz", "zst"] {
+            let path = std::env::temp_dir()
+                .join(format!("test_output_{}.cast.{ext}", uuid::Uuid::new_v4()));
+
+            let mut file = OutputFile::create(&path, false).unwrap();
+            file.write_all(b"hello ").unwrap();
+            assert_eq!(file.written(), 6);
+            file.finish().unwrap();
+
+            let mut file = OutputFile::create(&path, true).unwrap();
+            file.write_all(b"world").unwrap();
+            file.finish().unwrap();
+
+            let mut data = String::new();
+            open(&path).unwrap().read_to_string(&mut data).unwrap();
+
+            assert_eq!(data, "hello world");
+
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
@@ -0,0 +1,24 @@
+/// Animated PNG encoder
+use super::raster::Rasterizer;
+use super::{delays, TimedFrame};
+use anyhow::Result;
+use png::{BitDepth, ColorType, Encoder};
+use std::io::Write;
+
+pub fn write<W: Write>(out: W, frames: &[TimedFrame], rasterizer: &mut Rasterizer) -> Result<()> {
+    let mut encoder = Encoder::new(out, rasterizer.width(), rasterizer.height());
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0)?;
+    let mut writer = encoder.write_header()?;
+
+    // Frame delays are expressed as a fraction, here in milliseconds
+    for (timed, delay) in frames.iter().zip(delays(frames, 1000.0)) {
+        writer.set_frame_delay(delay.min(u16::MAX as u64) as u16, 1000)?;
+        writer.write_image_data(&rasterizer.render(&timed.frame))?;
+    }
+
+    writer.finish()?;
+
+    Ok(())
+}
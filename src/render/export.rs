@@ -0,0 +1,371 @@
+/// Static exports of a single screen
+///
+/// Used for sharing the current terminal state, e.g. in bug reports.
+use super::{svg, Cell, Frame, Palette};
+use std::fmt::Write;
+use std::str::FromStr;
+
+const SVG_FONT_SIZE: f64 = 14.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Html,
+    Svg,
+    Ansi,
+}
+
+impl ExportFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportFormat::Html => "html",
+            ExportFormat::Svg => "svg",
+            ExportFormat::Ansi => "ansi",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Html => "text/html; charset=utf-8",
+            ExportFormat::Svg => "image/svg+xml",
+            ExportFormat::Ansi => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "html" => Ok(ExportFormat::Html),
+            "svg" => Ok(ExportFormat::Svg),
+            "ansi" => Ok(ExportFormat::Ansi),
+            _ => Err(format!("invalid export format: {s}")),
+        }
+    }
+}
+
+pub fn export(frame: &Frame, palette: &Palette, format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Html => html(frame, palette),
+        ExportFormat::Svg => svg::still(frame, palette, SVG_FONT_SIZE),
+        ExportFormat::Ansi => ansi(frame, palette),
+    }
+}
+
+/// URL schemes made into links, others could run scripts when clicked
+const LINK_SCHEMES: [&str; 5] = ["http:", "https:", "ftp:", "mailto:", "file:"];
+
+/// Standalone HTML document with inline styles
+fn html(frame: &Frame, palette: &Palette) -> String {
+    let mut body = String::new();
+
+    for row in 0..frame.lines.len() {
+        let cells = frame.visible_line(row);
+        let mut links: Vec<_> = frame.links.iter().filter(|l| l.row == row).collect();
+        links.sort_by_key(|l| l.cols.start);
+        let mut col = 0;
+
+        for link in links {
+            let safe = LINK_SCHEMES
+                .iter()
+                .any(|scheme| link.url.to_ascii_lowercase().starts_with(scheme));
+
+            // Overlapping links are printed over each other, the first wins
+            if !safe || link.cols.start < col || link.cols.end > cells.len() {
+                continue;
+            }
+
+            html_runs(&mut body, &group(&cells[col..link.cols.start]), palette);
+            let _ = write!(body, "<a href=\"{}\">", escape_html(&link.url));
+            html_runs(&mut body, &group(&cells[link.cols.clone()]), palette);
+            body.push_str("</a>");
+            col = link.cols.end;
+        }
+
+        html_runs(&mut body, &runs(&cells[col..], palette), palette);
+        body.push('\n');
+    }
+
+    format!(
+        "<!DOCTYPE html>\n\
+         <html>\n\
+         <head>\n\
+         <meta charset=\"utf-8\">\n\
+         <title>ht snapshot</title>\n\
+         </head>\n\
+         <body style=\"margin: 0; background-color: {bg}\">\n\
+         <pre style=\"margin: 0; padding: 1em; font-family: 'DejaVu Sans Mono', Menlo, Consolas, monospace; font-size: 14px; line-height: 1.2; color: {fg}; background-color: {bg}\">\n\
+         {body}\
+         </pre>\n\
+         </body>\n\
+         </html>\n",
+        fg = palette.fg.hex(),
+        bg = palette.bg.hex(),
+    )
+}
+
+fn html_runs(body: &mut String, runs: &[Run], palette: &Palette) {
+    for run in runs {
+        let style = css(&run.style, palette);
+        let text = escape_html(&run.text);
+
+        if style.is_empty() {
+            body.push_str(&text);
+        } else {
+            let _ = write!(body, "<span style=\"{style}\">{text}</span>");
+        }
+    }
+}
+
+/// Text with SGR sequences, reproducing the screen when printed to a terminal
+///
+/// Cells in the theme's default colors are left uncolored so they blend in
+/// with the terminal the text is printed to.
+fn ansi(frame: &Frame, palette: &Palette) -> String {
+    let mut out = String::new();
+
+    for line in &frame.lines {
+        let mut styled = false;
+
+        for run in runs(line, palette) {
+            let sgr = sgr(&run.style, palette);
+
+            if sgr != "0" || styled {
+                let _ = write!(out, "\x1b[{sgr}m");
+                styled = sgr != "0";
+            }
+
+            out.push_str(&run.text);
+        }
+
+        if styled {
+            out.push_str("\x1b[0m");
+        }
+
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Cells sharing the same style, with the first cell carrying the style
+struct Run {
+    style: Cell,
+    text: String,
+}
+
+/// Runs of a whole line, without trailing blanks
+fn runs(cells: &[Cell], palette: &Palette) -> Vec<Run> {
+    let mut runs = group(cells);
+
+    // Trailing blanks without any visible styling carry no information
+    while let Some(run) = runs.last_mut() {
+        let invisible =
+            run.style.bg == palette.bg && !run.style.underline && !run.style.strikethrough;
+
+        if !invisible {
+            break;
+        }
+
+        run.text.truncate(run.text.trim_end_matches(' ').len());
+
+        if !run.text.is_empty() {
+            break;
+        }
+
+        runs.pop();
+    }
+
+    runs
+}
+
+/// Runs of a part of a line
+fn group(cells: &[Cell]) -> Vec<Run> {
+    let mut runs: Vec<Run> = Vec::new();
+
+    for cell in cells {
+        // The second half of a wide character is covered by the first
+        if cell.width == 0 {
+            continue;
+        }
+
+        match runs.last_mut() {
+            Some(run) if same_style(&run.style, cell) => run.text.push(cell.ch),
+
+            _ => runs.push(Run {
+                style: *cell,
+                text: cell.ch.to_string(),
+            }),
+        }
+    }
+
+    runs
+}
+
+fn same_style(a: &Cell, b: &Cell) -> bool {
+    a.fg == b.fg
+        && a.bg == b.bg
+        && a.bold == b.bold
+        && a.italic == b.italic
+        && a.underline == b.underline
+        && a.strikethrough == b.strikethrough
+}
+
+fn css(cell: &Cell, palette: &Palette) -> String {
+    let mut props = Vec::new();
+
+    if cell.fg != palette.fg {
+        props.push(format!("color: {}", cell.fg.hex()));
+    }
+
+    if cell.bg != palette.bg {
+        props.push(format!("background-color: {}", cell.bg.hex()));
+    }
+
+    if cell.bold {
+        props.push("font-weight: bold".to_string());
+    }
+
+    if cell.italic {
+        props.push("font-style: italic".to_string());
+    }
+
+    match (cell.underline, cell.strikethrough) {
+        (true, true) => props.push("text-decoration: underline line-through".to_string()),
+        (true, false) => props.push("text-decoration: underline".to_string()),
+        (false, true) => props.push("text-decoration: line-through".to_string()),
+        (false, false) => (),
+    }
+
+    props.join("; ")
+}
+
+fn sgr(cell: &Cell, palette: &Palette) -> String {
+    let mut params = vec!["0".to_string()];
+
+    if cell.bold {
+        params.push("1".to_string());
+    }
+
+    if cell.italic {
+        params.push("3".to_string());
+    }
+
+    if cell.underline {
+        params.push("4".to_string());
+    }
+
+    if cell.strikethrough {
+        params.push("9".to_string());
+    }
+
+    if cell.fg != palette.fg {
+        let c = cell.fg;
+        params.push(format!("38;2;{};{};{}", c.0, c.1, c.2));
+    }
+
+    if cell.bg != palette.bg {
+        let c = cell.bg;
+        params.push(format!("48;2;{};{};{}", c.0, c.1, c.2));
+    }
+
+    params.join(";")
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+
+    for ch in text.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::Hyperlink;
+
+    fn frame(input: &str) -> (Frame, Palette) {
+        let palette = Palette::default();
+        let mut vt = avt::Vt::new(10, 2);
+        vt.feed_str("\x1b[?25l");
+        vt.feed_str(input);
+
+        (Frame::capture(&vt, &palette), palette)
+    }
+
+    #[test]
+    fn test_html() {
+        let (frame, palette) = frame("\x1b[1;31m<a>\x1b[0m & b");
+        let html = export(&frame, &palette, ExportFormat::Html);
+        let red = palette.resolve(avt::Color::Indexed(9)).hex();
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains(&format!(
+            "<span style=\"color: {red}; font-weight: bold\">&lt;a&gt;</span> &amp; b\n\n</pre>"
+        )));
+    }
+
+    #[test]
+    fn test_html_links() {
+        let (mut frame, palette) = frame("see \x1b[1mdocs\x1b[0m x");
+
+        let link = |url: &str, cols| Hyperlink {
+            url: url.to_string(),
+            row: 0,
+            cols,
+            text: String::new(),
+        };
+
+        frame.links = vec![
+            link("https://a.example/?q=\"", 4..8),
+            link("javascript:x", 9..10),
+        ];
+        let html = export(&frame, &palette, ExportFormat::Html);
+
+        assert!(html.contains(
+            "see <a href=\"https://a.example/?q=&quot;\"><span style=\"font-weight: bold\">docs</span></a> x\n"
+        ));
+    }
+
+    #[test]
+    fn test_ansi() {
+        let (frame, palette) = frame("a\x1b[4mb\x1b[0m\r\n\x1b[42m \x1b[0mc  ");
+        let ansi = export(&frame, &palette, ExportFormat::Ansi);
+        let green = palette.resolve(avt::Color::Indexed(2));
+
+        assert_eq!(
+            ansi,
+            format!(
+                "a\x1b[0;4mb\x1b[0m\n\x1b[0;48;2;{};{};{}m \x1b[0mc\n",
+                green.0, green.1, green.2
+            )
+        );
+    }
+
+    #[test]
+    fn test_svg() {
+        let (frame, palette) = frame("x");
+        let svg = export(&frame, &palette, ExportFormat::Svg);
+
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(!svg.contains("@keyframes"));
+        assert!(svg.contains(">x</text>"));
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!("html".parse(), Ok(ExportFormat::Html));
+        assert_eq!("ansi".parse(), Ok(ExportFormat::Ansi));
+        assert!("pdf".parse::<ExportFormat>().is_err());
+    }
+}
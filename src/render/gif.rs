@@ -0,0 +1,68 @@
+/// Animated GIF encoder
+use super::raster::Rasterizer;
+use super::{delays, TimedFrame};
+use anyhow::{bail, Result};
+use gif::{Encoder, Frame, Repeat};
+use std::collections::HashMap;
+use std::io::Write;
+
+/// NeuQuant sampling factor, used only for frames with more than 256 colors
+const QUANTIZE_SPEED: i32 = 10;
+
+pub fn write<W: Write>(out: W, frames: &[TimedFrame], rasterizer: &mut Rasterizer) -> Result<()> {
+    let (width, height) = (rasterizer.width(), rasterizer.height());
+
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        bail!("image too large for GIF: {}x{}", width, height);
+    }
+
+    let (width, height) = (width as u16, height as u16);
+    let mut encoder = Encoder::new(out, width, height, &[])?;
+    encoder.set_repeat(Repeat::Infinite)?;
+
+    // GIF delays are in hundredths of a second
+    for (timed, delay) in frames.iter().zip(delays(frames, 100.0)) {
+        let mut pixels = rasterizer.render(&timed.frame);
+        let mut frame = indexed_frame(width, height, &pixels)
+            .unwrap_or_else(|| Frame::from_rgba_speed(width, height, &mut pixels, QUANTIZE_SPEED));
+
+        frame.delay = delay.min(u16::MAX as u64) as u16;
+        encoder.write_frame(&frame)?;
+    }
+
+    Ok(())
+}
+
+/// Builds an exact palette when the frame has at most 256 distinct colors,
+/// which is the common case for terminal content
+fn indexed_frame(width: u16, height: u16, rgba: &[u8]) -> Option<Frame<'static>> {
+    let mut colors: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut palette = Vec::new();
+    let mut indices = Vec::with_capacity(rgba.len() / 4);
+
+    for pixel in rgba.chunks_exact(4) {
+        let rgb = [pixel[0], pixel[1], pixel[2]];
+
+        let index = match colors.get(&rgb) {
+            Some(index) => *index,
+
+            None => {
+                if colors.len() == 256 {
+                    return None;
+                }
+
+                let index = colors.len() as u8;
+                colors.insert(rgb, index);
+                palette.extend_from_slice(&rgb);
+
+                index
+            }
+        };
+
+        indices.push(index);
+    }
+
+    Some(Frame::from_palette_pixels(
+        width, height, indices, palette, None,
+    ))
+}
@@ -0,0 +1,707 @@
+/// Offline rendering of terminal recordings
+///
+/// Recordings are replayed through an `avt::Vt`, sampled into frames at a
+/// fixed frame rate and then encoded by one of the format backends.
+pub mod apng;
+pub mod export;
+pub mod gif;
+pub mod raster;
+pub mod svg;
+
+use crate::recording::asciicast::{Asciicast, EventData};
+use crate::recording::asciicast_v3::ThemeConfig;
+use crate::streaming::alis;
+use anyhow::{bail, Context, Result};
+use raster::Rasterizer;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// Default asciinema player theme, used when a recording doesn't carry one
+const DEFAULT_FG: Rgb = Rgb(0xcc, 0xcc, 0xcc);
+const DEFAULT_BG: Rgb = Rgb(0x12, 0x13, 0x14);
+
+const DEFAULT_PALETTE: [Rgb; 16] = [
+    Rgb(0x00, 0x00, 0x00),
+    Rgb(0xdd, 0x3c, 0x69),
+    Rgb(0x4e, 0xbf, 0x22),
+    Rgb(0xdd, 0xaf, 0x3c),
+    Rgb(0x26, 0xb0, 0xd7),
+    Rgb(0xb9, 0x54, 0xe1),
+    Rgb(0x54, 0xe1, 0xb9),
+    Rgb(0xd9, 0xd9, 0xd9),
+    Rgb(0x4d, 0x4d, 0x4d),
+    Rgb(0xdd, 0x3c, 0x69),
+    Rgb(0x4e, 0xbf, 0x22),
+    Rgb(0xdd, 0xaf, 0x3c),
+    Rgb(0x26, 0xb0, 0xd7),
+    Rgb(0xb9, 0x54, 0xe1),
+    Rgb(0x54, 0xe1, 0xb9),
+    Rgb(0xff, 0xff, 0xff),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+
+    /// Mixes `other` into this color, `amount` being in 0.0..=1.0
+    pub fn blend(&self, other: Rgb, amount: f32) -> Rgb {
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * amount).round() as u8;
+
+        Rgb(
+            mix(self.0, other.0),
+            mix(self.1, other.1),
+            mix(self.2, other.2),
+        )
+    }
+
+    fn parse(color: &str) -> Result<Rgb> {
+        let [r, g, b] = alis::parse_color(color)?;
+
+        Ok(Rgb(r, g, b))
+    }
+}
+
+/// Resolved terminal colors
+#[derive(Debug, Clone)]
+pub struct Palette {
+    pub fg: Rgb,
+    pub bg: Rgb,
+    colors: [Rgb; 16],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            colors: DEFAULT_PALETTE,
+        }
+    }
+}
+
+impl Palette {
+    pub fn from_theme(theme: Option<&ThemeConfig>) -> Result<Self> {
+        let mut palette = Self::default();
+
+        if let Some(theme) = theme {
+            palette.fg = Rgb::parse(&theme.fg)?;
+            palette.bg = Rgb::parse(&theme.bg)?;
+
+            if let Some(colors) = &theme.palette {
+                let colors = colors
+                    .split(':')
+                    .map(Rgb::parse)
+                    .collect::<Result<Vec<_>>>()?;
+
+                match colors.len() {
+                    8 => {
+                        // Bright variants fall back to the normal ones
+                        palette.colors[..8].copy_from_slice(&colors);
+                        palette.colors[8..].copy_from_slice(&colors);
+                    }
+
+                    16 => palette.colors.copy_from_slice(&colors),
+
+                    n => bail!("theme palette must have 8 or 16 colors, got {}", n),
+                }
+            }
+        }
+
+        Ok(palette)
+    }
+
+    /// The 16 indexed colors, normal ones first, then bright ones
+    pub fn colors(&self) -> &[Rgb; 16] {
+        &self.colors
+    }
+
+    pub fn resolve(&self, color: avt::Color) -> Rgb {
+        match color {
+            avt::Color::Indexed(n) if n < 16 => self.colors[n as usize],
+
+            avt::Color::Indexed(n) if n < 232 => {
+                let n = n - 16;
+                let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+
+                Rgb(level(n / 36), level((n / 6) % 6), level(n % 6))
+            }
+
+            avt::Color::Indexed(n) => {
+                let v = 8 + (n - 232) * 10;
+
+                Rgb(v, v, v)
+            }
+
+            avt::Color::RGB(c) => Rgb(c.r, c.g, c.b),
+        }
+    }
+}
+
+/// A single screen cell with colors already resolved against a palette
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cell {
+    pub ch: char,
+    pub width: usize,
+    pub fg: Rgb,
+    pub bg: Rgb,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+}
+
+/// A snapshot of the terminal screen
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub cols: usize,
+    pub rows: usize,
+    pub lines: Vec<Vec<Cell>>,
+    pub cursor: Option<(usize, usize)>,
+    /// Links on the screen, used by the HTML export
+    pub links: Vec<Hyperlink>,
+}
+
+/// Text printed as a link (OSC 8), within a single row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hyperlink {
+    pub url: String,
+    pub row: usize,
+    pub cols: Range<usize>,
+    pub text: String,
+}
+
+impl Frame {
+    pub fn capture(vt: &avt::Vt, palette: &Palette) -> Self {
+        let (cols, rows) = vt.size();
+
+        let lines = vt
+            .view()
+            .iter()
+            .map(|line| {
+                line.cells()
+                    .iter()
+                    .map(|cell| resolve_cell(cell, palette))
+                    .collect()
+            })
+            .collect();
+
+        let cursor = vt.cursor();
+        let cursor = cursor.visible.then_some((cursor.col, cursor.row));
+
+        Self {
+            cols,
+            rows,
+            lines,
+            cursor,
+            links: Vec::new(),
+        }
+    }
+
+    /// Returns the cells of a line, with the cursor shown as inverted colors
+    pub fn visible_line(&self, row: usize) -> Vec<Cell> {
+        let mut cells = self.lines[row].clone();
+
+        if let Some((col, cursor_row)) = self.cursor {
+            if cursor_row == row && col < cells.len() {
+                let cell = &mut cells[col];
+                std::mem::swap(&mut cell.fg, &mut cell.bg);
+            }
+        }
+
+        cells
+    }
+}
+
+fn resolve_cell(cell: &avt::Cell, palette: &Palette) -> Cell {
+    let pen = cell.pen();
+
+    let mut fg = match pen.foreground() {
+        // Bold text in one of the 8 base colors is shown in its bright variant
+        Some(avt::Color::Indexed(n)) if n < 8 && pen.is_bold() => {
+            palette.resolve(avt::Color::Indexed(n + 8))
+        }
+
+        Some(color) => palette.resolve(color),
+        None => palette.fg,
+    };
+
+    let mut bg = pen
+        .background()
+        .map(|c| palette.resolve(c))
+        .unwrap_or(palette.bg);
+
+    if pen.is_inverse() {
+        std::mem::swap(&mut fg, &mut bg);
+    }
+
+    if pen.is_faint() {
+        fg = fg.blend(bg, 0.5);
+    }
+
+    Cell {
+        ch: cell.char(),
+        width: cell.width(),
+        fg,
+        bg,
+        bold: pen.is_bold(),
+        italic: pen.is_italic(),
+        underline: pen.is_underline(),
+        strikethrough: pen.is_strikethrough(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderConfig {
+    pub speed: f64,
+    pub fps: f64,
+    pub idle_time_limit: Option<f64>,
+    pub last_frame_duration: f64,
+    pub marker_pause: f64,
+    pub chapter: Option<String>,
+}
+
+/// A frame shown for `duration` seconds
+#[derive(Debug, Clone)]
+pub struct TimedFrame {
+    pub frame: Frame,
+    pub duration: f64,
+}
+
+/// Replays the recording and samples the screen into frames
+///
+/// Screen states that don't survive until the next frame boundary are
+/// dropped, and consecutive identical frames are merged.
+pub fn timeline(
+    cast: &Asciicast,
+    palette: &Palette,
+    config: &RenderConfig,
+) -> Result<Vec<TimedFrame>> {
+    if config.speed <= 0.0 || !config.speed.is_finite() {
+        bail!("invalid speed: {}", config.speed);
+    }
+
+    if config.fps <= 0.0 || !config.fps.is_finite() {
+        bail!("invalid frame rate: {}", config.fps);
+    }
+
+    if config.marker_pause < 0.0 || !config.marker_pause.is_finite() {
+        bail!("invalid marker pause: {}", config.marker_pause);
+    }
+
+    if config.last_frame_duration < 0.0 || !config.last_frame_duration.is_finite() {
+        bail!(
+            "invalid last frame duration: {}",
+            config.last_frame_duration
+        );
+    }
+
+    let idle_time_limit = config.idle_time_limit.or(cast.header.idle_time_limit);
+
+    if let Some(limit) = idle_time_limit.filter(|limit| *limit <= 0.0 || !limit.is_finite()) {
+        bail!("invalid idle time limit: {}", limit);
+    }
+
+    let (start, end) = chapter_range(cast, config.chapter.as_deref())?;
+    let mut vt = avt::Vt::builder()
+        .size(cast.header.cols, cast.header.rows)
+        .build();
+
+    // Everything before the chapter only sets up the screen
+    for entry in &cast.events[..start] {
+        apply(&mut vt, &entry.data);
+    }
+
+    let events = &cast.events[start..end];
+    let delay = |i: usize| -> f64 {
+        let interval = events[i].interval;
+        let interval = idle_time_limit.map_or(interval, |limit| interval.min(limit));
+
+        interval / config.speed
+    };
+
+    let slot = |time: f64| (time * config.fps).floor();
+    let mut keyframes = vec![(0.0, Frame::capture(&vt, palette))];
+    let mut time = 0.0;
+
+    for (i, entry) in events.iter().enumerate() {
+        time += delay(i);
+        apply(&mut vt, &entry.data);
+
+        if matches!(entry.data, EventData::Marker(_)) {
+            time += config.marker_pause;
+        }
+
+        let next_time = if i + 1 < events.len() {
+            time + delay(i + 1)
+        } else {
+            f64::INFINITY
+        };
+
+        if slot(next_time) > slot(time) {
+            let frame_time = slot(time) / config.fps;
+            let frame = Frame::capture(&vt, palette);
+
+            match keyframes.last_mut() {
+                Some((_, last)) if *last == frame => (),
+                Some((last_time, last)) if *last_time == frame_time => *last = frame,
+                _ => keyframes.push((frame_time, frame)),
+            }
+        }
+    }
+
+    let mut frames = Vec::with_capacity(keyframes.len());
+    let mut keyframes = keyframes.into_iter().peekable();
+
+    while let Some((time, frame)) = keyframes.next() {
+        let duration = match keyframes.peek() {
+            Some((next_time, _)) => next_time - time,
+            None => config.last_frame_duration,
+        };
+
+        frames.push(TimedFrame { frame, duration });
+    }
+
+    Ok(frames)
+}
+
+fn chapter_range(cast: &Asciicast, chapter: Option<&str>) -> Result<(usize, usize)> {
+    let Some(label) = chapter else {
+        return Ok((0, cast.events.len()));
+    };
+
+    let is_marker = |data: &EventData| matches!(data, EventData::Marker(_));
+
+    let start = cast
+        .events
+        .iter()
+        .position(|e| matches!(&e.data, EventData::Marker(l) if l == label));
+
+    let Some(start) = start else {
+        bail!("no marker labelled {:?} in recording", label);
+    };
+
+    let end = cast.events[start + 1..]
+        .iter()
+        .position(|e| is_marker(&e.data))
+        .map_or(cast.events.len(), |i| start + 1 + i);
+
+    // The chapter starts right after its marker
+    Ok((start + 1, end))
+}
+
+fn apply(vt: &mut avt::Vt, data: &EventData) {
+    match data {
+        EventData::Output(data) => {
+            vt.feed_str(data);
+        }
+
+        EventData::Resize(cols, rows) => {
+            vt.resize(*cols, *rows);
+        }
+
+        EventData::Input(_) | EventData::Marker(_) | EventData::Exit(_) => (),
+    }
+}
+
+/// Output formats of the render subcommand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Gif,
+    Apng,
+    Svg,
+}
+
+impl Format {
+    /// Takes the format given, or else the one of the output file's extension
+    fn resolve(format: Option<&str>, out: &Path) -> Result<Self> {
+        let format = match format {
+            Some(format) => format.to_lowercase(),
+            None => out
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ext.to_lowercase())
+                .unwrap_or_default(),
+        };
+
+        match format.as_str() {
+            "gif" => Ok(Format::Gif),
+            "apng" | "png" => Ok(Format::Apng),
+            "svg" => Ok(Format::Svg),
+            _ => bail!("invalid render format: {:?} (use gif, apng or svg)", format),
+        }
+    }
+}
+
+/// Renders the recording into the file at `out`
+///
+/// The format, the frames and the rasterizer are all prepared before the file
+/// is created, so that an existing file isn't truncated by a render which
+/// can't succeed.
+pub fn render_file(
+    cast: &Asciicast,
+    out: &Path,
+    format: Option<&str>,
+    config: &RenderConfig,
+    font_size: f64,
+) -> Result<()> {
+    let format = Format::resolve(format, out)?;
+    let palette = Palette::from_theme(cast.header.theme.as_ref())?;
+    let frames = timeline(cast, &palette, config)?;
+    let cols = frames.iter().map(|f| f.frame.cols).max().unwrap_or(0);
+    let rows = frames.iter().map(|f| f.frame.rows).max().unwrap_or(0);
+
+    let mut rasterizer = match format {
+        Format::Gif | Format::Apng => {
+            Some(Rasterizer::new(font_size as f32, cols, rows, palette.bg)?)
+        }
+        Format::Svg => None,
+    };
+
+    let file = File::create(out).context("failed to create output file")?;
+    let mut writer = BufWriter::new(file);
+
+    eprintln!("rendering {} frames to {:?}", frames.len(), out);
+
+    match (format, rasterizer.as_mut()) {
+        (Format::Gif, Some(rasterizer)) => gif::write(&mut writer, &frames, rasterizer)?,
+        (Format::Apng, Some(rasterizer)) => apng::write(&mut writer, &frames, rasterizer)?,
+        _ => writer.write_all(svg::animation(&frames, &palette, font_size).as_bytes())?,
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Converts frame durations into integer delays in the given time unit,
+/// rounding cumulative timestamps so errors don't add up over long
+/// recordings
+pub fn delays(frames: &[TimedFrame], units_per_sec: f64) -> Vec<u64> {
+    let mut time = 0.0;
+    let mut prev = 0;
+
+    frames
+        .iter()
+        .map(|f| {
+            time += f.duration;
+            let now = (time * units_per_sec).round() as u64;
+            let delay = now.saturating_sub(prev).max(1);
+            prev = now;
+
+            delay
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn cast(events: &str) -> Asciicast {
+        let input = format!("{{\"version\":3,\"term\":{{\"cols\":10,\"rows\":2}}}}\n{events}");
+
+        Asciicast::parse(Cursor::new(input)).unwrap()
+    }
+
+    fn config() -> RenderConfig {
+        RenderConfig {
+            speed: 1.0,
+            fps: 10.0,
+            idle_time_limit: None,
+            last_frame_duration: 1.0,
+            marker_pause: 0.0,
+            chapter: None,
+        }
+    }
+
+    fn text(frame: &Frame) -> String {
+        frame.lines[0]
+            .iter()
+            .map(|c| c.ch)
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+
+    #[test]
+    fn test_timeline_samples_and_merges() {
+        let cast = cast(
+            "[0.5,\"o\",\"a\"]\n\
+             [0.01,\"o\",\"b\"]\n\
+             [1.0,\"m\",\"\"]\n\
+             [1.0,\"o\",\"c\"]\n",
+        );
+
+        let palette = Palette::from_theme(None).unwrap();
+        let frames = timeline(&cast, &palette, &config()).unwrap();
+        let texts: Vec<_> = frames.iter().map(|f| text(&f.frame)).collect();
+        let durations: Vec<_> = frames.iter().map(|f| f.duration).collect();
+
+        // "a" alone never reaches a frame boundary, and the marker doesn't
+        // change the screen
+        assert_eq!(texts, vec!["", "ab", "abc"]);
+        assert_eq!(durations, vec![0.5, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn test_timeline_idle_time_limit_and_speed() {
+        let cast = cast("[10.0,\"o\",\"a\"]\n");
+        let palette = Palette::from_theme(None).unwrap();
+
+        let config = RenderConfig {
+            speed: 2.0,
+            idle_time_limit: Some(1.0),
+            ..config()
+        };
+
+        let frames = timeline(&cast, &palette, &config).unwrap();
+
+        assert_eq!(frames[0].duration, 0.5);
+    }
+
+    #[test]
+    fn test_timeline_invalid_config() {
+        let cast = cast("[1.0,\"o\",\"a\"]\n");
+        let palette = Palette::from_theme(None).unwrap();
+
+        for value in [-1.0, f64::NAN, f64::INFINITY] {
+            let configs = [
+                RenderConfig {
+                    idle_time_limit: Some(value),
+                    ..config()
+                },
+                RenderConfig {
+                    marker_pause: value,
+                    ..config()
+                },
+                RenderConfig {
+                    last_frame_duration: value,
+                    ..config()
+                },
+            ];
+
+            for config in configs {
+                assert!(timeline(&cast, &palette, &config).is_err());
+            }
+        }
+
+        let zero_limit = RenderConfig {
+            idle_time_limit: Some(0.0),
+            ..config()
+        };
+
+        assert!(timeline(&cast, &palette, &zero_limit).is_err());
+
+        // The recording's own limit too
+        let input = "{\"version\":3,\"term\":{\"cols\":10,\"rows\":2},\"idle_time_limit\":-2}\n";
+        let cast = Asciicast::parse(Cursor::new(input)).unwrap();
+        assert!(timeline(&cast, &palette, &config()).is_err());
+    }
+
+    #[test]
+    fn test_timeline_chapter() {
+        let cast = cast(
+            "[0.5,\"o\",\"a\"]\n\
+             [0.5,\"m\",\"two\"]\n\
+             [0.5,\"o\",\"b\"]\n\
+             [0.5,\"m\",\"three\"]\n\
+             [0.5,\"o\",\"c\"]\n",
+        );
+
+        let palette = Palette::from_theme(None).unwrap();
+
+        let config = RenderConfig {
+            chapter: Some("two".to_string()),
+            ..config()
+        };
+
+        let frames = timeline(&cast, &palette, &config).unwrap();
+        let texts: Vec<_> = frames.iter().map(|f| text(&f.frame)).collect();
+
+        assert_eq!(texts, vec!["a", "ab"]);
+        assert!(timeline(
+            &cast,
+            &palette,
+            &RenderConfig {
+                chapter: Some("nope".to_string()),
+                ..config
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_palette_resolution() {
+        let theme = ThemeConfig {
+            fg: "#ffffff".to_string(),
+            bg: "#000000".to_string(),
+            palette: Some(
+                "#000001:#000002:#000003:#000004:#000005:#000006:#000007:#000008".to_string(),
+            ),
+        };
+
+        let palette = Palette::from_theme(Some(&theme)).unwrap();
+
+        assert_eq!(palette.resolve(avt::Color::Indexed(1)), Rgb(0, 0, 2));
+        assert_eq!(palette.resolve(avt::Color::Indexed(9)), Rgb(0, 0, 2));
+        assert_eq!(palette.resolve(avt::Color::Indexed(16)), Rgb(0, 0, 0));
+        assert_eq!(
+            palette.resolve(avt::Color::Indexed(231)),
+            Rgb(255, 255, 255)
+        );
+        assert_eq!(palette.resolve(avt::Color::Indexed(232)), Rgb(8, 8, 8));
+        assert_eq!(palette.resolve(avt::Color::rgb(1, 2, 3)), Rgb(1, 2, 3));
+    }
+
+    #[test]
+    fn test_render_file_checks_format_first() {
+        let cast = cast("[0.5,\"o\",\"a\"]\n");
+        let dir = std::env::temp_dir().join(format!("ht-render-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // An unknown extension leaves an existing file untouched
+        let notes = dir.join("notes.txt");
+        std::fs::write(&notes, "notes").unwrap();
+        assert!(render_file(&cast, &notes, None, &config(), 14.0).is_err());
+        assert_eq!(std::fs::read_to_string(&notes).unwrap(), "notes");
+
+        // And so does an invalid config
+        let mut invalid = config();
+        invalid.fps = 0.0;
+        assert!(render_file(&cast, &notes, Some("svg"), &invalid, 14.0).is_err());
+        assert_eq!(std::fs::read_to_string(&notes).unwrap(), "notes");
+
+        let out = dir.join("demo.svg");
+        render_file(&cast, &out, None, &config(), 14.0).unwrap();
+        assert!(std::fs::read_to_string(&out).unwrap().starts_with("<svg"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_delays_do_not_drift() {
+        let frame = Frame {
+            cols: 0,
+            rows: 0,
+            lines: vec![],
+            cursor: None,
+            links: vec![],
+        };
+
+        let frames: Vec<_> = (0..3)
+            .map(|_| TimedFrame {
+                frame: frame.clone(),
+                duration: 1.0 / 3.0,
+            })
+            .collect();
+
+        assert_eq!(delays(&frames, 100.0), vec![33, 34, 33]);
+    }
+}
@@ -0,0 +1,224 @@
+/// Rasterizes frames into RGBA pixel buffers using the bundled font
+use super::{Frame, Rgb};
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+const FONT_REGULAR: &[u8] = include_bytes!("../../fonts/DejaVuSansMono.ttf");
+const FONT_BOLD: &[u8] = include_bytes!("../../fonts/DejaVuSansMono-Bold.ttf");
+
+/// Glyph coverage relative to the top-left corner of its cell
+struct GlyphBitmap {
+    left: i32,
+    top: i32,
+    width: u32,
+    height: u32,
+    coverage: Vec<f32>,
+}
+
+pub struct Rasterizer {
+    regular: FontRef<'static>,
+    bold: FontRef<'static>,
+    scale: PxScale,
+    ascent: f32,
+    cell_width: u32,
+    cell_height: u32,
+    padding: u32,
+    cols: usize,
+    rows: usize,
+    background: Rgb,
+    glyphs: HashMap<(char, bool), Option<GlyphBitmap>>,
+}
+
+impl Rasterizer {
+    /// Creates a rasterizer producing images large enough for `cols` x `rows`
+    /// cells, with the margin filled with `background`
+    pub fn new(font_size: f32, cols: usize, rows: usize, background: Rgb) -> Result<Self> {
+        let regular = FontRef::try_from_slice(FONT_REGULAR).context("invalid bundled font")?;
+        let bold = FontRef::try_from_slice(FONT_BOLD).context("invalid bundled font")?;
+        let scale = PxScale::from(font_size);
+        let scaled = regular.as_scaled(scale);
+        let cell_width = scaled.h_advance(regular.glyph_id('M')).ceil() as u32;
+        let cell_height = (scaled.height() + scaled.line_gap()).ceil() as u32;
+
+        Ok(Self {
+            ascent: scaled.ascent(),
+            regular,
+            bold,
+            scale,
+            cell_width,
+            cell_height,
+            padding: cell_width,
+            cols,
+            rows,
+            background,
+            glyphs: HashMap::new(),
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.cols as u32 * self.cell_width + 2 * self.padding
+    }
+
+    pub fn height(&self) -> u32 {
+        self.rows as u32 * self.cell_height + 2 * self.padding
+    }
+
+    /// Renders the frame into an RGBA buffer of `width()` x `height()` pixels
+    pub fn render(&mut self, frame: &Frame) -> Vec<u8> {
+        let mut image = Image {
+            width: self.width(),
+            height: self.height(),
+            pixels: vec![0; (self.width() * self.height() * 4) as usize],
+        };
+
+        image.fill_rect(0, 0, image.width, image.height, self.background);
+
+        for (row, line) in frame.lines.iter().enumerate().take(self.rows) {
+            for (col, cell) in line.iter().enumerate().take(self.cols) {
+                let x = self.padding + col as u32 * self.cell_width;
+                let y = self.padding + row as u32 * self.cell_height;
+                let (mut fg, mut bg) = (cell.fg, cell.bg);
+
+                if frame.cursor == Some((col, row)) {
+                    std::mem::swap(&mut fg, &mut bg);
+                }
+
+                image.fill_rect(x, y, self.cell_width, self.cell_height, bg);
+
+                // The second half of a wide character is drawn by the first
+                if cell.width == 0 || cell.ch == ' ' {
+                    continue;
+                }
+
+                if let Some(glyph) = self.glyph(cell.ch, cell.bold) {
+                    image.draw_glyph(x as i32, y as i32, glyph, fg);
+                }
+
+                let baseline = y + self.ascent.round() as u32;
+
+                if cell.underline {
+                    image.fill_rect(x, baseline + 1, self.cell_width, 1, fg);
+                }
+
+                if cell.strikethrough {
+                    image.fill_rect(x, baseline - self.cell_height / 3, self.cell_width, 1, fg);
+                }
+            }
+        }
+
+        image.pixels
+    }
+
+    fn glyph(&mut self, ch: char, bold: bool) -> Option<&GlyphBitmap> {
+        let font = if bold { &self.bold } else { &self.regular };
+        let (scale, ascent) = (self.scale, self.ascent);
+
+        self.glyphs
+            .entry((ch, bold))
+            .or_insert_with(|| {
+                let glyph = font
+                    .glyph_id(ch)
+                    .with_scale_and_position(scale, point(0.0, ascent));
+
+                let outlined = font.outline_glyph(glyph)?;
+                let bounds = outlined.px_bounds();
+                let width = bounds.width() as u32;
+                let height = bounds.height() as u32;
+                let mut coverage = vec![0.0; (width * height) as usize];
+
+                outlined.draw(|x, y, c| {
+                    if x < width && y < height {
+                        coverage[(y * width + x) as usize] = c;
+                    }
+                });
+
+                Some(GlyphBitmap {
+                    left: bounds.min.x as i32,
+                    top: bounds.min.y as i32,
+                    width,
+                    height,
+                    coverage,
+                })
+            })
+            .as_ref()
+    }
+}
+
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, color: Rgb) {
+        for py in y..(y + height).min(self.height) {
+            for px in x..(x + width).min(self.width) {
+                self.put(px, py, color);
+            }
+        }
+    }
+
+    fn draw_glyph(&mut self, x: i32, y: i32, glyph: &GlyphBitmap, color: Rgb) {
+        for gy in 0..glyph.height {
+            for gx in 0..glyph.width {
+                let coverage = glyph.coverage[(gy * glyph.width + gx) as usize];
+                let px = x + glyph.left + gx as i32;
+                let py = y + glyph.top + gy as i32;
+
+                if coverage <= 0.0 || px < 0 || py < 0 {
+                    continue;
+                }
+
+                let (px, py) = (px as u32, py as u32);
+
+                if px >= self.width || py >= self.height {
+                    continue;
+                }
+
+                let under = self.get(px, py);
+                self.put(px, py, under.blend(color, coverage.min(1.0)));
+            }
+        }
+    }
+
+    fn get(&self, x: u32, y: u32) -> Rgb {
+        let i = ((y * self.width + x) * 4) as usize;
+
+        Rgb(self.pixels[i], self.pixels[i + 1], self.pixels[i + 2])
+    }
+
+    fn put(&mut self, x: u32, y: u32, color: Rgb) {
+        let i = ((y * self.width + x) * 4) as usize;
+        self.pixels[i..i + 4].copy_from_slice(&[color.0, color.1, color.2, 0xff]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{Frame, Palette};
+
+    #[test]
+    fn test_render_draws_glyphs_in_fg_color() {
+        let palette = Palette::from_theme(None).unwrap();
+        let mut vt = avt::Vt::new(4, 1);
+        vt.feed_str("\x1b[?25l\x1b[31m\u{2588}");
+        let frame = Frame::capture(&vt, &palette);
+
+        let mut rasterizer = Rasterizer::new(16.0, 4, 1, palette.bg).unwrap();
+        let pixels = rasterizer.render(&frame);
+
+        assert_eq!(
+            pixels.len(),
+            (rasterizer.width() * rasterizer.height() * 4) as usize
+        );
+
+        let red = palette.resolve(avt::Color::Indexed(1));
+        let has_red = pixels.chunks(4).any(|p| Rgb(p[0], p[1], p[2]) == red);
+
+        assert!(has_red, "glyph should be drawn in its foreground color");
+        assert_eq!(&pixels[0..3], &[palette.bg.0, palette.bg.1, palette.bg.2]);
+    }
+}
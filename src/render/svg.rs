@@ -0,0 +1,304 @@
+/// SVG encoder
+///
+/// Frames are stacked vertically inside a clipping viewport and a CSS
+/// animation steps the stack upwards, showing one frame at a time.
+use super::{Cell, Frame, Palette, TimedFrame};
+use std::fmt::Write;
+
+/// Cell geometry, relative to the font size
+const CELL_WIDTH: f64 = 0.6;
+const CELL_HEIGHT: f64 = 1.2;
+const BASELINE: f64 = 0.96;
+
+struct Geometry {
+    font_size: f64,
+    cell_width: f64,
+    cell_height: f64,
+    padding: f64,
+}
+
+impl Geometry {
+    fn new(font_size: f64) -> Self {
+        Self {
+            font_size,
+            cell_width: font_size * CELL_WIDTH,
+            cell_height: font_size * CELL_HEIGHT,
+            padding: font_size * CELL_WIDTH,
+        }
+    }
+}
+
+pub fn animation(frames: &[TimedFrame], palette: &Palette, font_size: f64) -> String {
+    let geometry = Geometry::new(font_size);
+    let cols = frames.iter().map(|f| f.frame.cols).max().unwrap_or(0);
+    let rows = frames.iter().map(|f| f.frame.rows).max().unwrap_or(0);
+    let screen_height = rows as f64 * geometry.cell_height;
+    let total: f64 = frames.iter().map(|f| f.duration).sum();
+    let mut css = String::new();
+
+    if frames.len() > 1 && total > 0.0 {
+        let mut time = 0.0;
+        css.push_str("@keyframes roll {\n");
+
+        for (i, timed) in frames.iter().enumerate() {
+            let offset = 0.0 - i as f64 * screen_height;
+            let _ = writeln!(
+                css,
+                "{:.3}% {{ transform: translateY({:.2}px) }}",
+                time / total * 100.0,
+                offset
+            );
+            time += timed.duration;
+        }
+
+        let offset = 0.0 - (frames.len() - 1) as f64 * screen_height;
+        let _ = writeln!(css, "100% {{ transform: translateY({:.2}px) }}", offset);
+        css.push_str("}\n");
+        let _ = writeln!(
+            css,
+            ".film {{ animation: roll {:.3}s steps(1, end) infinite; }}",
+            total
+        );
+    }
+
+    let mut film = String::new();
+
+    for (i, timed) in frames.iter().enumerate() {
+        let _ = writeln!(
+            film,
+            "<g transform=\"translate(0 {:.2})\">",
+            i as f64 * screen_height
+        );
+        film.push_str(&screen(&timed.frame, palette, &geometry));
+        film.push_str("</g>\n");
+    }
+
+    document(
+        cols,
+        rows,
+        palette,
+        &geometry,
+        &css,
+        &format!("<g class=\"film\">\n{film}</g>\n"),
+    )
+}
+
+/// Renders a single frame as a static image
+pub fn still(frame: &Frame, palette: &Palette, font_size: f64) -> String {
+    let geometry = Geometry::new(font_size);
+    let content = screen(frame, palette, &geometry);
+
+    document(frame.cols, frame.rows, palette, &geometry, "", &content)
+}
+
+fn document(
+    cols: usize,
+    rows: usize,
+    palette: &Palette,
+    geometry: &Geometry,
+    css: &str,
+    content: &str,
+) -> String {
+    let screen_width = cols as f64 * geometry.cell_width;
+    let screen_height = rows as f64 * geometry.cell_height;
+    let width = screen_width + 2.0 * geometry.padding;
+    let height = screen_height + 2.0 * geometry.padding;
+
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width:.2}\" height=\"{height:.2}\" viewBox=\"0 0 {width:.2} {height:.2}\">\n\
+         <style>\n\
+         text {{ font-family: 'DejaVu Sans Mono', Menlo, Consolas, monospace; font-size: {font_size:.2}px; white-space: pre; }}\n\
+         .b {{ font-weight: bold; }}\n\
+         .i {{ font-style: italic; }}\n\
+         .u {{ text-decoration: underline; }}\n\
+         .s {{ text-decoration: line-through; }}\n\
+         .u.s {{ text-decoration: underline line-through; }}\n\
+         {css}\
+         </style>\n\
+         <rect width=\"100%\" height=\"100%\" fill=\"{bg}\"/>\n\
+         <svg x=\"{pad:.2}\" y=\"{pad:.2}\" width=\"{screen_width:.2}\" height=\"{screen_height:.2}\">\n\
+         {content}\
+         </svg>\n\
+         </svg>\n",
+        font_size = geometry.font_size,
+        bg = palette.bg.hex(),
+        pad = geometry.padding,
+    )
+}
+
+/// Renders a single screen as background rects followed by text runs
+fn screen(frame: &Frame, palette: &Palette, geometry: &Geometry) -> String {
+    let mut rects = String::new();
+    let mut texts = String::new();
+
+    for row in 0..frame.lines.len() {
+        let cells = frame.visible_line(row);
+
+        let y = row as f64 * geometry.cell_height;
+        let mut col = 0;
+
+        while col < cells.len() {
+            let start = col;
+            let bg = cells[col].bg;
+
+            while col < cells.len() && cells[col].bg == bg {
+                col += 1;
+            }
+
+            if bg != palette.bg {
+                let _ = writeln!(
+                    rects,
+                    "<rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{:.2}\" fill=\"{}\"/>",
+                    start as f64 * geometry.cell_width,
+                    y,
+                    (col - start) as f64 * geometry.cell_width,
+                    geometry.cell_height,
+                    bg.hex()
+                );
+            }
+        }
+
+        let baseline = y + geometry.font_size * BASELINE;
+        let mut col = 0;
+
+        while col < cells.len() {
+            let start = col;
+            let first = cells[col];
+            let mut text = String::new();
+
+            // Runs are broken at wide characters so every run starts at an
+            // exact cell position
+            while col < cells.len() && same_text_style(&cells[col], &first) {
+                let cell = &cells[col];
+                col += 1;
+
+                if cell.width == 0 {
+                    continue;
+                }
+
+                push_escaped(&mut text, cell.ch);
+
+                if cell.width > 1 {
+                    break;
+                }
+            }
+
+            // Trailing blanks are covered by the background already
+            let text = text.trim_end();
+
+            if text.is_empty() {
+                continue;
+            }
+
+            let _ = writeln!(
+                texts,
+                "<text x=\"{:.2}\" y=\"{:.2}\" fill=\"{}\"{}>{}</text>",
+                start as f64 * geometry.cell_width,
+                baseline,
+                first.fg.hex(),
+                class_attr(&first),
+                text
+            );
+        }
+    }
+
+    rects + &texts
+}
+
+fn same_text_style(a: &Cell, b: &Cell) -> bool {
+    a.fg == b.fg
+        && a.bold == b.bold
+        && a.italic == b.italic
+        && a.underline == b.underline
+        && a.strikethrough == b.strikethrough
+}
+
+fn class_attr(cell: &Cell) -> String {
+    let classes: Vec<&str> = [
+        (cell.bold, "b"),
+        (cell.italic, "i"),
+        (cell.underline, "u"),
+        (cell.strikethrough, "s"),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, class)| *class)
+    .collect();
+
+    if classes.is_empty() {
+        String::new()
+    } else {
+        format!(" class=\"{}\"", classes.join(" "))
+    }
+}
+
+fn push_escaped(out: &mut String, ch: char) {
+    match ch {
+        '&' => out.push_str("&amp;"),
+        '<' => out.push_str("&lt;"),
+        '>' => out.push_str("&gt;"),
+        '"' => out.push_str("&quot;"),
+        // Control characters aren't allowed in XML
+        c if (c as u32) < 0x20 => out.push(' '),
+        c => out.push(c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(input: &str) -> Frame {
+        let palette = Palette::from_theme(None).unwrap();
+        let mut vt = avt::Vt::new(10, 2);
+        vt.feed_str("\x1b[?25l");
+        vt.feed_str(input);
+
+        Frame::capture(&vt, &palette)
+    }
+
+    #[test]
+    fn test_animation_keyframes() {
+        let palette = Palette::from_theme(None).unwrap();
+
+        let frames = vec![
+            TimedFrame {
+                frame: frame("a"),
+                duration: 1.0,
+            },
+            TimedFrame {
+                frame: frame("b"),
+                duration: 3.0,
+            },
+        ];
+
+        let svg = animation(&frames, &palette, 10.0);
+
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.contains("0.000% { transform: translateY(0.00px) }"));
+        assert!(svg.contains("25.000% { transform: translateY(-24.00px) }"));
+        assert!(svg.contains("animation: roll 4.000s steps(1, end) infinite"));
+        assert!(svg.contains(">a</text>"));
+        assert!(svg.contains(">b</text>"));
+    }
+
+    #[test]
+    fn test_styles_and_escaping() {
+        let palette = Palette::from_theme(None).unwrap();
+
+        let frames = vec![TimedFrame {
+            frame: frame("\x1b[1;41m<&>\x1b[0m x"),
+            duration: 1.0,
+        }];
+
+        let svg = animation(&frames, &palette, 10.0);
+
+        assert!(!svg.contains("@keyframes"));
+        assert!(svg.contains(&format!(
+            "fill=\"{}\"/>",
+            palette.resolve(avt::Color::Indexed(1)).hex()
+        )));
+        assert!(svg.contains("class=\"b\">&lt;&amp;&gt;</text>"));
+        assert!(svg.contains("> x</text>"));
+    }
+}
@@ -0,0 +1,129 @@
+use crate::recording::asciicast::{Entry, EventData};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct PlayerConfig {
+    pub speed: f64,
+    pub idle_time_limit: Option<f64>,
+    pub instant: bool,
+}
+
+/// Plays back recorded events at their recorded pace
+///
+/// The schedule is anchored to the time the player was created, so slow
+/// consumers don't accumulate drift.
+pub struct Player {
+    events: VecDeque<Entry>,
+    config: PlayerConfig,
+    clock: Instant,
+}
+
+impl Player {
+    pub fn new(events: Vec<Entry>, config: PlayerConfig) -> Self {
+        Self {
+            events: events.into(),
+            config,
+            clock: Instant::now(),
+        }
+    }
+
+    /// Waits until the next event is due and returns it
+    ///
+    /// This is cancel-safe: if the returned future is dropped before
+    /// completion no event is consumed.
+    pub async fn next(&mut self) -> Option<EventData> {
+        let interval = self.scaled_interval(self.events.front()?.interval);
+
+        if !self.config.instant {
+            tokio::time::sleep_until(self.clock + interval).await;
+        }
+
+        let entry = self.events.pop_front()?;
+        self.clock += interval;
+
+        Some(entry.data)
+    }
+
+    fn scaled_interval(&self, interval: f64) -> Duration {
+        let interval = match self.config.idle_time_limit {
+            Some(limit) => interval.min(limit),
+            None => interval,
+        };
+
+        Duration::from_secs_f64(interval / self.config.speed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(interval: f64, data: &str) -> Entry {
+        Entry {
+            interval,
+            data: EventData::Output(data.to_string()),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_plays_at_recorded_pace() {
+        let config = PlayerConfig {
+            speed: 2.0,
+            idle_time_limit: None,
+            instant: false,
+        };
+
+        let start = Instant::now();
+        let mut player = Player::new(vec![output(1.0, "a"), output(3.0, "b")], config);
+
+        assert_eq!(
+            player.next().await,
+            Some(EventData::Output("a".to_string()))
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+
+        assert_eq!(
+            player.next().await,
+            Some(EventData::Output("b".to_string()))
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(2000));
+
+        assert_eq!(player.next().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_time_limit() {
+        let config = PlayerConfig {
+            speed: 1.0,
+            idle_time_limit: Some(0.5),
+            instant: false,
+        };
+
+        let start = Instant::now();
+        let mut player = Player::new(vec![output(0.25, "a"), output(10.0, "b")], config);
+
+        player.next().await;
+        player.next().await;
+
+        assert_eq!(start.elapsed(), Duration::from_millis(750));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_instant() {
+        let config = PlayerConfig {
+            speed: 1.0,
+            idle_time_limit: None,
+            instant: true,
+        };
+
+        let start = Instant::now();
+        let mut player = Player::new(vec![output(5.0, "a"), output(5.0, "b")], config);
+
+        player.next().await;
+        player.next().await;
+
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,443 @@
+/// Detection of the sequences avt ignores: terminal queries, which programs
+/// wait a reply for, and OSC sequences carrying metadata
+///
+/// The output is scanned separately from the VT, keeping the state of an
+/// unfinished sequence between chunks.
+use base64::prelude::*;
+use std::mem;
+
+/// How an OSC sequence was terminated, replies use the same terminator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminator {
+    Bel,
+    St,
+}
+
+impl Terminator {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Terminator::Bel => "\x07",
+            Terminator::St => "\x1b\\",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// DA1, `CSI c`
+    PrimaryAttributes,
+    /// DA2, `CSI > c`
+    SecondaryAttributes,
+    /// DSR, `CSI 5 n`
+    Status,
+    /// CPR, `CSI 6 n`
+    CursorPosition,
+    /// DECXCPR, `CSI ? 6 n`
+    ExtendedCursorPosition,
+    /// XTVERSION, `CSI > q`
+    Version,
+    /// `OSC 10 ; ?`
+    Foreground(Terminator),
+    /// `OSC 11 ; ?`
+    Background(Terminator),
+    /// `OSC 52 ; selection ; ?`
+    Clipboard(String, Terminator),
+}
+
+/// Shell integration marks (OSC 133)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mark {
+    /// `A`, before the prompt
+    PromptStart,
+    /// `B`, after the prompt, where the command line starts
+    CommandStart,
+    /// `C`, before the output of the command
+    OutputStart,
+    /// `D`, after the command, with its exit status when known
+    CommandEnd(Option<i32>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sequence {
+    Query(Query),
+    /// OSC 0 and 2
+    Title(String),
+    /// OSC 7, with the path of the `file://` URL
+    Cwd(String),
+    /// OSC 8, starting a link with a URL or ending it without one
+    Hyperlink(Option<String>),
+    /// OSC 9 and OSC 777 `notify`, with an optional title and a body
+    Notification(Option<String>, String),
+    Mark(Mark),
+    /// OSC 52, with the selection and the decoded text, empty when the
+    /// data isn't base64, which clears the selection
+    Copy(String, String),
+}
+
+/// Longest CSI sequence kept, anything longer isn't a query
+const MAX_CSI_LEN: usize = 32;
+
+/// Longest OSC sequence kept, anything longer is ignored
+const MAX_OSC_LEN: usize = 8192;
+
+/// Room for the code and the selection of OSC 52, besides the data
+const MAX_OSC52_PREFIX_LEN: usize = 16;
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    Csi(String),
+    /// The data, unless too long
+    Osc(Option<String>),
+    /// ESC inside an OSC sequence, which may be ST
+    OscEscape(Option<String>),
+}
+
+#[derive(Debug)]
+pub struct Scanner {
+    state: State,
+    /// Longest OSC 52 sequence kept, unlike others limited by the size of
+    /// the clipboard
+    max_clipboard_len: usize,
+}
+
+impl Scanner {
+    /// Creates a scanner keeping clipboard copies of up to `clipboard_limit`
+    /// bytes
+    pub fn new(clipboard_limit: usize) -> Self {
+        Self {
+            state: State::Ground,
+            max_clipboard_len: clipboard_limit.div_ceil(3) * 4 + MAX_OSC52_PREFIX_LEN,
+        }
+    }
+
+    /// Returns the sequences found in `data`, each with the byte offset just
+    /// past its end
+    pub fn scan(&mut self, data: &str) -> Vec<(usize, Sequence)> {
+        let mut sequences = Vec::new();
+
+        for (i, ch) in data.char_indices() {
+            if let Some(seq) = self.feed(ch) {
+                sequences.push((i + ch.len_utf8(), seq));
+            }
+        }
+
+        sequences
+    }
+
+    fn feed(&mut self, ch: char) -> Option<Sequence> {
+        let (state, seq) = match (mem::take(&mut self.state), ch) {
+            (State::Ground, '\x1b') => (State::Escape, None),
+            (State::Ground, _) => (State::Ground, None),
+
+            (State::Escape, '[') => (State::Csi(String::new()), None),
+            (State::Escape, ']') => (State::Osc(Some(String::new())), None),
+            (State::Escape, '\x1b') => (State::Escape, None),
+            (State::Escape, _) => (State::Ground, None),
+
+            (State::Csi(_), '\x1b') => (State::Escape, None),
+
+            (State::Csi(params), '\x40'..='\x7e') => {
+                (State::Ground, csi_query(&params, ch).map(Sequence::Query))
+            }
+
+            (State::Csi(mut params), '\x20'..='\x3f') if params.len() < MAX_CSI_LEN => {
+                params.push(ch);
+
+                (State::Csi(params), None)
+            }
+
+            // C0 controls are executed in the middle of a sequence
+            (State::Csi(params), '\0'..='\x1f') => (State::Csi(params), None),
+
+            (State::Csi(_), _) => (State::Ground, None),
+
+            (State::Osc(data), '\x07') => (
+                State::Ground,
+                data.and_then(|data| osc(&data, Terminator::Bel)),
+            ),
+
+            (State::Osc(data), '\x1b') => (State::OscEscape(data), None),
+
+            (State::Osc(data), _) => {
+                let data = data
+                    .filter(|data| {
+                        if data.starts_with("52;") {
+                            data.len() < self.max_clipboard_len
+                        } else {
+                            data.len() < MAX_OSC_LEN
+                        }
+                    })
+                    .map(|mut data| {
+                        data.push(ch);
+                        data
+                    });
+
+                (State::Osc(data), None)
+            }
+
+            (State::OscEscape(data), '\\') => (
+                State::Ground,
+                data.and_then(|data| osc(&data, Terminator::St)),
+            ),
+
+            // An unterminated OSC sequence followed by another sequence
+            (State::OscEscape(_), _) => {
+                self.state = State::Escape;
+
+                return self.feed(ch);
+            }
+        };
+
+        self.state = state;
+
+        seq
+    }
+}
+
+fn csi_query(params: &str, final_char: char) -> Option<Query> {
+    match (params, final_char) {
+        ("" | "0", 'c') => Some(Query::PrimaryAttributes),
+        (">" | ">0", 'c') => Some(Query::SecondaryAttributes),
+        ("5", 'n') => Some(Query::Status),
+        ("6", 'n') => Some(Query::CursorPosition),
+        ("?6", 'n') => Some(Query::ExtendedCursorPosition),
+        (">" | ">0", 'q') => Some(Query::Version),
+        _ => None,
+    }
+}
+
+fn osc(data: &str, terminator: Terminator) -> Option<Sequence> {
+    let (code, args) = data.split_once(';')?;
+
+    match code {
+        "0" | "2" => Some(Sequence::Title(args.to_string())),
+
+        "7" => file_url_path(args).map(Sequence::Cwd),
+
+        "8" => {
+            // Parameters, like an id, come before the URL
+            let (_params, url) = args.split_once(';')?;
+
+            Some(Sequence::Hyperlink(
+                (!url.is_empty()).then(|| url.to_string()),
+            ))
+        }
+
+        // ConEmu uses OSC 9 with a numeric subcommand for other purposes,
+        // like progress reports
+        "9" if !is_subcommand(args) => Some(Sequence::Notification(None, args.to_string())),
+
+        "10" if args == "?" => Some(Sequence::Query(Query::Foreground(terminator))),
+        "11" if args == "?" => Some(Sequence::Query(Query::Background(terminator))),
+
+        "52" => {
+            let (selection, data) = args.split_once(';')?;
+
+            // An empty selection means `s0`
+            let selection = if selection.is_empty() {
+                "s0"
+            } else {
+                selection
+            };
+
+            if data == "?" {
+                return Some(Sequence::Query(Query::Clipboard(
+                    selection.to_string(),
+                    terminator,
+                )));
+            }
+
+            let text = BASE64_STANDARD
+                .decode(data)
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                .unwrap_or_default();
+
+            Some(Sequence::Copy(selection.to_string(), text))
+        }
+
+        "133" => {
+            let mut params = args.split(';');
+
+            let mark = match params.next()? {
+                "A" => Mark::PromptStart,
+                "B" => Mark::CommandStart,
+                "C" => Mark::OutputStart,
+                "D" => Mark::CommandEnd(params.next().and_then(|s| s.parse().ok())),
+                _ => return None,
+            };
+
+            Some(Sequence::Mark(mark))
+        }
+
+        "777" => {
+            let (title, body) = args.strip_prefix("notify;")?.split_once(';')?;
+
+            Some(Sequence::Notification(
+                Some(title.to_string()),
+                body.to_string(),
+            ))
+        }
+
+        _ => None,
+    }
+}
+
+fn is_subcommand(args: &str) -> bool {
+    let (subcommand, _) = args.split_once(';').unwrap_or((args, ""));
+
+    !subcommand.is_empty() && subcommand.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Returns the percent-decoded path of a `file://host/path` URL
+fn file_url_path(url: &str) -> Option<String> {
+    let rest = url.strip_prefix("file://")?;
+    let path = &rest[rest.find('/')?..];
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut iter = path.bytes();
+
+    while let Some(b) = iter.next() {
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+
+        let hex = [iter.next()?, iter.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(query: Query) -> Sequence {
+        Sequence::Query(query)
+    }
+
+    #[test]
+    fn test_queries() {
+        let mut scanner = Scanner::new(0);
+
+        assert_eq!(
+            scanner.scan("a\x1b[cb\x1b[>0c\x1b[5n\x1b[6n\x1b[?6n\x1b[>q"),
+            vec![
+                (4, query(Query::PrimaryAttributes)),
+                (10, query(Query::SecondaryAttributes)),
+                (14, query(Query::Status)),
+                (18, query(Query::CursorPosition)),
+                (23, query(Query::ExtendedCursorPosition)),
+                (27, query(Query::Version)),
+            ]
+        );
+
+        assert_eq!(
+            scanner.scan("\x1b]10;?\x07\x1b]11;?\x1b\\"),
+            vec![
+                (7, query(Query::Foreground(Terminator::Bel))),
+                (15, query(Query::Background(Terminator::St))),
+            ]
+        );
+
+        // Other sequences aren't queries
+        assert!(scanner
+            .scan("\x1b[1;2H\x1b[2J\x1b[6;1n\x1b]1;icon\x07\x1b]10;#fff\x07\x1b(B")
+            .is_empty());
+
+        // A query split across chunks is found in the chunk completing it
+        assert!(scanner.scan("ż\x1b[").is_empty());
+        assert_eq!(scanner.scan("6n"), vec![(2, query(Query::CursorPosition))]);
+        assert!(scanner.scan("\x1b]11").is_empty());
+        assert!(scanner.scan(";?\x1b").is_empty());
+        assert_eq!(
+            scanner.scan("\\"),
+            vec![(1, query(Query::Background(Terminator::St)))]
+        );
+
+        // An unterminated OSC sequence doesn't hide the next query
+        assert_eq!(
+            scanner.scan("\x1b]0;title\x1b[c"),
+            vec![(12, query(Query::PrimaryAttributes))]
+        );
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut scanner = Scanner::new(0);
+
+        let seqs: Vec<_> = scanner
+            .scan(concat!(
+                "\x1b]0;vim ż\x07",
+                "\x1b]2;\x1b\\",
+                "\x1b]7;file://host/home/a%20b\x07",
+                "\x1b]7;http://host/x\x07",
+                "\x1b]8;id=1;https://example.com/?a=b;c\x1b\\link\x1b]8;;\x1b\\",
+                "\x1b]9;done\x07",
+                "\x1b]9;4;1;50\x07",
+                "\x1b]777;notify;Build;passed; 3 tests\x07",
+                "\x1b]133;A;k=i\x07\x1b]133;B\x07\x1b]133;C\x07",
+                "\x1b]133;D;1\x07\x1b]133;D\x07\x1b]133;X\x07",
+            ))
+            .into_iter()
+            .map(|(_, seq)| seq)
+            .collect();
+
+        assert_eq!(
+            seqs,
+            vec![
+                Sequence::Title("vim ż".to_string()),
+                Sequence::Title("".to_string()),
+                Sequence::Cwd("/home/a b".to_string()),
+                Sequence::Hyperlink(Some("https://example.com/?a=b;c".to_string())),
+                Sequence::Hyperlink(None),
+                Sequence::Notification(None, "done".to_string()),
+                Sequence::Notification(Some("Build".to_string()), "passed; 3 tests".to_string()),
+                Sequence::Mark(Mark::PromptStart),
+                Sequence::Mark(Mark::CommandStart),
+                Sequence::Mark(Mark::OutputStart),
+                Sequence::Mark(Mark::CommandEnd(Some(1))),
+                Sequence::Mark(Mark::CommandEnd(None)),
+            ]
+        );
+
+        // Clipboard copies are kept up to the scanner's limit
+        let mut scanner = Scanner::new(6);
+
+        assert_eq!(
+            scanner.scan("\x1b]52;c;aGVsbG8h\x07\x1b]52;;?\x1b\\\x1b]52;p;!\x07"),
+            vec![
+                (16, Sequence::Copy("c".to_string(), "hello!".to_string())),
+                (
+                    25,
+                    query(Query::Clipboard("s0".to_string(), Terminator::St))
+                ),
+                (34, Sequence::Copy("p".to_string(), "".to_string())),
+            ]
+        );
+
+        let data = BASE64_STANDARD.encode("x".repeat(MAX_OSC_LEN));
+        assert!(scanner.scan(&format!("\x1b]52;c;{data}\x07")).is_empty());
+
+        let mut scanner = Scanner::new(MAX_OSC_LEN);
+        assert_eq!(
+            scanner.scan(&format!("\x1b]52;c;{data}\x07")),
+            vec![(
+                data.len() + 8,
+                Sequence::Copy("c".to_string(), "x".repeat(MAX_OSC_LEN))
+            )]
+        );
+
+        // Overly long sequences are dropped
+        let title = "x".repeat(MAX_OSC_LEN);
+        assert!(scanner.scan(&format!("\x1b]0;{title}\x07")).is_empty());
+        assert_eq!(
+            scanner.scan("\x1b]0;x\x07"),
+            vec![(6, Sequence::Title("x".to_string()))]
+        );
+    }
+}
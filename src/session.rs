@@ -1,36 +1,148 @@
-use anyhow::Result;
+use crate::render::export::{self, ExportFormat};
+use crate::render::{Frame, Hyperlink, Palette, Rgb};
+use crate::scan::{self, Mark, Query, Sequence, Terminator};
+use anyhow::{bail, Result};
+use base64::prelude::*;
+use futures_util::future::Either;
 use futures_util::{stream, Stream, StreamExt};
 use serde_json::json;
+use std::collections::VecDeque;
 use std::future;
+use std::ops::Range;
+use std::pin::Pin;
 use std::time::{Duration, Instant};
 use tokio::sync::{broadcast, mpsc, oneshot};
-use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::wrappers::{
+    errors::BroadcastStreamRecvError, BroadcastStream, UnboundedReceiverStream,
+};
 
 pub struct Session {
     vt: avt::Vt,
     broadcast_tx: broadcast::Sender<Event>,
+    /// Subscribers which must not miss any event they're interested in, like
+    /// recorders
+    lossless_txs: Vec<(mpsc::UnboundedSender<Event>, Filter)>,
     stream_time: f64,
     start_time: Instant,
     last_event_time: Instant,
     pid: i32,
+    palette: Palette,
+    scanner: scan::Scanner,
+    title: Option<String>,
+    cwd: Option<String>,
+    /// The link being printed, with the line and column it started at
+    link: Option<(String, usize, usize)>,
+    /// Printed links, by absolute line, oldest first
+    links: VecDeque<Hyperlink>,
+    shell: Shell,
+    clipboard: Clipboard,
 }
 
 #[derive(Clone)]
 pub enum Event {
-    Init(f64, usize, usize, i32, String, String),
+    Init(f64, usize, usize, i32, String, String, Metadata),
     Output(f64, String),
     Resize(f64, usize, usize),
-    Snapshot(usize, usize, String, String),
+    Snapshot(usize, usize, String, String, Metadata),
     Marker(f64, String),
     Input(f64, String),
     Exit(f64, i32),
+    Export(ExportFormat, String),
+    Title(f64, String),
+    Cwd(f64, String),
+    Notification(f64, Option<String>, String),
+    CommandStarted(f64, ShellCommand),
+    CommandFinished(f64, ShellCommand),
+    Clipboard(f64, String, String),
 }
 
-pub struct Client(oneshot::Sender<Subscription>);
+/// What the program reported about itself with OSC sequences, as of an init
+/// or snapshot event
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub cwd: Option<String>,
+    /// Links on the screen
+    pub links: Vec<Hyperlink>,
+    pub clipboard: Option<String>,
+}
+
+/// A command run at a shell prompt, as told by shell integration marks
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellCommand {
+    /// Set for commands typed by `runCommand`
+    pub id: Option<String>,
+    pub command: String,
+    /// Lines of the output, counting the scrollback, known once finished
+    pub output_lines: Range<usize>,
+    pub output: String,
+    pub status: Option<i32>,
+}
+
+impl ShellCommand {
+    /// Returns the data of the `commandFinished` event
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "command": self.command,
+            "status": self.status,
+            "output": self.output,
+            "outputRange": json!({
+                "start": self.output_lines.start,
+                "end": self.output_lines.end,
+            }),
+        })
+    }
+}
+
+/// Shell integration (OSC 133) state, positions being absolute lines and
+/// columns
+#[derive(Default)]
+struct Shell {
+    /// Whether the shell sent any mark
+    integrated: bool,
+    /// Where the command line starts, while the shell waits at a prompt
+    prompt: Option<(usize, usize)>,
+    /// Whether `runCommand` typed a command at the prompt, with its id
+    claimed: Option<Option<String>>,
+    /// The running command, with where its output starts
+    running: Option<(ShellCommand, usize, usize)>,
+}
+
+/// The clipboard buffer, filled by OSC 52 copies and `setClipboard`
+struct Clipboard {
+    text: Option<String>,
+    /// Whether OSC 52 reads get the buffer
+    readable: bool,
+    /// Largest text kept, in bytes
+    limit: usize,
+}
+
+/// Printed links kept for finding those still on the screen
+const MAX_LINKS: usize = 1024;
+
+/// Largest clipboard text kept unless set otherwise
+const DEFAULT_CLIPBOARD_LIMIT: usize = 1024 * 1024;
+
+/// Tells which events a subscriber is interested in
+pub type Filter = fn(&Event) -> bool;
+
+pub struct Client {
+    sub_tx: oneshot::Sender<Subscription>,
+    /// Set for lossless subscriptions
+    filter: Option<Filter>,
+}
 
 pub struct Subscription {
     init: Event,
-    broadcast_rx: broadcast::Receiver<Event>,
+    events: Receiver,
+}
+
+enum Receiver {
+    /// Bounded, a subscriber falling behind misses events
+    Broadcast(broadcast::Receiver<Event>),
+    /// Unbounded, events queue up until the subscriber gets to them
+    Lossless(mpsc::UnboundedReceiver<Event>),
 }
 
 impl Session {
@@ -41,57 +153,109 @@ impl Session {
         Self {
             vt: build_vt(cols, rows),
             broadcast_tx,
+            lossless_txs: Vec::new(),
             stream_time: 0.0,
             start_time: now,
             last_event_time: now,
             pid,
+            palette: Palette::default(),
+            scanner: scan::Scanner::new(DEFAULT_CLIPBOARD_LIMIT),
+            title: None,
+            cwd: None,
+            link: None,
+            links: VecDeque::new(),
+            shell: Shell::default(),
+            clipboard: Clipboard {
+                text: None,
+                readable: false,
+                limit: DEFAULT_CLIPBOARD_LIMIT,
+            },
         }
     }
 
-    pub fn output(&mut self, data: String) {
-        self.vt.feed_str(&data);
+    /// Feeds output of the process, returning the replies to the terminal
+    /// queries in it, which are to be written back to the process
+    ///
+    /// Metadata changes are broadcast after the output carrying them.
+    pub fn output(&mut self, data: String) -> String {
         let time = self.start_time.elapsed().as_secs_f64();
-        let _ = self.broadcast_tx.send(Event::Output(time, data));
+        let (replies, events) = self.feed(&data, time);
+        self.broadcast(Event::Output(time, data));
+
+        for event in events {
+            self.broadcast(event);
+        }
+
         self.stream_time = time;
         self.last_event_time = Instant::now();
+
+        replies
     }
 
     pub fn resize(&mut self, cols: usize, rows: usize) {
         resize_vt(&mut self.vt, cols, rows);
         let time = self.start_time.elapsed().as_secs_f64();
-        let _ = self.broadcast_tx.send(Event::Resize(time, cols, rows));
+        self.broadcast(Event::Resize(time, cols, rows));
         self.stream_time = time;
         self.last_event_time = Instant::now();
     }
 
-    pub fn snapshot(&self) {
+    /// Starts over with a new screen, e.g. when a watched stream restarts
+    ///
+    /// Subscribers get a new init event.
+    pub fn restart(&mut self, cols: usize, rows: usize, data: String) {
+        self.vt = build_vt(cols, rows);
+        self.scanner = scan::Scanner::new(self.clipboard.limit);
+        self.title = None;
+        self.cwd = None;
+        self.link = None;
+        self.links.clear();
+        self.shell = Shell::default();
+        self.clipboard.text = None;
+        let time = self.start_time.elapsed().as_secs_f64();
+        self.feed(&data, time);
+        self.stream_time = time;
+        self.last_event_time = Instant::now();
+        let init = self.init_event();
+        self.broadcast(init);
+    }
+
+    pub fn snapshot(&mut self) {
         let (cols, rows) = self.vt.size();
 
-        let _ = self.broadcast_tx.send(Event::Snapshot(
+        self.broadcast(Event::Snapshot(
             cols,
             rows,
             self.vt.dump(),
             self.text_view(),
+            self.metadata(),
         ));
     }
 
+    pub fn export(&mut self, format: ExportFormat) {
+        let mut frame = Frame::capture(&self.vt, &self.palette);
+        frame.links = self.visible_links();
+        let content = export::export(&frame, &self.palette, format);
+        self.broadcast(Event::Export(format, content));
+    }
+
     pub fn marker(&mut self, label: String) {
         let time = self.start_time.elapsed().as_secs_f64();
-        let _ = self.broadcast_tx.send(Event::Marker(time, label));
+        self.broadcast(Event::Marker(time, label));
         self.stream_time = time;
         self.last_event_time = Instant::now();
     }
 
     pub fn input(&mut self, data: String) {
         let time = self.start_time.elapsed().as_secs_f64();
-        let _ = self.broadcast_tx.send(Event::Input(time, data));
+        self.broadcast(Event::Input(time, data));
         self.stream_time = time;
         self.last_event_time = Instant::now();
     }
 
     pub fn exit(&mut self, status: i32) {
         let time = self.start_time.elapsed().as_secs_f64();
-        let _ = self.broadcast_tx.send(Event::Exit(time, status));
+        self.broadcast(Event::Exit(time, status));
         self.stream_time = time;
         self.last_event_time = Instant::now();
     }
@@ -100,25 +264,379 @@ impl Session {
         self.vt.cursor_key_app_mode()
     }
 
+    /// Whether the shell waits at a prompt for a command, as told by shell
+    /// integration marks
+    pub fn at_prompt(&self) -> bool {
+        self.shell.prompt.is_some() && self.shell.claimed.is_none()
+    }
+
+    /// Whether the shell sent any shell integration mark yet
+    pub fn shell_integrated(&self) -> bool {
+        self.shell.integrated
+    }
+
+    /// Notes that `runCommand` typed a command at the prompt, so the command's
+    /// events carry `id`
+    pub fn claim_prompt(&mut self, id: Option<String>) {
+        self.shell.claimed = Some(id);
+    }
+
     pub fn set_pid(&mut self, pid: i32) {
         self.pid = pid;
     }
 
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    /// Sets whether OSC 52 reads are answered with the clipboard buffer, and
+    /// the largest text it keeps
+    pub fn set_clipboard_access(&mut self, readable: bool, limit: usize) {
+        self.clipboard.readable = readable;
+        self.clipboard.limit = limit;
+        self.scanner = scan::Scanner::new(limit);
+    }
+
+    /// Fills the clipboard buffer, for programs reading it with OSC 52
+    pub fn set_clipboard(&mut self, text: String) -> Result<()> {
+        if text.len() > self.clipboard.limit {
+            bail!(
+                "clipboard text is {} bytes, over the {} byte limit",
+                text.len(),
+                self.clipboard.limit
+            );
+        }
+
+        self.clipboard.text = Some(text);
+
+        Ok(())
+    }
+
     pub fn subscribe(&self) -> Subscription {
+        let init = self.init_event();
+        let events = Receiver::Broadcast(self.broadcast_tx.subscribe());
+
+        Subscription { init, events }
+    }
+
+    /// Subscribes to all events, however far behind the subscriber falls
+    pub fn subscribe_lossless(&mut self) -> Subscription {
+        self.subscribe_filtered(|_| true)
+    }
+
+    /// Subscribes to the events passing `filter`, however far behind the
+    /// subscriber falls
+    pub fn subscribe_filtered(&mut self, filter: Filter) -> Subscription {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.lossless_txs.push((tx, filter));
+
+        Subscription {
+            init: self.init_event(),
+            events: Receiver::Lossless(rx),
+        }
+    }
+
+    /// Feeds the VT, handling the sequences it ignores
+    fn feed(&mut self, data: &str, time: f64) -> (String, Vec<Event>) {
+        let mut replies = String::new();
+        let mut events = Vec::new();
+        let mut start = 0;
+
+        // The screen must be up to date with the output preceding a sequence,
+        // e.g. for the cursor position
+        for (end, seq) in self.scanner.scan(data) {
+            self.vt.feed_str(&data[start..end]);
+            start = end;
+
+            match seq {
+                Sequence::Query(query) => replies.push_str(&self.reply(query)),
+
+                Sequence::Title(title) => {
+                    let new_title = (!title.is_empty()).then(|| title.clone());
+
+                    if new_title != self.title {
+                        self.title = new_title;
+                        events.push(Event::Title(time, title));
+                    }
+                }
+
+                Sequence::Cwd(cwd) => {
+                    if self.cwd.as_ref() != Some(&cwd) {
+                        self.cwd = Some(cwd.clone());
+                        events.push(Event::Cwd(time, cwd));
+                    }
+                }
+
+                Sequence::Hyperlink(url) => {
+                    self.end_link();
+
+                    if let Some(url) = url {
+                        let (line, col) = self.cursor_position();
+                        let (cols, _) = self.vt.size();
+
+                        // Pending a wrap, the text starts on the next line
+                        self.link = if col < cols {
+                            Some((url, line, col))
+                        } else {
+                            Some((url, line + 1, 0))
+                        };
+                    }
+                }
+
+                Sequence::Notification(title, body) => {
+                    events.push(Event::Notification(time, title, body));
+                }
+
+                Sequence::Mark(mark) => {
+                    self.shell.integrated = true;
+                    events.extend(self.mark(mark, time));
+                }
+
+                // The scanner only roughly limits the size of copies
+                Sequence::Copy(_, text) if text.len() > self.clipboard.limit => {}
+
+                Sequence::Copy(selection, text) => {
+                    self.clipboard.text = (!text.is_empty()).then(|| text.clone());
+                    events.push(Event::Clipboard(time, selection, text));
+                }
+            }
+        }
+
+        self.vt.feed_str(&data[start..]);
+
+        (replies, events)
+    }
+
+    fn mark(&mut self, mark: Mark, time: f64) -> Option<Event> {
+        match mark {
+            // A command without an end mark is over once a prompt shows up
+            Mark::PromptStart => self.finish_command(None, time),
+
+            Mark::CommandStart => {
+                let event = self.finish_command(None, time);
+                self.shell.prompt = Some(self.cursor_position());
+                self.shell.claimed = None;
+
+                event
+            }
+
+            Mark::OutputStart => {
+                let (line, col) = self.cursor_position();
+
+                // The command line is what was typed after the prompt
+                let command = match self.shell.prompt.take() {
+                    Some(start) => self.text_between(start, (line, col)).trim().to_string(),
+                    None => String::new(),
+                };
+
+                let command = ShellCommand {
+                    id: self.shell.claimed.take().flatten(),
+                    command,
+                    output_lines: line..line,
+                    output: String::new(),
+                    status: None,
+                };
+
+                self.shell.running = Some((command.clone(), line, col));
+
+                Some(Event::CommandStarted(time, command))
+            }
+
+            Mark::CommandEnd(status) => self.finish_command(status, time),
+        }
+    }
+
+    fn finish_command(&mut self, status: Option<i32>, time: f64) -> Option<Event> {
+        let (mut command, start_line, start_col) = self.shell.running.take()?;
+        let (line, col) = self.cursor_position();
+
+        // The output ends with a newline, unless the cursor is past it
+        let end_line = if col > 0 { line + 1 } else { line };
+
+        command.output_lines = start_line..end_line.max(start_line);
+        command.output = self
+            .text_between((start_line, start_col), (line, col))
+            .trim_end()
+            .to_string();
+        command.status = status;
+
+        Some(Event::CommandFinished(time, command))
+    }
+
+    /// Returns the text from one position to another, with a line per row
+    /// except for wrapped rows
+    fn text_between(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        let lines = self.vt.lines();
+        let mut text = String::new();
+
+        for (n, line) in lines.iter().enumerate().take(end.0 + 1).skip(start.0) {
+            let from = if n == start.0 { start.1 } else { 0 };
+            let to = if n == end.0 { end.1 } else { line.len() };
+            let to = to.min(line.len());
+
+            if from < to {
+                text.push_str(cells_text(line, from..to).trim_end());
+            }
+
+            // avt doesn't tell which rows wrapped, those filled up to the
+            // last column are taken as wrapped
+            let wrapped = line
+                .cells()
+                .last()
+                .is_some_and(|cell| cell.width() == 0 || cell.char() != ' ');
+
+            if n < end.0 && !wrapped {
+                text.push('\n');
+            }
+        }
+
+        text
+    }
+
+    /// Returns the cursor's absolute line, counting the scrollback, and its
+    /// column, which is past the last one pending a wrap
+    fn cursor_position(&self) -> (usize, usize) {
+        let cursor = self.vt.cursor();
+        let offset = self.vt.lines().len() - self.vt.view().len();
+
+        (offset + cursor.row, cursor.col)
+    }
+
+    /// Keeps the text printed since the link started, line by line
+    fn end_link(&mut self) {
+        let Some((url, start_line, start_col)) = self.link.take() else {
+            return;
+        };
+
+        let (end_line, end_col) = self.cursor_position();
+        let (cols, rows) = self.vt.size();
+        let lines = self.vt.lines();
+
+        // The cursor jumped around rather than following the text
+        if end_line < start_line || end_line - start_line >= rows {
+            return;
+        }
+
+        for (n, line) in lines.iter().enumerate().take(end_line + 1).skip(start_line) {
+            let from = if n == start_line { start_col } else { 0 };
+            let to = if n == end_line {
+                end_col.min(cols)
+            } else {
+                cols
+            };
+
+            if from >= to {
+                continue;
+            }
+
+            if self.links.len() == MAX_LINKS {
+                self.links.pop_front();
+            }
+
+            self.links.push_back(Hyperlink {
+                url: url.clone(),
+                row: n,
+                cols: from..to,
+                text: cells_text(line, from..to),
+            });
+        }
+    }
+
+    /// Returns the printed links still on the screen, i.e. with their text
+    /// where it was printed, in screen rows
+    fn visible_links(&self) -> Vec<Hyperlink> {
+        let lines = self.vt.lines();
+        let offset = lines.len() - self.vt.view().len();
+        let mut links = Vec::new();
+
+        for link in &self.links {
+            if link.row < offset || link.row >= lines.len() {
+                continue;
+            }
+
+            let line = &lines[link.row];
+
+            if link.cols.end <= line.len() && cells_text(line, link.cols.clone()) == link.text {
+                links.push(Hyperlink {
+                    row: link.row - offset,
+                    ..link.clone()
+                });
+            }
+        }
+
+        links
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            title: self.title.clone(),
+            cwd: self.cwd.clone(),
+            links: self.visible_links(),
+            clipboard: self.clipboard.text.clone(),
+        }
+    }
+
+    fn reply(&self, query: Query) -> String {
+        match query {
+            // VT220 with ANSI color
+            Query::PrimaryAttributes => "\x1b[?62;22c".to_string(),
+
+            Query::SecondaryAttributes => "\x1b[>1;0;0c".to_string(),
+
+            Query::Status => "\x1b[0n".to_string(),
+
+            Query::CursorPosition | Query::ExtendedCursorPosition => {
+                let cursor = self.vt.cursor();
+                let (cols, _) = self.vt.size();
+                let row = cursor.row + 1;
+
+                // The cursor sits past the last column until the next
+                // character wraps
+                let col = cursor.col.min(cols - 1) + 1;
+
+                if query == Query::CursorPosition {
+                    format!("\x1b[{row};{col}R")
+                } else {
+                    format!("\x1b[?{row};{col};1R")
+                }
+            }
+
+            Query::Version => format!("\x1bP>|ht {}\x1b\\", env!("CARGO_PKG_VERSION")),
+
+            Query::Foreground(terminator) => color_reply(10, &self.palette.fg, terminator),
+
+            Query::Background(terminator) => color_reply(11, &self.palette.bg, terminator),
+
+            // Left unanswered unless allowed, as by xterm
+            Query::Clipboard(_, _) if !self.clipboard.readable => String::new(),
+
+            Query::Clipboard(selection, terminator) => {
+                let text = self.clipboard.text.as_deref().unwrap_or_default();
+                let data = BASE64_STANDARD.encode(text);
+
+                format!("\x1b]52;{selection};{data}{}", terminator.as_str())
+            }
+        }
+    }
+
+    fn broadcast(&mut self, event: Event) {
+        self.lossless_txs
+            .retain(|(tx, filter)| !filter(&event) || tx.send(event.clone()).is_ok());
+        let _ = self.broadcast_tx.send(event);
+    }
+
+    fn init_event(&self) -> Event {
         let (cols, rows) = self.vt.size();
 
-        let init = Event::Init(
+        Event::Init(
             self.elapsed_time(),
             cols,
             rows,
             self.pid,
             self.vt.dump(),
             self.text_view(),
-        );
-
-        let broadcast_rx = self.broadcast_tx.subscribe();
-
-        Subscription { init, broadcast_rx }
+            self.metadata(),
+        )
     }
 
     fn elapsed_time(&self) -> f64 {
@@ -135,10 +653,26 @@ impl Session {
     }
 }
 
+fn cells_text(line: &avt::Line, cols: Range<usize>) -> String {
+    line.cells()[cols]
+        .iter()
+        .filter(|cell| cell.width() > 0)
+        .map(|cell| cell.char())
+        .collect()
+}
+
+/// Formats a color like xterm does, with 16 bits per channel
+fn color_reply(code: u8, color: &Rgb, terminator: Terminator) -> String {
+    let Rgb(r, g, b) = color;
+    let terminator = terminator.as_str();
+
+    format!("\x1b]{code};rgb:{r:02x}{r:02x}/{g:02x}{g:02x}/{b:02x}{b:02x}{terminator}")
+}
+
 impl Event {
     pub fn to_json(&self) -> serde_json::Value {
         match self {
-            Event::Init(_time, cols, rows, pid, seq, text) => json!({
+            Event::Init(_time, cols, rows, pid, seq, text, metadata) => json!({
                 "type": "init",
                 "data": json!({
                     "cols": cols,
@@ -146,6 +680,10 @@ impl Event {
                     "pid": pid,
                     "seq": seq,
                     "text": text,
+                    "title": metadata.title,
+                    "cwd": metadata.cwd,
+                    "links": links_json(&metadata.links),
+                    "clipboard": metadata.clipboard,
                 })
             }),
 
@@ -164,13 +702,17 @@ impl Event {
                 })
             }),
 
-            Event::Snapshot(cols, rows, seq, text) => json!({
+            Event::Snapshot(cols, rows, seq, text, metadata) => json!({
                 "type": "snapshot",
                 "data": json!({
                     "cols": cols,
                     "rows": rows,
                     "seq": seq,
                     "text": text,
+                    "title": metadata.title,
+                    "cwd": metadata.cwd,
+                    "links": links_json(&metadata.links),
+                    "clipboard": metadata.clipboard,
                 })
             }),
 
@@ -194,10 +736,75 @@ impl Event {
                     "status": status
                 })
             }),
+
+            Event::Export(format, content) => json!({
+                "type": "export",
+                "data": json!({
+                    "format": format.name(),
+                    "content": content
+                })
+            }),
+
+            Event::Title(_time, title) => json!({
+                "type": "title",
+                "data": json!({
+                    "title": title
+                })
+            }),
+
+            Event::Cwd(_time, cwd) => json!({
+                "type": "cwd",
+                "data": json!({
+                    "cwd": cwd
+                })
+            }),
+
+            Event::Notification(_time, title, body) => json!({
+                "type": "notification",
+                "data": json!({
+                    "title": title,
+                    "body": body
+                })
+            }),
+
+            Event::Clipboard(_time, selection, text) => json!({
+                "type": "clipboard",
+                "data": json!({
+                    "selection": selection,
+                    "text": text
+                })
+            }),
+
+            Event::CommandStarted(_time, command) => json!({
+                "type": "commandStarted",
+                "data": json!({
+                    "id": command.id,
+                    "command": command.command,
+                })
+            }),
+
+            Event::CommandFinished(_time, command) => json!({
+                "type": "commandFinished",
+                "data": command.to_json()
+            }),
         }
     }
 }
 
+fn links_json(links: &[Hyperlink]) -> serde_json::Value {
+    links
+        .iter()
+        .map(|link| {
+            json!({
+                "url": link.url,
+                "text": link.text,
+                "row": link.row,
+                "col": link.cols.start,
+            })
+        })
+        .collect()
+}
+
 fn build_vt(cols: usize, rows: usize) -> avt::Vt {
     avt::Vt::builder().size(cols, rows).build()
 }
@@ -207,19 +814,402 @@ fn resize_vt(vt: &mut avt::Vt, cols: usize, rows: usize) {
 }
 
 impl Client {
-    pub fn accept(self, subscription: Subscription) {
-        let _ = self.0.send(subscription);
+    pub fn accept(self, session: &mut Session) {
+        let subscription = match self.filter {
+            Some(filter) => session.subscribe_filtered(filter),
+            None => session.subscribe(),
+        };
+
+        let _ = self.sub_tx.send(subscription);
     }
 }
 
+impl Subscription {
+    pub fn into_stream(self) -> impl Stream<Item = Result<Event, BroadcastStreamRecvError>> {
+        let init = stream::once(future::ready(Ok(self.init)));
+
+        let events = match self.events {
+            Receiver::Broadcast(rx) => Either::Left(BroadcastStream::new(rx)),
+            Receiver::Lossless(rx) => Either::Right(UnboundedReceiverStream::new(rx).map(Ok)),
+        };
+
+        init.chain(events)
+    }
+}
+
+/// Subscribes to the session, missing events when falling too far behind
 pub async fn stream(
     clients_tx: &mpsc::Sender<Client>,
+) -> Result<impl Stream<Item = Result<Event, BroadcastStreamRecvError>>> {
+    subscribe(clients_tx, None).await
+}
+
+/// Subscribes to the session without ever missing an event
+///
+/// For consumers which must be complete, like recorders. Events queue up in
+/// memory while the consumer is busy.
+pub async fn lossless_stream(
+    clients_tx: &mpsc::Sender<Client>,
+) -> Result<impl Stream<Item = Result<Event, BroadcastStreamRecvError>>> {
+    subscribe(clients_tx, Some(|_| true)).await
+}
+
+/// Subscribes to the session without ever missing an event passing `filter`
+///
+/// Only those events queue up in memory while the consumer is busy.
+pub async fn filtered_stream(
+    clients_tx: &mpsc::Sender<Client>,
+    filter: Filter,
+) -> Result<impl Stream<Item = Result<Event, BroadcastStreamRecvError>>> {
+    subscribe(clients_tx, Some(filter)).await
+}
+
+/// Subscribes to the session, catching up after falling too far behind
+///
+/// Missed events are replaced by a marker noting the gap, followed by a
+/// redraw of the current screen (and a resize, if the size changed).
+pub async fn resync_stream(clients_tx: &mpsc::Sender<Client>) -> Result<impl Stream<Item = Event>> {
+    let resync = Resync {
+        clients_tx: clients_tx.clone(),
+        events: Box::pin(stream(clients_tx).await?),
+        size: None,
+        pending: VecDeque::new(),
+    };
+
+    Ok(stream::unfold(resync, |mut resync| async move {
+        resync.next().await.map(|event| (event, resync))
+    }))
+}
+
+async fn subscribe(
+    clients_tx: &mpsc::Sender<Client>,
+    filter: Option<Filter>,
 ) -> Result<impl Stream<Item = Result<Event, BroadcastStreamRecvError>>> {
     let (sub_tx, sub_rx) = oneshot::channel();
-    clients_tx.send(Client(sub_tx)).await?;
+    clients_tx.send(Client { sub_tx, filter }).await?;
     let sub = tokio::time::timeout(Duration::from_secs(5), sub_rx).await??;
-    let init = stream::once(future::ready(Ok(sub.init)));
-    let events = BroadcastStream::new(sub.broadcast_rx);
 
-    Ok(init.chain(events))
+    Ok(sub.into_stream())
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<Event, BroadcastStreamRecvError>> + Send>>;
+
+struct Resync {
+    clients_tx: mpsc::Sender<Client>,
+    events: EventStream,
+    size: Option<(usize, usize)>,
+    pending: VecDeque<Event>,
+}
+
+impl Resync {
+    async fn next(&mut self) -> Option<Event> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+
+        match self.events.next().await? {
+            Ok(event) => {
+                if let Event::Init(_, cols, rows, _, _, _, _) | Event::Resize(_, cols, rows) = event
+                {
+                    self.size = Some((cols, rows));
+                }
+
+                Some(event)
+            }
+
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                self.events = Box::pin(stream(&self.clients_tx).await.ok()?);
+
+                let Some(Ok(Event::Init(time, cols, rows, _, seq, _, _))) =
+                    self.events.next().await
+                else {
+                    return None;
+                };
+
+                if self.size != Some((cols, rows)) {
+                    self.size = Some((cols, rows));
+                    self.pending.push_back(Event::Resize(time, cols, rows));
+                }
+
+                self.pending
+                    .push_back(Event::Output(time, format!("\x1bc{seq}")));
+
+                Some(Event::Marker(time, format!("gap: {missed} events missed")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lossless_subscription() {
+        let mut session = Session::new(10, 2, 0);
+        let lagging = session.subscribe().into_stream();
+        let lossless = session.subscribe_lossless().into_stream();
+
+        for i in 0..2000 {
+            session.output(i.to_string());
+        }
+
+        drop(session);
+
+        let lagging: Vec<_> = lagging.collect().await;
+        let lossless: Vec<_> = lossless.collect().await;
+
+        assert!(lagging.iter().any(|e| e.is_err()));
+        assert_eq!(lossless.len(), 2001);
+        assert!(matches!(&lossless[2000], Ok(Event::Output(_, data)) if data == "1999"));
+    }
+
+    #[tokio::test]
+    async fn test_filtered_subscription() {
+        let mut session = Session::new(10, 2, 0);
+        let filtered = session
+            .subscribe_filtered(|e| matches!(e, Event::Marker(_, _)))
+            .into_stream();
+
+        session.output("a".to_string());
+        session.marker("m".to_string());
+        session.output("b".to_string());
+        drop(session);
+
+        let filtered: Vec<_> = filtered.collect().await;
+
+        assert_eq!(filtered.len(), 2);
+        assert!(matches!(&filtered[0], Ok(Event::Init(..))));
+        assert!(matches!(&filtered[1], Ok(Event::Marker(_, label)) if label == "m"));
+    }
+
+    #[test]
+    fn test_query_replies() {
+        let mut session = Session::new(10, 3, 0);
+
+        assert_eq!(session.output("hello".to_string()), "");
+        assert_eq!(session.output("\x1b[c".to_string()), "\x1b[?62;22c");
+
+        // The cursor is reported where it was when queried
+        assert_eq!(
+            session.output("\x1b[6n\r\nab\x1b[6nc".to_string()),
+            "\x1b[1;6R\x1b[2;3R"
+        );
+
+        assert_eq!(session.output("\x1b[?6n".to_string()), "\x1b[?2;4;1R");
+        assert_eq!(session.output("\x1b[5n".to_string()), "\x1b[0n");
+
+        // Colors come from the theme, in a query split across chunks
+        assert_eq!(session.output("\x1b]11;".to_string()), "");
+        assert_eq!(
+            session.output("?\x07\x1b]10;?\x1b\\".to_string()),
+            "\x1b]11;rgb:1212/1313/1414\x07\x1b]10;rgb:cccc/cccc/cccc\x1b\\"
+        );
+
+        assert_eq!(session.vt.view()[1].text(), "abc       ");
+    }
+
+    #[tokio::test]
+    async fn test_clipboard() {
+        let mut session = Session::new(10, 3, 0);
+        let events = session.subscribe().into_stream();
+
+        // Reads aren't answered unless allowed
+        session.output("\x1b]52;c;aGk=\x07".to_string());
+        assert_eq!(session.output("\x1b]52;c;?\x07".to_string()), "");
+
+        session.set_clipboard_access(true, 4);
+        assert_eq!(
+            session.output("\x1b]52;;?\x1b\\".to_string()),
+            "\x1b]52;s0;aGk=\x1b\\"
+        );
+
+        session.set_clipboard("ok".to_string()).unwrap();
+        assert_eq!(
+            session.output("\x1b]52;p;?\x07".to_string()),
+            "\x1b]52;p;b2s=\x07"
+        );
+
+        session
+            .set_clipboard("too long".to_string())
+            .expect_err("should fail");
+
+        // Copies over the limit are dropped
+        session.output("\x1b]52;c;dG9vIGxvbmc=\x07".to_string());
+        session.snapshot();
+        drop(session);
+
+        let events: Vec<_> = events
+            .map(|e| e.unwrap().to_json())
+            .filter(|e| std::future::ready(e["type"] != "output"))
+            .collect()
+            .await;
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[1],
+            json!({ "type": "clipboard", "data": { "selection": "c", "text": "hi" } })
+        );
+        assert_eq!(events[2]["data"]["clipboard"], "ok");
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let mut session = Session::new(10, 3, 0);
+        let events = session.subscribe().into_stream();
+
+        session.output("\x1b]2;vim\x07\x1b]7;file://host/tmp\x07".to_string());
+        session.output("\x1b]2;vim\x07\x1b]777;notify;Build;done\x1b\\".to_string());
+
+        session.output(
+            concat!(
+                "a \x1b]8;;https://a.example\x1b\\link\x1b]8;;\x1b\\\r\n",
+                "\x1b]8;;https://b.example\x1b\\gone\x1b]8;;\x1b\\\r\n",
+                "\x1b]8;;https://c.example\x07wrapped link\x1b]8;;\x07",
+            )
+            .to_string(),
+        );
+
+        // The first link scrolls off the screen, the second is overwritten
+        // and the last one scrolls up
+        session.output("\x1b[1;1Hnope\x1b[3;1H\r\n".to_string());
+        session.snapshot();
+        drop(session);
+
+        let events: Vec<_> = events.map(|e| e.unwrap().to_json()).collect().await;
+        let types: Vec<_> = events.iter().map(|e| e["type"].as_str().unwrap()).collect();
+
+        assert_eq!(
+            types,
+            vec![
+                "init",
+                "output",
+                "title",
+                "cwd",
+                "output",
+                "notification",
+                "output",
+                "output",
+                "snapshot"
+            ]
+        );
+
+        assert_eq!(events[2]["data"]["title"], "vim");
+        assert_eq!(events[3]["data"]["cwd"], "/tmp");
+        assert_eq!(events[5]["data"]["title"], "Build");
+        assert_eq!(events[5]["data"]["body"], "done");
+
+        let snapshot = &events[8]["data"];
+        assert_eq!(snapshot["title"], "vim");
+        assert_eq!(snapshot["cwd"], "/tmp");
+
+        assert_eq!(
+            snapshot["links"],
+            json!([
+                { "url": "https://c.example", "text": "wrapped li", "row": 0, "col": 0 },
+                { "url": "https://c.example", "text": "nk", "row": 1, "col": 0 },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shell_integration() {
+        let mut session = Session::new(20, 5, 0);
+        let events = session.subscribe().into_stream();
+
+        assert!(!session.shell_integrated());
+        session.output("\x1b]133;A\x07$ \x1b]133;B\x07".to_string());
+        assert!(session.shell_integrated());
+        assert!(session.at_prompt());
+
+        // runCommand claims the prompt until the next one
+        session.claim_prompt(Some("r1".to_string()));
+        assert!(!session.at_prompt());
+        session.output("ls -l  \r\n\x1b]133;C\x07".to_string());
+        session.output("a\r\nb\r\n\x1b]133;D;2\x07".to_string());
+
+        // A command without an end mark is over at the next prompt
+        session.output("\x1b]133;A\x07$ \x1b]133;B\x07vim\r\n\x1b]133;C\x07x".to_string());
+        session.output("\r\n\x1b]133;A\x07$ \x1b]133;B\x07".to_string());
+        assert!(session.at_prompt());
+        drop(session);
+
+        let events: Vec<_> = events
+            .map(|e| e.unwrap().to_json())
+            .filter(|e| std::future::ready(e["type"] != "output"))
+            .collect()
+            .await;
+
+        assert_eq!(
+            events,
+            vec![
+                json!({ "type": "init", "data": events[0]["data"] }),
+                json!({
+                    "type": "commandStarted",
+                    "data": { "id": "r1", "command": "ls -l" }
+                }),
+                json!({
+                    "type": "commandFinished",
+                    "data": {
+                        "id": "r1",
+                        "command": "ls -l",
+                        "status": 2,
+                        "output": "a\nb",
+                        "outputRange": { "start": 1, "end": 3 }
+                    }
+                }),
+                json!({
+                    "type": "commandStarted",
+                    "data": { "id": null, "command": "vim" }
+                }),
+                json!({
+                    "type": "commandFinished",
+                    "data": {
+                        "id": null,
+                        "command": "vim",
+                        "status": null,
+                        "output": "x",
+                        "outputRange": { "start": 4, "end": 5 }
+                    }
+                }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resync_after_lag() {
+        let (clients_tx, mut clients_rx) = mpsc::channel::<Client>(1);
+
+        tokio::spawn(async move {
+            let mut session = Session::new(10, 2, 0);
+            clients_rx.recv().await.unwrap().accept(&mut session);
+
+            for _ in 0..2000 {
+                session.output("x".to_string());
+            }
+
+            session.resize(5, 2);
+
+            // The lagging subscriber resubscribes
+            clients_rx.recv().await.unwrap().accept(&mut session);
+            session.output("after".to_string());
+        });
+
+        let events: Vec<_> = resync_stream(&clients_tx).await.unwrap().collect().await;
+
+        assert_eq!(events.len(), 5);
+        assert!(matches!(events[0], Event::Init(_, 10, 2, _, _, _, _)));
+        assert!(matches!(&events[1], Event::Marker(_, label) if label.starts_with("gap: ")));
+        assert!(matches!(events[2], Event::Resize(_, 5, 2)));
+
+        let Event::Output(_, seq) = &events[3] else {
+            panic!("expected a redraw");
+        };
+
+        let mut vt = avt::Vt::new(5, 2);
+        vt.feed_str(seq);
+        assert_eq!(vt.view()[0].text(), "xxxxx");
+
+        assert!(matches!(&events[4], Event::Output(_, data) if data == "after"));
+    }
 }
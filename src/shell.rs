@@ -0,0 +1,217 @@
+/// Shell integration snippets, marking prompts and commands with OSC 133, and
+/// their injection into the shell ht launches
+use crate::daemon;
+use anyhow::{Context, Result};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+const BASH: &str = include_str!("shell/integration.bash");
+const ZSH: &str = include_str!("shell/integration.zsh");
+const FISH: &str = include_str!("shell/integration.fish");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    /// Returns the shell a program path refers to, if supported
+    fn of_program(program: &str) -> Option<Self> {
+        let name = Path::new(program).file_name()?.to_str()?;
+
+        name.trim_start_matches('-').parse().ok()
+    }
+
+    pub fn snippet(&self) -> &'static str {
+        match self {
+            Shell::Bash => BASH,
+            Shell::Zsh => ZSH,
+            Shell::Fish => FISH,
+        }
+    }
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(format!("unsupported shell: {s} (use bash, zsh or fish)")),
+        }
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Shell::Bash => f.write_str("bash"),
+            Shell::Zsh => f.write_str("zsh"),
+            Shell::Fish => f.write_str("fish"),
+        }
+    }
+}
+
+/// Returns the command launching an interactive shell with its integration
+/// loaded after the user's own startup files
+///
+/// Only a shell started without arguments is changed, anything else is
+/// launched as is with a warning.
+pub fn inject(command: &[String]) -> Result<Vec<String>> {
+    let shell = match command {
+        [program] => Shell::of_program(program),
+        _ => None,
+    };
+
+    let Some(shell) = shell else {
+        eprintln!("shell integration needs bash, zsh or fish without arguments, launching {command:?} as is");
+
+        return Ok(command.to_vec());
+    };
+
+    let dir = daemon::dir()?.join("shell");
+    fs::create_dir_all(&dir).with_context(|| format!("can't create {}", dir.display()))?;
+
+    inject_into(shell, &command[0], &dir)
+}
+
+fn inject_into(shell: Shell, program: &str, dir: &Path) -> Result<Vec<String>> {
+    let snippet = dir.join(format!("integration.{shell}"));
+    fs::write(&snippet, shell.snippet())?;
+    let snippet = snippet.to_string_lossy().into_owned();
+
+    match shell {
+        // --init-file replaces ~/.bashrc, so it's sourced first
+        Shell::Bash => {
+            let rc = dir.join("bashrc");
+
+            fs::write(
+                &rc,
+                format!("[ -f ~/.bashrc ] && . ~/.bashrc\n. {}\n", quote(&snippet)),
+            )?;
+
+            Ok(vec![
+                program.to_string(),
+                "--init-file".to_string(),
+                rc.to_string_lossy().into_owned(),
+            ])
+        }
+
+        // zsh reads its startup files from ZDOTDIR, pointed at wrappers
+        // sourcing the user's files, which restore ZDOTDIR in the end
+        Shell::Zsh => {
+            let zdotdir = dir.join("zsh");
+            fs::create_dir_all(&zdotdir)?;
+
+            fs::write(
+                zdotdir.join(".zshenv"),
+                concat!(
+                    "__ht_zdotdir=$ZDOTDIR\n",
+                    "ZDOTDIR=${HT_USER_ZDOTDIR:-$HOME}\n",
+                    "[[ -f $ZDOTDIR/.zshenv ]] && source $ZDOTDIR/.zshenv\n",
+                    "HT_USER_ZDOTDIR=$ZDOTDIR\n",
+                    "ZDOTDIR=$__ht_zdotdir\n",
+                    "unset __ht_zdotdir\n",
+                ),
+            )?;
+
+            fs::write(
+                zdotdir.join(".zshrc"),
+                format!(
+                    concat!(
+                        "ZDOTDIR=$HT_USER_ZDOTDIR\n",
+                        "unset HT_USER_ZDOTDIR\n",
+                        "[[ -f $ZDOTDIR/.zshrc ]] && source $ZDOTDIR/.zshrc\n",
+                        "source {}\n",
+                    ),
+                    quote(&snippet)
+                ),
+            )?;
+
+            let user_zdotdir = env::var("ZDOTDIR").unwrap_or_default();
+
+            Ok(vec![
+                "env".to_string(),
+                format!("HT_USER_ZDOTDIR={user_zdotdir}"),
+                format!("ZDOTDIR={}", zdotdir.to_string_lossy()),
+                program.to_string(),
+            ])
+        }
+
+        // Commands given with -C run after config.fish
+        Shell::Fish => Ok(vec![
+            program.to_string(),
+            "-C".to_string(),
+            format!("source {}", quote(&snippet)),
+        ]),
+    }
+}
+
+/// Quotes a path for the shells, which all take single-quoted strings
+fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &[String]) -> Vec<&str> {
+        command.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn test_inject() {
+        let dir = env::temp_dir().join(format!("ht-shell-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let command = inject_into(Shell::Bash, "/bin/bash", &dir).unwrap();
+        assert_eq!(
+            args(&command),
+            vec!["/bin/bash", "--init-file", &path("bashrc")]
+        );
+        let rc = fs::read_to_string(dir.join("bashrc")).unwrap();
+        assert!(rc.contains(&format!(". '{}'", path("integration.bash"))));
+        assert_eq!(
+            fs::read_to_string(dir.join("integration.bash")).unwrap(),
+            BASH
+        );
+
+        let command = inject_into(Shell::Zsh, "zsh", &dir).unwrap();
+        assert_eq!(command[0], "env");
+        assert!(command.contains(&format!("ZDOTDIR={}", path("zsh"))));
+        assert_eq!(command.last().unwrap(), "zsh");
+        assert!(dir.join("zsh/.zshenv").exists());
+        assert!(dir.join("zsh/.zshrc").exists());
+
+        let command = inject_into(Shell::Fish, "fish", &dir).unwrap();
+        assert_eq!(
+            args(&command),
+            vec![
+                "fish",
+                "-C",
+                &format!("source '{}'", path("integration.fish"))
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Other commands are launched as is
+        let command = vec!["bash".to_string(), "-c".to_string(), "ls".to_string()];
+        assert_eq!(inject(&command).unwrap(), command);
+        let command = vec!["python3".to_string()];
+        assert_eq!(inject(&command).unwrap(), command);
+
+        assert_eq!(Shell::of_program("/usr/bin/zsh"), Some(Shell::Zsh));
+        assert_eq!(Shell::of_program("-bash"), Some(Shell::Bash));
+        assert_eq!(Shell::of_program("sh"), None);
+    }
+}
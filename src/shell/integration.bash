@@ -0,0 +1,26 @@
+# ht shell integration for bash: marks prompts and commands with OSC 133
+
+if [[ -z "$__ht_integrated" ]]; then
+    __ht_integrated=1
+
+    __ht_precmd() {
+        local status=$?
+        printf '\e]133;D;%s\a' "$status"
+        return $status
+    }
+
+    # Runs last, so that the marks survive prompt commands rewriting PS1
+    __ht_postcmd() {
+        local status=$?
+
+        if [[ "$PS1" != *'133;B'* ]]; then
+            PS1='\[\e]133;A\a\]'"$PS1"'\[\e]133;B\a\]'
+        fi
+
+        return $status
+    }
+
+    PS0="$PS0"'\e]133;C\a'
+    # Newlines keep a PROMPT_COMMAND ending with ; valid
+    PROMPT_COMMAND=$'__ht_precmd\n'"$PROMPT_COMMAND"$'\n__ht_postcmd'
+fi
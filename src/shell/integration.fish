@@ -0,0 +1,22 @@
+# ht shell integration for fish: marks prompts and commands with OSC 133
+
+if not set -q __ht_integrated
+    set -g __ht_integrated 1
+
+    function __ht_preexec --on-event fish_preexec
+        printf '\e]133;C\a'
+    end
+
+    function __ht_postexec --on-event fish_postexec
+        printf '\e]133;D;%s\a' $status
+    end
+
+    functions -q fish_prompt
+    functions -c fish_prompt __ht_fish_prompt
+
+    function fish_prompt
+        printf '\e]133;A\a'
+        __ht_fish_prompt
+        printf '\e]133;B\a'
+    end
+end
@@ -1,7 +1,7 @@
-/// ALiS v1 binary protocol encoder
+/// ALiS v1 binary protocol encoder and decoder
 ///
 /// Specification: https://docs.asciinema.org/manual/alis/v1/
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
 /// ALiS magic string and version
 pub const ALIS_MAGIC: &[u8] = b"ALiS\x01";
@@ -11,12 +11,29 @@ pub const ALIS_MAGIC: &[u8] = b"ALiS\x01";
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EventType {
     Init = 0x01,
-    EOT = 0x04,     // End of Transmission
-    Output = 0x6F,  // 'o'
-    Input = 0x69,   // 'i'
-    Resize = 0x72,  // 'r'
-    Marker = 0x6D,  // 'm'
-    Exit = 0x78,    // 'x'
+    Eot = 0x04,    // End of Transmission
+    Output = 0x6F, // 'o'
+    Input = 0x69,  // 'i'
+    Resize = 0x72, // 'r'
+    Marker = 0x6D, // 'm'
+    Exit = 0x78,   // 'x'
+}
+
+impl EventType {
+    fn from_byte(byte: u8) -> Result<Self> {
+        let event_type = match byte {
+            0x01 => EventType::Init,
+            0x04 => EventType::Eot,
+            0x6F => EventType::Output,
+            0x69 => EventType::Input,
+            0x72 => EventType::Resize,
+            0x6D => EventType::Marker,
+            0x78 => EventType::Exit,
+            _ => bail!("unknown event type: {byte:#04x}"),
+        };
+
+        Ok(event_type)
+    }
 }
 
 /// Theme format codes
@@ -60,7 +77,7 @@ pub fn encode_string(s: &str) -> Vec<u8> {
 }
 
 /// Theme configuration for encoding
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Theme {
     pub fg: String,
     pub bg: String,
@@ -124,7 +141,7 @@ pub fn encode_theme(theme: Option<&Theme>) -> Result<Vec<u8>> {
 }
 
 /// Parse color string (#RRGGBB) to RGB bytes
-fn parse_color(color: &str) -> Result<[u8; 3]> {
+pub fn parse_color(color: &str) -> Result<[u8; 3]> {
     let color = color.trim_start_matches('#');
 
     if color.len() != 6 {
@@ -228,13 +245,220 @@ pub fn encode_exit(id: u64, rel_time: u64, status: i32) -> Vec<u8> {
 pub fn encode_eot(id: u64, rel_time: u64) -> Vec<u8> {
     let mut buf = Vec::new();
 
-    buf.push(EventType::EOT as u8);
+    buf.push(EventType::Eot as u8);
     buf.extend_from_slice(&encode_leb128(id));
     buf.extend_from_slice(&encode_leb128(rel_time));
 
     buf
 }
 
+/// Decoded ALiS event
+///
+/// Times are in microseconds, relative to the previous event, except for the
+/// init event's, which is the stream time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlisEvent {
+    Init {
+        last_id: u64,
+        time: u64,
+        cols: u16,
+        rows: u16,
+        theme: Option<Theme>,
+        init_data: String,
+    },
+    Output {
+        id: u64,
+        rel_time: u64,
+        data: String,
+    },
+    Input {
+        id: u64,
+        rel_time: u64,
+        data: String,
+    },
+    Resize {
+        id: u64,
+        rel_time: u64,
+        cols: u16,
+        rows: u16,
+    },
+    Marker {
+        id: u64,
+        rel_time: u64,
+        label: String,
+    },
+    Exit {
+        id: u64,
+        rel_time: u64,
+        status: i32,
+    },
+    Eot {
+        id: u64,
+        rel_time: u64,
+    },
+}
+
+/// Reads consecutive fields of an ALiS message
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self
+            .buf
+            .get(self.pos)
+            .context("unexpected end of message")?;
+        self.pos += 1;
+
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos.saturating_add(len))
+            .context("unexpected end of message")?;
+
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    fn leb128(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        bail!("LEB128 value is too long")
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let value = self.leb128()?;
+
+        u16::try_from(value).with_context(|| format!("value out of range: {value}"))
+    }
+
+    /// Reads a terminal size, which must be at least 1x1
+    fn size(&mut self) -> Result<(u16, u16)> {
+        let cols = self.u16()?;
+        let rows = self.u16()?;
+
+        if cols == 0 || rows == 0 {
+            bail!("invalid terminal size: {cols}x{rows}");
+        }
+
+        Ok((cols, rows))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.leb128()? as usize;
+        let bytes = self.bytes(len)?;
+
+        String::from_utf8(bytes.to_vec()).context("string is not valid UTF-8")
+    }
+
+    fn color(&mut self) -> Result<String> {
+        let rgb = self.bytes(3)?;
+
+        Ok(format!("#{:02x}{:02x}{:02x}", rgb[0], rgb[1], rgb[2]))
+    }
+
+    fn theme(&mut self) -> Result<Option<Theme>> {
+        let colors = match self.byte()? {
+            f if f == ThemeFormat::None as u8 => return Ok(None),
+            f if f == ThemeFormat::Palette8 as u8 => 8,
+            f if f == ThemeFormat::Palette16 as u8 => 16,
+            format => bail!("invalid theme format: {format:#04x}"),
+        };
+
+        let fg = self.color()?;
+        let bg = self.color()?;
+        let palette = (0..colors).map(|_| self.color()).collect::<Result<_>>()?;
+
+        Ok(Some(Theme { fg, bg, palette }))
+    }
+}
+
+/// Decode a single event message
+pub fn decode_event(buf: &[u8]) -> Result<AlisEvent> {
+    let mut r = Reader { buf, pos: 0 };
+
+    let event = match EventType::from_byte(r.byte()?)? {
+        EventType::Init => {
+            let last_id = r.leb128()?;
+            let time = r.leb128()?;
+            let (cols, rows) = r.size()?;
+
+            AlisEvent::Init {
+                last_id,
+                time,
+                cols,
+                rows,
+                theme: r.theme()?,
+                init_data: r.string()?,
+            }
+        }
+
+        EventType::Output => AlisEvent::Output {
+            id: r.leb128()?,
+            rel_time: r.leb128()?,
+            data: r.string()?,
+        },
+
+        EventType::Input => AlisEvent::Input {
+            id: r.leb128()?,
+            rel_time: r.leb128()?,
+            data: r.string()?,
+        },
+
+        EventType::Resize => {
+            let id = r.leb128()?;
+            let rel_time = r.leb128()?;
+            let (cols, rows) = r.size()?;
+
+            AlisEvent::Resize {
+                id,
+                rel_time,
+                cols,
+                rows,
+            }
+        }
+
+        EventType::Marker => AlisEvent::Marker {
+            id: r.leb128()?,
+            rel_time: r.leb128()?,
+            label: r.string()?,
+        },
+
+        // The status is sign extended by the encoder
+        EventType::Exit => AlisEvent::Exit {
+            id: r.leb128()?,
+            rel_time: r.leb128()?,
+            status: r.leb128()? as i32,
+        },
+
+        EventType::Eot => AlisEvent::Eot {
+            id: r.leb128()?,
+            rel_time: r.leb128()?,
+        },
+    };
+
+    if r.pos != buf.len() {
+        bail!("{} trailing byte(s) after event", buf.len() - r.pos);
+    }
+
+    Ok(event)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -317,7 +541,7 @@ mod tests {
     #[test]
     fn test_eot_event_encoding() {
         let encoded = encode_eot(5, 300);
-        assert_eq!(encoded[0], EventType::EOT as u8);
+        assert_eq!(encoded[0], EventType::Eot as u8);
         assert_eq!(encoded[1], 0x05); // id = 5
         assert_eq!(encoded[2], 0xAC); // rel_time = 300 (0xAC, 0x02 in LEB128)
         assert_eq!(encoded[3], 0x02);
@@ -336,4 +560,105 @@ mod tests {
         assert_eq!(encoded[6], 0x04); // string length = 4
         assert_eq!(&encoded[7..], b"test");
     }
+
+    #[test]
+    fn test_round_trip() {
+        let theme = Theme {
+            fg: "#cccccc".to_string(),
+            bg: "#121314".to_string(),
+            palette: (0..16).map(|i| format!("#0000{i:02x}")).collect(),
+        };
+
+        let messages = [
+            encode_init(7, 1_500_000, 80, 24, Some(&theme), "\x1b[1mhi").unwrap(),
+            encode_output(8, 300, "żółw 🐢"),
+            encode_input(9, 0, "\r"),
+            encode_resize(10, 128, 132, 50),
+            encode_marker(11, 16384, "chapter 1"),
+            encode_exit(12, 1, -1),
+            encode_eot(13, 2),
+        ];
+
+        let events: Vec<_> = messages.iter().map(|m| decode_event(m).unwrap()).collect();
+
+        assert_eq!(
+            events,
+            vec![
+                AlisEvent::Init {
+                    last_id: 7,
+                    time: 1_500_000,
+                    cols: 80,
+                    rows: 24,
+                    theme: Some(theme),
+                    init_data: "\x1b[1mhi".to_string(),
+                },
+                AlisEvent::Output {
+                    id: 8,
+                    rel_time: 300,
+                    data: "żółw 🐢".to_string(),
+                },
+                AlisEvent::Input {
+                    id: 9,
+                    rel_time: 0,
+                    data: "\r".to_string(),
+                },
+                AlisEvent::Resize {
+                    id: 10,
+                    rel_time: 128,
+                    cols: 132,
+                    rows: 50,
+                },
+                AlisEvent::Marker {
+                    id: 11,
+                    rel_time: 16384,
+                    label: "chapter 1".to_string(),
+                },
+                AlisEvent::Exit {
+                    id: 12,
+                    rel_time: 1,
+                    status: -1,
+                },
+                AlisEvent::Eot {
+                    id: 13,
+                    rel_time: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_8_color_theme_round_trip() {
+        let theme = Theme {
+            fg: "#ffffff".to_string(),
+            bg: "#000000".to_string(),
+            palette: (0..8).map(|i| format!("#{i:02x}0000")).collect(),
+        };
+
+        let encoded = encode_init(0, 0, 10, 2, Some(&theme), "").unwrap();
+
+        let AlisEvent::Init { theme: decoded, .. } = decode_event(&encoded).unwrap() else {
+            panic!("expected init");
+        };
+
+        assert_eq!(decoded, Some(theme));
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let output = encode_output(1, 2, "hello");
+
+        assert!(decode_event(&[]).is_err());
+        assert!(decode_event(&[0x42, 0x00]).is_err());
+        assert!(decode_event(&output[..output.len() - 1]).is_err());
+        assert!(decode_event(&[output.as_slice(), &[0x00]].concat()).is_err());
+        assert!(decode_event(&encode_output(1, 2, "\u{0}")[..4]).is_err());
+        assert!(decode_event(&[0x6F, 0x01, 0x00, 0x02, 0xC3, 0x28]).is_err());
+    }
+
+    #[test]
+    fn test_empty_size() {
+        assert!(decode_event(&encode_init(0, 0, 0, 24, None, "").unwrap()).is_err());
+        assert!(decode_event(&encode_init(0, 0, 80, 0, None, "").unwrap()).is_err());
+        assert!(decode_event(&encode_resize(1, 0, 0, 0)).is_err());
+    }
 }
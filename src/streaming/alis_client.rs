@@ -0,0 +1,116 @@
+/// ALiS v1 consumer, for watching a live stream of any ALiS producer
+use crate::streaming::alis::{self, AlisEvent};
+use anyhow::{bail, Context, Result};
+use futures_util::{stream, Stream, StreamExt};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// Connects to an ALiS endpoint (e.g. `ws://127.0.0.1:8080/ws/alis-v1`)
+///
+/// The stream ends when the connection is closed. Messages which can't be
+/// decoded are passed on as errors, without ending it.
+pub async fn connect(url: &str) -> Result<impl Stream<Item = Result<AlisEvent>>> {
+    let mut request = url
+        .into_client_request()
+        .context("failed to build WebSocket request")?;
+
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static("v1.alis"),
+    );
+
+    let (mut ws_stream, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .with_context(|| format!("failed to connect to {url}"))?;
+
+    match next_binary(&mut ws_stream).await {
+        Some(Ok(magic)) if magic == alis::ALIS_MAGIC => {}
+        Some(Err(e)) => return Err(e).context("failed to read ALiS magic"),
+        _ => bail!("{url} isn't an ALiS v1 stream"),
+    }
+
+    Ok(stream::unfold(Some(ws_stream), |ws_stream| async move {
+        let mut ws_stream = ws_stream?;
+
+        match next_binary(&mut ws_stream).await? {
+            Ok(data) => Some((alis::decode_event(&data), Some(ws_stream))),
+            Err(e) => Some((Err(e.into()), None)),
+        }
+    }))
+}
+
+/// Returns the next binary message, skipping the others, or `None` once the
+/// connection is closed
+async fn next_binary<S>(
+    ws_stream: &mut S,
+) -> Option<Result<Vec<u8>, tokio_tungstenite::tungstenite::Error>>
+where
+    S: Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    loop {
+        match ws_stream.next().await? {
+            Ok(Message::Binary(data)) => return Some(Ok(data)),
+            Ok(Message::Close(_)) => return None,
+            Ok(_) => continue,
+            Err(e) => return Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+    use std::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_watch_http_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let (command_tx, _) = mpsc::channel(1);
+        let options = crate::api::http::Options::default();
+        let server = crate::api::http::start(listener, clients_tx, command_tx, options)
+            .await
+            .unwrap();
+
+        tokio::spawn(server);
+
+        let mut session = Session::new(10, 2, 0);
+        session.output("hello".to_string());
+
+        let url = format!("ws://{addr}/ws/alis-v1");
+        let connecting = tokio::spawn(async move { connect(&url).await.map(Box::pin) });
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        let mut events = connecting.await.unwrap().unwrap();
+
+        let Some(Ok(AlisEvent::Init {
+            cols: 10,
+            rows: 2,
+            init_data,
+            ..
+        })) = events.next().await
+        else {
+            panic!("expected init");
+        };
+
+        let mut vt = avt::Vt::new(10, 2);
+        vt.feed_str(&init_data);
+        assert_eq!(vt.view()[0].text(), "hello     ");
+
+        session.output(" world".to_string());
+        session.exit(3);
+
+        assert!(matches!(
+            events.next().await,
+            Some(Ok(AlisEvent::Output { data, .. })) if data == " world"
+        ));
+
+        assert!(matches!(
+            events.next().await,
+            Some(Ok(AlisEvent::Exit { status: 3, .. }))
+        ));
+    }
+}
@@ -1,38 +1,12 @@
+use crate::redact::Redactor;
 use crate::session;
 use crate::streaming::alis;
 use anyhow::Result;
 use axum::extract::ws;
 use futures_util::{SinkExt, StreamExt};
+use std::borrow::Cow;
 use std::time::Instant;
 use tokio::sync::mpsc;
-use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
-
-/// Handle ALiS binary WebSocket connection for local consumers
-pub async fn handle_alis_binary_socket(
-    socket: ws::WebSocket,
-    clients_tx: mpsc::Sender<session::Client>,
-) -> Result<()> {
-    let (mut sink, stream) = socket.split();
-
-    // Drain incoming messages (consumer shouldn't send anything meaningful)
-    let drainer = tokio::spawn(stream.map(Ok).forward(futures_util::sink::drain()));
-
-    // Send magic string immediately
-    sink.send(ws::Message::Binary(alis::ALIS_MAGIC.to_vec()))
-        .await?;
-
-    // Subscribe to events and convert to ALiS binary messages
-    let result = session::stream(&clients_tx)
-        .await?
-        .filter_map(alis_binary_message)
-        .forward(&mut sink)
-        .await;
-
-    drainer.abort();
-    result?;
-
-    Ok(())
-}
 
 /// State tracker for ALiS binary encoding
 struct AlisState {
@@ -63,61 +37,12 @@ impl AlisState {
     }
 }
 
-async fn alis_binary_message(
-    event: Result<session::Event, BroadcastStreamRecvError>,
-) -> Option<Result<ws::Message, axum::Error>> {
-    use session::Event::*;
-
-    // We need to maintain state across calls, but filter_map doesn't allow mutable state
-    // For now, we'll use thread-local storage or compute relative time on the fly
-    // This is a limitation - ideally we'd use a stateful stream adapter
-
-    match event {
-        Ok(Init(_time, cols, rows, _pid, seq, _text)) => {
-            // For Init, rel_time should be 0 (or microseconds since session start)
-            match alis::encode_init(0, 0, cols as u16, rows as u16, None, &seq) {
-                Ok(bytes) => Some(Ok(ws::Message::Binary(bytes))),
-                Err(e) => Some(Err(axum::Error::new(e))),
-            }
-        }
-
-        Ok(Output(_time, data)) => {
-            // Without state, we can't properly calculate rel_time
-            // We'll use absolute time converted to micros as a workaround
-            // This is not ideal but functional for local preview
-            let id = (_time * 1_000_000.0) as u64; // Use time as pseudo-id
-            let bytes = alis::encode_output(id, 0, &data);
-            Some(Ok(ws::Message::Binary(bytes)))
-        }
-
-        Ok(Resize(_time, cols, rows)) => {
-            let id = (_time * 1_000_000.0) as u64;
-            let bytes = alis::encode_resize(id, 0, cols as u16, rows as u16);
-            Some(Ok(ws::Message::Binary(bytes)))
-        }
-
-        Ok(Marker(_time, label)) => {
-            let id = (_time * 1_000_000.0) as u64;
-            let bytes = alis::encode_marker(id, 0, &label);
-            Some(Ok(ws::Message::Binary(bytes)))
-        }
-
-        Ok(Exit(_time, status)) => {
-            let id = (_time * 1_000_000.0) as u64;
-            let bytes = alis::encode_exit(id, 0, status);
-            Some(Ok(ws::Message::Binary(bytes)))
-        }
-
-        Ok(Input(_, _)) | Ok(Snapshot(_, _, _, _)) => None,
-
-        Err(e) => Some(Err(axum::Error::new(e))),
-    }
-}
-
 /// Stateful version using proper state tracking
 pub async fn handle_alis_binary_socket_stateful(
     socket: ws::WebSocket,
     clients_tx: mpsc::Sender<session::Client>,
+    theme: Option<alis::Theme>,
+    redactor: Redactor,
 ) -> Result<()> {
     let (mut sink, stream) = socket.split();
 
@@ -128,43 +53,57 @@ pub async fn handle_alis_binary_socket_stateful(
     sink.send(ws::Message::Binary(alis::ALIS_MAGIC.to_vec()))
         .await?;
 
-    // Subscribe to events
-    let mut events = session::stream(&clients_tx).await?;
+    // Subscribe to events, catching up with the screen if the client can't
+    // keep up
+    let events = redactor.stream(session::resync_stream(&clients_tx).await?);
+    tokio::pin!(events);
     let mut state = AlisState::new();
 
-    while let Some(event_result) = events.next().await {
-        match event_result {
-            Ok(event) => {
-                if let Some(msg) = convert_to_alis_binary(&mut state, event)? {
-                    if sink.send(msg).await.is_err() {
-                        break;
-                    }
-                }
-            }
-            Err(_) => {
-                // Lagged, continue
-                continue;
+    while let Some(event) = events.next().await {
+        if let Some(msg) = convert_to_alis_binary(&mut state, event, theme.as_ref())? {
+            if sink.send(msg).await.is_err() {
+                drainer.abort();
+                return Ok(());
             }
         }
     }
 
+    // The session is over, as opposed to the connection being dropped
+    let _ = sink.send(eot_message(&mut state)).await;
+    let _ = sink.send(close_message()).await;
+
     drainer.abort();
     Ok(())
 }
 
+fn eot_message(state: &mut AlisState) -> ws::Message {
+    state.event_id += 1;
+    let rel_time = state.calculate_rel_time_micros();
+
+    ws::Message::Binary(alis::encode_eot(state.event_id, rel_time))
+}
+
+fn close_message() -> ws::Message {
+    ws::Message::Close(Some(ws::CloseFrame {
+        code: ws::close_code::NORMAL,
+        reason: Cow::from("ended"),
+    }))
+}
+
 fn convert_to_alis_binary(
     state: &mut AlisState,
     event: session::Event,
+    theme: Option<&alis::Theme>,
 ) -> Result<Option<ws::Message>> {
     use session::Event::*;
 
     match event {
-        Init(_time, cols, rows, _pid, seq, _text) => {
+        Init(_time, cols, rows, _pid, seq, _text, _) => {
             state.start_time = Instant::now();
             state.last_event_time = Some(state.start_time);
             state.event_id = 0;
 
-            let bytes = alis::encode_init(0, 0, cols as u16, rows as u16, None, &seq)?;
+            let bytes = alis::encode_init(0, 0, cols as u16, rows as u16, theme, &seq)?;
             Ok(Some(ws::Message::Binary(bytes)))
         }
 
@@ -196,6 +135,115 @@ fn convert_to_alis_binary(
             Ok(Some(ws::Message::Binary(bytes)))
         }
 
-        Input(_, _) | Snapshot(_, _, _, _) => Ok(None),
+        Input(_, _)
+        | Snapshot(_, _, _, _, _)
+        | Export(_, _)
+        | Title(_, _)
+        | Cwd(_, _)
+        | Notification(_, _, _)
+        | CommandStarted(_, _)
+        | CommandFinished(_, _)
+        | Clipboard(_, _, _) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+    use crate::streaming::alis::AlisEvent;
+    use std::net::TcpListener;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message;
+
+    async fn next<S>(ws_stream: &mut S) -> Message
+    where
+        S: futures_util::Stream<Item = tokio_tungstenite::tungstenite::Result<Message>> + Unpin,
+    {
+        ws_stream.next().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_restart_and_eot() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let (command_tx, _) = mpsc::channel(1);
+        let options = crate::api::http::Options::default();
+        let server = crate::api::http::start(listener, clients_tx, command_tx, options)
+            .await
+            .unwrap();
+
+        tokio::spawn(server);
+
+        let mut request = format!("ws://{addr}/ws/alis-v1")
+            .into_client_request()
+            .unwrap();
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static("v1.alis"),
+        );
+
+        let connecting = tokio::spawn(tokio_tungstenite::connect_async(request));
+        let mut session = Session::new(10, 2, 0);
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        let (mut ws_stream, _) = connecting.await.unwrap().unwrap();
+
+        assert_eq!(
+            next(&mut ws_stream).await,
+            Message::Binary(alis::ALIS_MAGIC.to_vec())
+        );
+
+        let init = next(&mut ws_stream).await.into_data();
+        let Ok(AlisEvent::Init { init_data, .. }) = alis::decode_event(&init) else {
+            panic!("expected init");
+        };
+
+        assert_eq!(
+            init,
+            alis::encode_init(0, 0, 10, 2, None, &init_data).unwrap()
+        );
+
+        session.output("a".to_string());
+        let output = next(&mut ws_stream).await.into_data();
+        let Ok(AlisEvent::Output { rel_time, .. }) = alis::decode_event(&output) else {
+            panic!("expected output");
+        };
+
+        assert_eq!(output, alis::encode_output(1, rel_time, "a"));
+
+        // A restarted session starts over with a new init
+        session.restart(12, 3, "b".to_string());
+        let init = next(&mut ws_stream).await.into_data();
+        let Ok(AlisEvent::Init { init_data, .. }) = alis::decode_event(&init) else {
+            panic!("expected init");
+        };
+
+        assert_eq!(
+            init,
+            alis::encode_init(0, 0, 12, 3, None, &init_data).unwrap()
+        );
+
+        let mut vt = avt::Vt::new(12, 3);
+        vt.feed_str(&init_data);
+        assert_eq!(vt.view()[0].text().trim_end(), "b");
+
+        // Ending the session is announced before closing the connection
+        drop(session);
+        let eot = next(&mut ws_stream).await.into_data();
+        let Ok(AlisEvent::Eot { rel_time, .. }) = alis::decode_event(&eot) else {
+            panic!("expected EOT");
+        };
+
+        assert_eq!(eot, alis::encode_eot(1, rel_time));
+
+        let Message::Close(Some(frame)) = next(&mut ws_stream).await else {
+            panic!("expected close frame");
+        };
+
+        assert_eq!(frame.code, CloseCode::Normal);
+        assert_eq!(frame.reason, "ended");
     }
 }
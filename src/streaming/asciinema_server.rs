@@ -1,15 +1,24 @@
-use crate::session::Event;
+use crate::redact::Redactor;
+use crate::session::{Event, Metadata};
 use crate::streaming::alis;
 use anyhow::{Context, Result};
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{SinkExt, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::VecDeque;
 use std::path::PathBuf;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
 use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use tokio_tungstenite::{connect_async_with_config, tungstenite::protocol::Message};
 
+type WsStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Clone)]
 pub enum StreamProtocol {
     Alis,
@@ -27,6 +36,28 @@ pub struct StreamerConfig {
     pub capture_input: bool,
     pub theme: Option<alis::Theme>,
     pub term_type: Option<String>,
+    pub redactor: Redactor,
+    pub reconnect: ReconnectConfig,
+}
+
+/// How to reconnect after losing the connection to the server
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    /// Delay before the first attempt, doubled after each failed one
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// How much event data to keep for replaying after reconnecting
+    pub buffer_size: usize,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            buffer_size: 1024 * 1024,
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -52,6 +83,7 @@ pub struct AsciinemaServerStreamer {
     event_id: u64,
     last_event_time: Option<Instant>,
     start_time: Instant,
+    screen: Screen,
 }
 
 impl AsciinemaServerStreamer {
@@ -61,6 +93,7 @@ impl AsciinemaServerStreamer {
             event_id: 0,
             last_event_time: None,
             start_time: Instant::now(),
+            screen: Screen::default(),
         }
     }
 
@@ -74,47 +107,161 @@ impl AsciinemaServerStreamer {
 
         // Create stream
         let ws_url = self.create_stream(&install_id).await?;
-        eprintln!("Connected to asciinema server");
 
         // Connect to WebSocket
-        let (mut ws_stream, _) = self.connect_websocket(&ws_url).await?;
-
-        // Send magic string for ALiS protocol
-        if matches!(self.config.protocol, StreamProtocol::Alis) {
-            ws_stream
-                .send(Message::Binary(alis::ALIS_MAGIC.to_vec()))
-                .await
-                .context("failed to send ALiS magic")?;
-        }
+        let ws_stream = self.connect(&ws_url).await?;
+        eprintln!("Connected to asciinema server");
 
-        // Subscribe to events
-        let mut events = crate::session::stream(clients_tx).await?;
+        // Subscribe to events, catching up with the screen if the connection
+        // can't keep up
+        let events = crate::session::resync_stream(clients_tx).await?;
+        let events = self.config.redactor.stream(events);
 
         // Signal that we're subscribed and ready to receive events
         if let Some(tx) = ready_tx {
             let _ = tx.send(());
         }
 
-        while let Some(event_result) = events.next().await {
-            match event_result {
-                Ok(event) => {
-                    let messages = self.encode_event(event)?;
-                    for msg in messages {
-                        if let Err(e) = ws_stream.send(msg).await {
-                            eprintln!("failed to send event to server: {}", e);
-                            return Err(e.into());
+        self.stream_events(&ws_url, ws_stream, events).await
+    }
+
+    /// Sends the events to the server, reconnecting to the same stream
+    /// whenever the connection is lost
+    ///
+    /// Events are buffered while disconnected. On reconnect the viewers get
+    /// the screen as it was before the buffered events, then the events.
+    async fn stream_events(
+        &mut self,
+        ws_url: &str,
+        ws_stream: WsStream,
+        events: impl Stream<Item = Event>,
+    ) -> Result<()> {
+        tokio::pin!(events);
+        let mut connection = Some(ws_stream);
+        let mut backlog = Backlog::new(self.config.reconnect.buffer_size);
+        let mut delay = self.config.reconnect.min_delay;
+        let reconnect = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(reconnect);
+
+        loop {
+            tokio::select! {
+                event = events.next() => {
+                    let Some(event) = event else {
+                        break;
+                    };
+
+                    let Some(ws_stream) = &mut connection else {
+                        backlog.push(event, &mut self.screen);
+                        continue;
+                    };
+
+                    match self.send(ws_stream, event.clone()).await {
+                        Ok(()) => self.screen.apply(&event),
+
+                        Err(e) => {
+                            eprintln!("lost connection to asciinema server: {e:#}");
+                            backlog.push(event, &mut self.screen);
+                            connection = None;
+                            delay = self.config.reconnect.min_delay;
+                            reconnect.as_mut().reset(tokio::time::Instant::now() + delay);
                         }
                     }
                 }
-                Err(_) => {
-                    // Lagged behind, continue
-                    continue;
+
+                message = next_message(&mut connection), if connection.is_some() => {
+                    if let Some(Ok(Message::Close(_))) | Some(Err(_)) | None = message {
+                        eprintln!("lost connection to asciinema server");
+                        connection = None;
+                        delay = self.config.reconnect.min_delay;
+                        reconnect.as_mut().reset(tokio::time::Instant::now() + delay);
+                    }
+                }
+
+                _ = &mut reconnect, if connection.is_none() => {
+                    match self.reconnect(ws_url, &mut backlog).await {
+                        Ok(ws_stream) => {
+                            eprintln!("Reconnected to asciinema server");
+                            connection = Some(ws_stream);
+                        }
+
+                        Err(e) => {
+                            delay = (delay * 2).min(self.config.reconnect.max_delay);
+                            eprintln!(
+                                "reconnecting to asciinema server failed: {e:#}, retrying in {delay:?}"
+                            );
+                            reconnect.as_mut().reset(tokio::time::Instant::now() + delay);
+                        }
+                    }
                 }
             }
         }
 
-        // Close WebSocket gracefully
-        ws_stream.close(None).await.ok();
+        match connection {
+            // Tell the viewers the stream ended, then close WebSocket
+            // gracefully
+            Some(mut ws_stream) => {
+                if matches!(self.config.protocol, StreamProtocol::Alis) {
+                    self.event_id += 1;
+                    let rel_time = self.calculate_rel_time_micros();
+                    let eot = alis::encode_eot(self.event_id, rel_time);
+                    ws_stream.send(Message::Binary(eot)).await.ok();
+                }
+
+                ws_stream.close(None).await.ok();
+            }
+
+            None => {
+                eprintln!(
+                    "stream ended while disconnected, {} event(s) weren't sent",
+                    backlog.events.len()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Connects to the stream again, then brings its viewers up to date
+    async fn reconnect(&mut self, ws_url: &str, backlog: &mut Backlog) -> Result<WsStream> {
+        let mut ws_stream = self.connect(ws_url).await?;
+
+        if let Some(init) = self.screen.init() {
+            self.send(&mut ws_stream, init).await?;
+        }
+
+        while let Some(event) = backlog.events.front() {
+            self.send(&mut ws_stream, event.clone()).await?;
+            let event = backlog.pop().unwrap();
+            self.screen.apply(&event);
+        }
+
+        Ok(ws_stream)
+    }
+
+    async fn connect(&self, ws_url: &str) -> Result<WsStream> {
+        let (mut ws_stream, _) =
+            tokio::time::timeout(CONNECT_TIMEOUT, self.connect_websocket(ws_url))
+                .await
+                .context("timed out connecting to WebSocket")??;
+
+        // Send magic string for ALiS protocol
+        if matches!(self.config.protocol, StreamProtocol::Alis) {
+            ws_stream
+                .send(Message::Binary(alis::ALIS_MAGIC.to_vec()))
+                .await
+                .context("failed to send ALiS magic")?;
+        }
+
+        Ok(ws_stream)
+    }
+
+    async fn send(&mut self, ws_stream: &mut WsStream, event: Event) -> Result<()> {
+        for msg in self.encode_event(event)? {
+            ws_stream
+                .send(msg)
+                .await
+                .context("failed to send event to server")?;
+        }
 
         Ok(())
     }
@@ -180,9 +327,7 @@ impl AsciinemaServerStreamer {
         &self,
         url: &str,
     ) -> Result<(
-        tokio_tungstenite::WebSocketStream<
-            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-        >,
+        WsStream,
         tokio_tungstenite::tungstenite::http::Response<Option<Vec<u8>>>,
     )> {
         let subprotocol = match self.config.protocol {
@@ -190,12 +335,16 @@ impl AsciinemaServerStreamer {
             StreamProtocol::AsciicastV3 => "v3.asciicast",
         };
 
-        let request = tokio_tungstenite::tungstenite::http::Request::builder()
-            .uri(url)
-            .header("Sec-WebSocket-Protocol", subprotocol)
-            .body(())
+        // Starting from the URL gets us the handshake headers
+        let mut request = url
+            .into_client_request()
             .context("failed to build WebSocket request")?;
 
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(subprotocol),
+        );
+
         let ws_config = WebSocketConfig {
             max_message_size: Some(64 << 20), // 64 MB
             max_frame_size: Some(16 << 20),   // 16 MB
@@ -220,7 +369,7 @@ impl AsciinemaServerStreamer {
         let mut messages = Vec::new();
 
         match event {
-            Event::Init(_time, cols, rows, _pid, seq, _text) => {
+            Event::Init(_time, cols, rows, _pid, seq, _text, _) => {
                 self.start_time = Instant::now();
                 self.last_event_time = Some(self.start_time);
 
@@ -271,8 +420,16 @@ impl AsciinemaServerStreamer {
                 messages.push(Message::Binary(bytes));
             }
 
-            Event::Snapshot(_, _, _, _) | Event::Input(_, _) => {
-                // Ignore snapshots and input if not capturing
+            Event::Snapshot(_, _, _, _, _)
+            | Event::Input(_, _)
+            | Event::Export(_, _)
+            | Event::Title(_, _)
+            | Event::Cwd(_, _)
+            | Event::Notification(_, _, _)
+            | Event::CommandStarted(_, _)
+            | Event::CommandFinished(_, _)
+            | Event::Clipboard(_, _, _) => {
+                // Ignore snapshots, metadata and input if not capturing
             }
         }
 
@@ -283,7 +440,7 @@ impl AsciinemaServerStreamer {
         let mut messages = Vec::new();
 
         match event {
-            Event::Init(time, cols, rows, _pid, seq, _text) => {
+            Event::Init(time, cols, rows, _pid, seq, _text, _) => {
                 self.start_time = Instant::now();
                 self.last_event_time = Some(self.start_time);
 
@@ -307,7 +464,7 @@ impl AsciinemaServerStreamer {
                         "bg": theme.bg,
                     });
                     if !theme.palette.is_empty() {
-                        theme_obj["palette"] = json!(theme.palette);
+                        theme_obj["palette"] = json!(theme.palette.join(":"));
                     }
                     header["term"]["theme"] = theme_obj;
                 }
@@ -355,7 +512,15 @@ impl AsciinemaServerStreamer {
                 messages.push(Message::Text(event_line));
             }
 
-            Event::Snapshot(_, _, _, _) | Event::Input(_, _) => {
+            Event::Snapshot(_, _, _, _, _)
+            | Event::Input(_, _)
+            | Event::Export(_, _)
+            | Event::Title(_, _)
+            | Event::Cwd(_, _)
+            | Event::Notification(_, _, _)
+            | Event::CommandStarted(_, _)
+            | Event::CommandFinished(_, _)
+            | Event::Clipboard(_, _, _) => {
                 // Ignore
             }
         }
@@ -387,3 +552,371 @@ impl AsciinemaServerStreamer {
         secs
     }
 }
+
+async fn next_message(
+    connection: &mut Option<WsStream>,
+) -> Option<Result<Message, tokio_tungstenite::tungstenite::Error>> {
+    match connection {
+        Some(ws_stream) => ws_stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The screen as the server's viewers last saw it
+#[derive(Default)]
+struct Screen {
+    vt: Option<avt::Vt>,
+    time: f64,
+}
+
+impl Screen {
+    fn apply(&mut self, event: &Event) {
+        match event {
+            Event::Init(time, cols, rows, _pid, seq, _text, _) => {
+                let mut vt = avt::Vt::builder().size(*cols, *rows).build();
+                vt.feed_str(seq);
+                self.vt = Some(vt);
+                self.time = *time;
+            }
+
+            Event::Output(time, data) => {
+                if let Some(vt) = &mut self.vt {
+                    vt.feed_str(data);
+                }
+
+                self.time = *time;
+            }
+
+            Event::Resize(time, cols, rows) => {
+                if let Some(vt) = &mut self.vt {
+                    vt.resize(*cols, *rows);
+                }
+
+                self.time = *time;
+            }
+
+            _ => {}
+        }
+    }
+
+    /// An init event recreating the screen
+    fn init(&self) -> Option<Event> {
+        let vt = self.vt.as_ref()?;
+        let (cols, rows) = vt.size();
+        Some(Event::Init(
+            self.time,
+            cols,
+            rows,
+            0,
+            vt.dump(),
+            String::new(),
+            Metadata::default(),
+        ))
+    }
+}
+
+/// Events waiting to be sent after reconnecting
+///
+/// When it's full, the oldest events are applied to the screen instead, so
+/// they still reach the viewers as part of the init event.
+struct Backlog {
+    events: VecDeque<Event>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Backlog {
+    fn new(max_size: usize) -> Self {
+        Self {
+            events: VecDeque::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn push(&mut self, event: Event, screen: &mut Screen) {
+        self.size += event_size(&event);
+        self.events.push_back(event);
+
+        while self.size > self.max_size {
+            match self.pop() {
+                Some(event) => screen.apply(&event),
+                None => break,
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Event> {
+        let event = self.events.pop_front()?;
+        self.size -= event_size(&event);
+
+        Some(event)
+    }
+}
+
+fn event_size(event: &Event) -> usize {
+    match event {
+        Event::Init(_, _, _, _, seq, text, _) => seq.len() + text.len(),
+        Event::Output(_, data) | Event::Input(_, data) | Event::Marker(_, data) => data.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+    use crate::streaming::alis::AlisEvent;
+    use axum::extract::ws::{self, WebSocketUpgrade};
+    use axum::extract::State;
+    use axum::http::header;
+    use axum::response::IntoResponse;
+    use axum::routing::{get, post};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Stand-in for an asciinema server, handing each producer connection
+    /// to the test, which closes it by dropping the receiver
+    #[derive(Clone)]
+    struct Server {
+        base_url: String,
+        streams_created: Arc<AtomicUsize>,
+        connections_tx: mpsc::UnboundedSender<mpsc::UnboundedReceiver<Vec<u8>>>,
+    }
+
+    async fn create_stream(State(server): State<Server>) -> impl IntoResponse {
+        let id = server.streams_created.fetch_add(1, Ordering::SeqCst);
+        let ws_url = server.base_url.replace("http://", "ws://");
+        let body = json!({ "ws_producer_url": format!("{ws_url}/ws/producer/{id}") });
+
+        (
+            [(header::CONTENT_TYPE, "application/json")],
+            body.to_string(),
+        )
+    }
+
+    async fn producer(State(server): State<Server>, ws: WebSocketUpgrade) -> impl IntoResponse {
+        ws.protocols(["v1.alis"])
+            .on_upgrade(move |mut socket| async move {
+                let (messages_tx, messages_rx) = mpsc::unbounded_channel();
+                let _ = server.connections_tx.send(messages_rx);
+
+                loop {
+                    tokio::select! {
+                        message = socket.recv() => match message {
+                            Some(Ok(ws::Message::Binary(data))) => {
+                                let _ = messages_tx.send(data);
+                            }
+
+                            Some(Ok(_)) => {}
+                            _ => break,
+                        },
+
+                        _ = messages_tx.closed() => {
+                            let _ = socket.send(ws::Message::Close(None)).await;
+                            break;
+                        }
+                    }
+                }
+            })
+    }
+
+    async fn start_server() -> (
+        Server,
+        mpsc::UnboundedReceiver<mpsc::UnboundedReceiver<Vec<u8>>>,
+    ) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (connections_tx, connections_rx) = mpsc::unbounded_channel();
+
+        let server = Server {
+            base_url: format!("http://{}", listener.local_addr().unwrap()),
+            streams_created: Arc::new(AtomicUsize::new(0)),
+            connections_tx,
+        };
+
+        let app = axum::Router::new()
+            .route("/api/v1/streams", post(create_stream))
+            .route("/ws/producer/:id", get(producer))
+            .with_state(server.clone());
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (server, connections_rx)
+    }
+
+    fn config(server_url: String) -> StreamerConfig {
+        StreamerConfig {
+            server_url,
+            install_id: Some("test".to_string()),
+            install_id_path: None,
+            title: None,
+            visibility: None,
+            protocol: StreamProtocol::Alis,
+            capture_input: false,
+            theme: None,
+            term_type: None,
+            redactor: Redactor::default(),
+            reconnect: ReconnectConfig {
+                min_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(100),
+                buffer_size: 1024,
+            },
+        }
+    }
+
+    /// Returns the type and the string payload of an ALiS init or output
+    /// message
+    fn decode(message: &[u8]) -> (u8, String) {
+        fn skip_leb128(bytes: &[u8], pos: &mut usize) {
+            while bytes[*pos] & 0x80 != 0 {
+                *pos += 1;
+            }
+
+            *pos += 1;
+        }
+
+        let mut pos = 1;
+
+        let fields = match message[0] {
+            // last id, time, cols, rows and the theme format (none)
+            0x01 => 5,
+            // id and time
+            _ => 2,
+        };
+
+        for _ in 0..fields {
+            skip_leb128(message, &mut pos);
+        }
+
+        skip_leb128(message, &mut pos);
+
+        (
+            message[0],
+            String::from_utf8(message[pos..].to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let (server, mut connections_rx) = start_server().await;
+        let mut streamer = AsciinemaServerStreamer::new(config(server.base_url.clone()));
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let mut session = Session::new(20, 2, 0);
+
+        let handle = tokio::spawn(async move { streamer.run(&clients_tx, None).await });
+
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        let mut first = connections_rx.recv().await.unwrap();
+
+        assert_eq!(first.recv().await.unwrap(), alis::ALIS_MAGIC);
+        assert_eq!(decode(&first.recv().await.unwrap()).0, 0x01);
+
+        session.output("one ".to_string());
+        assert_eq!(
+            decode(&first.recv().await.unwrap()),
+            (0x6f, "one ".to_string())
+        );
+
+        // The server drops the connection, and the output keeps coming
+        drop(first);
+        session.output("two".to_string());
+
+        let mut second = connections_rx.recv().await.unwrap();
+        assert_eq!(second.recv().await.unwrap(), alis::ALIS_MAGIC);
+
+        // The viewers get a new init with the screen, then whatever the
+        // init doesn't have yet
+        let (kind, seq) = decode(&second.recv().await.unwrap());
+        assert_eq!(kind, 0x01);
+
+        let mut vt = avt::Vt::new(20, 2);
+        vt.feed_str(&seq);
+
+        while vt.view()[0].text().trim_end() != "one two" {
+            let (kind, data) = decode(&second.recv().await.unwrap());
+            assert_eq!(kind, 0x6f);
+            vt.feed_str(&data);
+        }
+
+        drop(session);
+        handle.await.unwrap().unwrap();
+
+        // The same stream is used throughout
+        assert_eq!(server.streams_created.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_restart_and_eot() {
+        let (server, mut connections_rx) = start_server().await;
+        let mut streamer = AsciinemaServerStreamer::new(config(server.base_url.clone()));
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let mut session = Session::new(20, 2, 0);
+
+        let handle = tokio::spawn(async move { streamer.run(&clients_tx, None).await });
+
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        let mut connection = connections_rx.recv().await.unwrap();
+
+        assert_eq!(connection.recv().await.unwrap(), alis::ALIS_MAGIC);
+        assert_eq!(decode(&connection.recv().await.unwrap()).0, 0x01);
+
+        session.output("one".to_string());
+        assert_eq!(decode(&connection.recv().await.unwrap()).0, 0x6f);
+
+        // A restarted session gets a new init, continuing the event ids
+        session.restart(30, 3, "two".to_string());
+        let init = connection.recv().await.unwrap();
+        let Ok(AlisEvent::Init { init_data, .. }) = alis::decode_event(&init) else {
+            panic!("expected init");
+        };
+
+        assert_eq!(
+            init,
+            alis::encode_init(1, 0, 30, 3, None, &init_data).unwrap()
+        );
+
+        // Ending the session ends the stream
+        drop(session);
+        let eot = connection.recv().await.unwrap();
+        let Ok(AlisEvent::Eot { rel_time, .. }) = alis::decode_event(&eot) else {
+            panic!("expected EOT");
+        };
+
+        assert_eq!(eot, alis::encode_eot(2, rel_time));
+        assert_eq!(connection.recv().await, None);
+
+        handle.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_backlog_overflow() {
+        let mut screen = Screen::default();
+        let mut backlog = Backlog::new(4);
+
+        screen.apply(&Event::Init(
+            0.0,
+            10,
+            1,
+            0,
+            String::new(),
+            String::new(),
+            Metadata::default(),
+        ));
+        backlog.push(Event::Output(1.0, "ab".to_string()), &mut screen);
+        backlog.push(Event::Output(2.0, "cd".to_string()), &mut screen);
+        backlog.push(Event::Output(3.0, "ef".to_string()), &mut screen);
+
+        // The oldest output went to the screen to make room
+        assert_eq!(backlog.events.len(), 2);
+
+        let Some(Event::Init(time, 10, 1, _, seq, _, _)) = screen.init() else {
+            panic!("expected init");
+        };
+
+        let mut vt = avt::Vt::new(10, 1);
+        vt.feed_str(&seq);
+
+        assert_eq!(time, 1.0);
+        assert_eq!(vt.view()[0].text(), "ab        ");
+    }
+}
@@ -1,3 +1,5 @@
 pub mod alis;
-pub mod asciinema_server;
+pub mod alis_client;
 pub mod alis_local;
+pub mod asciinema_server;
+pub mod relay;
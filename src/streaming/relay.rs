@@ -0,0 +1,669 @@
+/// ALiS relay: a small asciinema-compatible streaming server
+///
+/// Producers create a stream with `POST /api/v1/streams` and send ALiS v1 to
+/// the returned producer URL, like they would to an asciinema server. Any
+/// number of viewers can then watch the stream at `/ws/alis-v1/<id>`.
+use crate::api::auth::{self, AuthConfig, Scope};
+use crate::streaming::alis::{self, AlisEvent};
+use anyhow::Result;
+use axum::{
+    extract::{ws, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use futures_util::{sink, SinkExt, StreamExt};
+use serde_json::json;
+use std::collections::HashMap;
+use std::future::{Future, IntoFuture};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// How long a stream is kept without a producer, for it to reconnect and for
+/// late viewers to learn how it ended
+const ABANDONED_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+struct Relay {
+    addr: SocketAddr,
+    streams: Arc<Mutex<HashMap<String, Arc<Mutex<LiveStream>>>>>,
+    abandoned_after: Duration,
+}
+
+/// State of one stream, enough to bring a viewer joining late up to date
+struct LiveStream {
+    /// Secret part of the producer URL, given to the stream's creator only
+    producer_token: String,
+    /// Whether a producer is connected
+    producing: bool,
+    /// Number of producer connections so far
+    connections: u64,
+    /// Terminal state, `None` until the producer sends its init event
+    vt: Option<avt::Vt>,
+    theme: Option<alis::Theme>,
+    last_id: u64,
+    /// Stream time in microseconds
+    time: u64,
+    /// Whether the producer ended the stream (with EOT)
+    ended: bool,
+    /// Messages from the producer, passed on as they are
+    messages_tx: broadcast::Sender<Vec<u8>>,
+}
+
+/// Starts the relay, with streams created by producers with the control
+/// scope and watched by viewers with the read-only one
+///
+/// Producers authenticate with their install ID, which must be a token with
+/// the control scope.
+pub async fn start(
+    listener: TcpListener,
+    auth: AuthConfig,
+) -> Result<impl Future<Output = io::Result<()>>> {
+    let addr = listener.local_addr()?;
+    auth.check_addr(addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    eprintln!("relay listening on {addr}");
+    auth.print_tokens();
+
+    let relay = Relay {
+        addr,
+        streams: Arc::default(),
+        abandoned_after: ABANDONED_AFTER,
+    };
+
+    Ok(axum::serve(listener, router(relay, auth)).into_future())
+}
+
+fn router(relay: Relay, auth: AuthConfig) -> Router<()> {
+    let auth = Arc::new(auth);
+
+    let create = Router::new()
+        .route("/api/v1/streams", post(create_stream_handler))
+        .route_layer(middleware::from_fn_with_state(
+            auth.guard(Scope::Control),
+            auth::require,
+        ));
+
+    let watch = Router::new()
+        .route("/ws/alis-v1/:id", get(viewer_handler))
+        .route_layer(middleware::from_fn_with_state(
+            auth.guard(Scope::Read),
+            auth::require,
+        ));
+
+    create
+        .merge(watch)
+        .route("/ws/producer/:id/:token", get(producer_handler))
+        .with_state(relay)
+}
+
+/// Stream creation handler
+///
+/// Responds like the asciinema server does, with a producer URL only the
+/// creator knows. The stream is removed if no producer connects to it.
+async fn create_stream_handler(State(relay): State<Relay>, headers: HeaderMap) -> Response {
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let stream = LiveStream::new();
+    let producer_token = stream.producer_token.clone();
+
+    relay
+        .streams
+        .lock()
+        .unwrap()
+        .insert(id.clone(), Arc::new(Mutex::new(stream)));
+
+    relay.remove_if_abandoned(id.clone(), 0);
+
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(String::from)
+        .unwrap_or_else(|| relay.addr.to_string());
+
+    eprintln!("stream {id} created");
+
+    let body = json!({
+        "id": id,
+        "url": format!("ws://{host}/ws/alis-v1/{id}"),
+        "ws_producer_url": format!("ws://{host}/ws/producer/{id}/{producer_token}"),
+    });
+
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+/// Producer handler
+///
+/// A stream has one producer at a time, which can reconnect, e.g. after a
+/// network failure, any number of times before the stream is abandoned.
+async fn producer_handler(
+    ws: ws::WebSocketUpgrade,
+    Path((id, token)): Path<(String, String)>,
+    State(relay): State<Relay>,
+) -> Response {
+    let Some(stream) = relay.stream(&id) else {
+        return (StatusCode::NOT_FOUND, "404").into_response();
+    };
+
+    let producer = {
+        let mut live_stream = stream.lock().unwrap();
+
+        if !auth::constant_time_eq(token.as_bytes(), live_stream.producer_token.as_bytes()) {
+            return (StatusCode::NOT_FOUND, "404").into_response();
+        }
+
+        if live_stream.producing {
+            return (StatusCode::CONFLICT, "stream already has a producer").into_response();
+        }
+
+        live_stream.producing = true;
+        live_stream.connections += 1;
+
+        Producer {
+            relay: relay.clone(),
+            id: id.clone(),
+            stream: stream.clone(),
+        }
+    };
+
+    // The producer is dropped when the connection ends or fails to upgrade
+    ws.protocols(["v1.alis"])
+        .on_upgrade(move |socket| async move {
+            handle_producer_socket(socket, &producer.stream).await;
+            eprintln!("stream {id} producer disconnected");
+        })
+        .into_response()
+}
+
+/// Connected producer of a stream
+struct Producer {
+    relay: Relay,
+    id: String,
+    stream: Arc<Mutex<LiveStream>>,
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        let connections = {
+            // Runs while unwinding too, so the stream may be poisoned
+            let mut stream = self.stream.lock().unwrap_or_else(PoisonError::into_inner);
+            stream.producing = false;
+
+            stream.connections
+        };
+
+        self.relay.remove_if_abandoned(self.id.clone(), connections);
+    }
+}
+
+async fn handle_producer_socket(mut socket: ws::WebSocket, stream: &Mutex<LiveStream>) {
+    match socket.recv().await {
+        Some(Ok(ws::Message::Binary(magic))) if magic == alis::ALIS_MAGIC => {}
+        _ => return,
+    }
+
+    while let Some(Ok(message)) = socket.recv().await {
+        let ws::Message::Binary(data) = message else {
+            continue;
+        };
+
+        match alis::decode_event(&data) {
+            Ok(event) => stream.lock().unwrap().apply(&event, data),
+            Err(e) => eprintln!("skipping invalid ALiS message: {e:#}"),
+        }
+    }
+}
+
+/// Viewer handler
+///
+/// Viewers joining mid-stream start with an init event with the current
+/// screen, and so do viewers which can't keep up.
+async fn viewer_handler(
+    ws: ws::WebSocketUpgrade,
+    Path(id): Path<String>,
+    State(relay): State<Relay>,
+) -> Response {
+    let Some(stream) = relay.stream(&id) else {
+        return (StatusCode::NOT_FOUND, "404").into_response();
+    };
+
+    ws.protocols(["v1.alis"])
+        .on_upgrade(move |socket| async move {
+            let _ = handle_viewer_socket(socket, &stream).await;
+        })
+        .into_response()
+}
+
+async fn handle_viewer_socket(socket: ws::WebSocket, stream: &Mutex<LiveStream>) -> Result<()> {
+    let (mut sink, incoming) = socket.split();
+    let drainer = tokio::spawn(incoming.map(Ok).forward(sink::drain()));
+
+    let result = async {
+        let (mut messages_rx, mut init) = stream.lock().unwrap().subscribe();
+
+        sink.send(ws::Message::Binary(alis::ALIS_MAGIC.to_vec()))
+            .await?;
+
+        loop {
+            for message in init {
+                sink.send(ws::Message::Binary(message)).await?;
+            }
+
+            loop {
+                match messages_rx.recv().await {
+                    Ok(message) => sink.send(ws::Message::Binary(message)).await?,
+                    Err(broadcast::error::RecvError::Lagged(_)) => break,
+                    Err(broadcast::error::RecvError::Closed) => {
+                        sink.send(ws::Message::Close(None)).await?;
+
+                        return Ok(());
+                    }
+                }
+            }
+
+            // Start over with the current screen
+            (messages_rx, init) = stream.lock().unwrap().subscribe();
+        }
+    }
+    .await;
+
+    drainer.abort();
+    result
+}
+
+impl Relay {
+    fn stream(&self, id: &str) -> Option<Arc<Mutex<LiveStream>>> {
+        self.streams.lock().unwrap().get(id).cloned()
+    }
+
+    /// Removes the stream later unless a producer connected to it meanwhile,
+    /// disconnecting its viewers
+    fn remove_if_abandoned(&self, id: String, connections: u64) {
+        let relay = self.clone();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(relay.abandoned_after).await;
+            let mut streams = relay.streams.lock().unwrap();
+
+            let Some(stream) = streams.get(&id) else {
+                return;
+            };
+
+            let mut stream = stream.lock().unwrap();
+
+            if stream.producing || stream.connections != connections {
+                return;
+            }
+
+            stream.close();
+            drop(stream);
+            streams.remove(&id);
+            eprintln!("stream {id} removed");
+        });
+    }
+}
+
+impl LiveStream {
+    fn new() -> Self {
+        let (messages_tx, _) = broadcast::channel(1024);
+
+        Self {
+            producer_token: auth::generate_token(),
+            producing: false,
+            connections: 0,
+            vt: None,
+            theme: None,
+            last_id: 0,
+            time: 0,
+            ended: false,
+            messages_tx,
+        }
+    }
+
+    /// Updates the state with the producer's event, then passes it on to
+    /// the viewers
+    fn apply(&mut self, event: &AlisEvent, message: Vec<u8>) {
+        if let AlisEvent::Init { cols, rows, .. } | AlisEvent::Resize { cols, rows, .. } = event {
+            if *cols == 0 || *rows == 0 {
+                eprintln!("skipping ALiS event with invalid size {cols}x{rows}");
+                return;
+            }
+        }
+
+        let (id, rel_time) = match event {
+            AlisEvent::Init {
+                last_id,
+                time,
+                cols,
+                rows,
+                theme,
+                init_data,
+            } => {
+                let mut vt = avt::Vt::new(*cols as usize, *rows as usize);
+                vt.feed_str(init_data);
+                self.vt = Some(vt);
+                self.theme = theme.clone();
+                self.last_id = *last_id;
+                self.time = *time;
+                self.ended = false;
+                let _ = self.messages_tx.send(message);
+
+                return;
+            }
+
+            // Without an init, viewers couldn't make sense of the event
+            _ if self.vt.is_none() => return,
+
+            AlisEvent::Output { id, rel_time, data } => {
+                self.vt.as_mut().unwrap().feed_str(data);
+                (id, rel_time)
+            }
+
+            AlisEvent::Resize {
+                id,
+                rel_time,
+                cols,
+                rows,
+            } => {
+                self.vt
+                    .as_mut()
+                    .unwrap()
+                    .resize(*cols as usize, *rows as usize);
+
+                (id, rel_time)
+            }
+
+            AlisEvent::Eot { id, rel_time } => {
+                self.ended = true;
+                (id, rel_time)
+            }
+
+            AlisEvent::Input { id, rel_time, .. }
+            | AlisEvent::Marker { id, rel_time, .. }
+            | AlisEvent::Exit { id, rel_time, .. } => (id, rel_time),
+        };
+
+        self.last_id = *id;
+        self.time += rel_time;
+        let _ = self.messages_tx.send(message);
+    }
+
+    /// Disconnects the viewers, by dropping the sender their receivers are
+    /// subscribed to
+    fn close(&mut self) {
+        (self.messages_tx, _) = broadcast::channel(1);
+    }
+
+    /// Returns a receiver of the following messages and the ones bringing a
+    /// viewer up to date
+    fn subscribe(&self) -> (broadcast::Receiver<Vec<u8>>, Vec<Vec<u8>>) {
+        let mut messages = Vec::new();
+
+        if let Some(vt) = &self.vt {
+            let (cols, rows) = vt.size();
+
+            let init = alis::encode_init(
+                self.last_id,
+                self.time,
+                cols as u16,
+                rows as u16,
+                self.theme.as_ref(),
+                &vt.dump(),
+            );
+
+            // The theme was decoded from a valid message, so it encodes fine
+            messages.push(init.unwrap());
+
+            if self.ended {
+                messages.push(alis::encode_eot(self.last_id + 1, 0));
+            }
+        }
+
+        (self.messages_tx.subscribe(), messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redact::Redactor;
+    use crate::session::Session;
+    use crate::streaming::alis_client;
+    use crate::streaming::asciinema_server::{
+        AsciinemaServerStreamer, ReconnectConfig, StreamProtocol, StreamerConfig,
+    };
+    use tokio::sync::{mpsc, oneshot};
+
+    /// Starts a relay taking the `test` install ID from producers, and the
+    /// `viewer` token from viewers
+    async fn start_relay(abandoned_after: Duration) -> Relay {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut auth = AuthConfig::default();
+        auth.add_token("test".to_string(), Scope::Control);
+        auth.add_token("viewer".to_string(), Scope::Read);
+
+        let relay = Relay {
+            addr: listener.local_addr().unwrap(),
+            streams: Arc::default(),
+            abandoned_after,
+        };
+
+        let app = router(relay.clone(), auth);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        relay
+    }
+
+    fn streamer(addr: SocketAddr) -> AsciinemaServerStreamer {
+        AsciinemaServerStreamer::new(StreamerConfig {
+            server_url: format!("http://{addr}"),
+            install_id: Some("test".to_string()),
+            install_id_path: None,
+            title: None,
+            visibility: None,
+            protocol: StreamProtocol::Alis,
+            capture_input: false,
+            theme: None,
+            term_type: None,
+            redactor: Redactor::default(),
+            reconnect: ReconnectConfig::default(),
+        })
+    }
+
+    /// Returns the screen's first line after the viewer's init event
+    fn init_line(event: Option<Result<AlisEvent>>) -> String {
+        let Some(Ok(AlisEvent::Init {
+            cols,
+            rows,
+            init_data,
+            ..
+        })) = event
+        else {
+            panic!("expected init, got {event:?}");
+        };
+
+        let mut vt = avt::Vt::new(cols as usize, rows as usize);
+        vt.feed_str(&init_data);
+
+        vt.view()[0].text().trim_end().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let relay = start_relay(ABANDONED_AFTER).await;
+        let mut streamer = streamer(relay.addr);
+        let (clients_tx, mut clients_rx) = mpsc::channel(1);
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let mut session = Session::new(20, 2, 0);
+        session.output("hello".to_string());
+
+        let handle = tokio::spawn(async move { streamer.run(&clients_tx, Some(ready_tx)).await });
+        clients_rx.recv().await.unwrap().accept(&mut session);
+        ready_rx.await.unwrap();
+
+        let id = relay.streams.lock().unwrap().keys().next().unwrap().clone();
+        let stream = relay.stream(&id).unwrap();
+        let url = format!("ws://{}/ws/alis-v1/{id}?token=viewer", relay.addr);
+
+        // Wait for the producer's init to reach the relay
+        while stream.lock().unwrap().vt.is_none() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // Viewers joining late start with the screen as it is
+        let mut first = Box::pin(alis_client::connect(&url).await.unwrap());
+        assert_eq!(init_line(first.next().await), "hello");
+
+        session.output(" world".to_string());
+
+        assert!(matches!(
+            first.next().await,
+            Some(Ok(AlisEvent::Output { data, .. })) if data == " world"
+        ));
+
+        let mut second = Box::pin(alis_client::connect(&url).await.unwrap());
+        assert_eq!(init_line(second.next().await), "hello world");
+
+        // The end of the stream reaches every viewer
+        drop(session);
+        handle.await.unwrap().unwrap();
+
+        for viewer in [&mut first, &mut second] {
+            assert!(matches!(
+                viewer.next().await,
+                Some(Ok(AlisEvent::Eot { .. }))
+            ));
+        }
+
+        // And viewers joining after it learn that it ended
+        let mut third = Box::pin(alis_client::connect(&url).await.unwrap());
+        assert_eq!(init_line(third.next().await), "hello world");
+        assert!(matches!(
+            third.next().await,
+            Some(Ok(AlisEvent::Eot { .. }))
+        ));
+
+        let unknown = format!("ws://{}/ws/alis-v1/nope?token=viewer", relay.addr);
+        assert!(alis_client::connect(&unknown).await.is_err());
+
+        let unauthorized = format!("ws://{}/ws/alis-v1/{id}", relay.addr);
+        assert!(alis_client::connect(&unauthorized).await.is_err());
+    }
+
+    /// Waits for the relay to remove the stream
+    async fn removed(relay: &Relay, id: &str) {
+        let removal = async {
+            while relay.stream(id).is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), removal)
+            .await
+            .expect("stream should be removed");
+    }
+
+    #[tokio::test]
+    async fn test_producers() {
+        let relay = start_relay(Duration::from_millis(100)).await;
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/api/v1/streams", relay.addr);
+
+        let create = |install_id: Option<&'static str>| {
+            let mut request = client.post(&url);
+
+            if let Some(install_id) = install_id {
+                request = request.basic_auth("", Some(install_id));
+            }
+
+            request.send()
+        };
+
+        // Streams are created with an install ID granting control
+        let response = create(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = create(Some("viewer")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = create(Some("test")).await.unwrap();
+        let body: serde_json::Value = response.json().await.unwrap();
+        let id = body["id"].as_str().unwrap();
+        let producer_url = body["ws_producer_url"].as_str().unwrap();
+
+        // The producer URL only works with its token, for one producer at a
+        // time
+        let (base, _) = producer_url.rsplit_once('/').unwrap();
+        assert!(tokio_tungstenite::connect_async(format!("{base}/nope"))
+            .await
+            .is_err());
+
+        let (producer, _) = tokio_tungstenite::connect_async(producer_url)
+            .await
+            .unwrap();
+
+        assert!(tokio_tungstenite::connect_async(producer_url)
+            .await
+            .is_err());
+
+        let viewer_url = format!("ws://{}/ws/alis-v1/{id}?token=viewer", relay.addr);
+        let mut viewer = Box::pin(alis_client::connect(&viewer_url).await.unwrap());
+
+        // Still there while the producer is connected
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(relay.stream(id).is_some());
+
+        // Removed when it doesn't come back, closing the viewers
+        drop(producer);
+        removed(&relay, id).await;
+        assert!(viewer.next().await.is_none());
+
+        // And so are streams no producer connects to
+        let response = create(Some("test")).await.unwrap();
+        let body: serde_json::Value = response.json().await.unwrap();
+        removed(&relay, body["id"].as_str().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_non_loopback_without_auth() {
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+
+        assert!(start(listener, AuthConfig::default()).await.is_err());
+    }
+
+    #[test]
+    fn test_empty_size() {
+        let mut stream = LiveStream::new();
+        let init = |cols, rows| AlisEvent::Init {
+            last_id: 0,
+            time: 0,
+            cols,
+            rows,
+            theme: None,
+            init_data: String::new(),
+        };
+
+        stream.apply(&init(0, 0), Vec::new());
+        assert!(stream.vt.is_none());
+
+        stream.apply(&init(10, 2), Vec::new());
+
+        let resize = AlisEvent::Resize {
+            id: 1,
+            rel_time: 0,
+            cols: 0,
+            rows: 2,
+        };
+
+        stream.apply(&resize, Vec::new());
+        assert_eq!(stream.vt.as_ref().unwrap().size(), (10, 2));
+    }
+}
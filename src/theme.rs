@@ -0,0 +1,260 @@
+/// Terminal color themes: built-in ones, theme files and command line overrides
+///
+/// Built-in themes have the colors of the asciinema player's themes of the
+/// same names.
+use crate::recording::asciicast_v3::ThemeConfig;
+use crate::render::Palette;
+use crate::streaming::alis;
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::path::Path;
+
+/// Name, foreground, background and palette of the built-in themes
+const THEMES: &[(&str, &str, &str, &str)] = &[
+    (
+        "asciinema",
+        "#cccccc",
+        "#121314",
+        "#000000:#dd3c69:#4ebf22:#ddaf3c:#26b0d7:#b954e1:#54e1b9:#d9d9d9:\
+         #4d4d4d:#dd3c69:#4ebf22:#ddaf3c:#26b0d7:#b954e1:#54e1b9:#ffffff",
+    ),
+    (
+        "dracula",
+        "#f8f8f2",
+        "#282a36",
+        "#21222c:#ff5555:#50fa7b:#f1fa8c:#bd93f9:#ff79c6:#8be9fd:#f8f8f2:\
+         #6272a4:#ff6e6e:#69ff94:#ffffa5:#d6acff:#ff92df:#a4ffff:#ffffff",
+    ),
+    (
+        "monokai",
+        "#f8f8f2",
+        "#272822",
+        "#272822:#f92672:#a6e22e:#f4bf75:#66d9ef:#ae81ff:#a1efe4:#f8f8f2:\
+         #75715e:#f92672:#a6e22e:#f4bf75:#66d9ef:#ae81ff:#a1efe4:#f9f8f5",
+    ),
+    (
+        "nord",
+        "#eceff4",
+        "#2e3440",
+        "#3b4252:#bf616a:#a3be8c:#ebcb8b:#81a1c1:#b48ead:#88c0d0:#eceff4",
+    ),
+    (
+        "solarized-dark",
+        "#839496",
+        "#002b36",
+        "#073642:#dc322f:#859900:#b58900:#268bd2:#d33682:#2aa198:#eee8d5:\
+         #002b36:#cb4b16:#586e75:#657b83:#839496:#6c71c4:#93a1a1:#fdf6e3",
+    ),
+    (
+        "solarized-light",
+        "#657b83",
+        "#fdf6e3",
+        "#073642:#dc322f:#859900:#b58900:#268bd2:#d33682:#2aa198:#eee8d5:\
+         #002b36:#cb4b16:#586e75:#657c83:#839496:#6c71c4:#93a1a1:#fdf6e3",
+    ),
+    (
+        "tango",
+        "#cccccc",
+        "#121314",
+        "#000000:#cc0000:#4e9a06:#c4a000:#3465a4:#75507b:#06989a:#d3d7cf:\
+         #555753:#ef2929:#8ae234:#fce94f:#729fcf:#ad7fa8:#34e2e2:#eeeeec",
+    ),
+];
+
+/// Returns the built-in theme with the given name
+pub fn builtin(name: &str) -> Option<ThemeConfig> {
+    let name = if name == "solarized" {
+        "solarized-dark"
+    } else {
+        name
+    };
+
+    THEMES
+        .iter()
+        .find(|(n, _, _, _)| *n == name)
+        .map(|(_, fg, bg, palette)| ThemeConfig {
+            fg: fg.to_string(),
+            bg: bg.to_string(),
+            palette: Some(palette.to_string()),
+        })
+}
+
+/// Loads a theme file
+///
+/// The file is a JSON object like an asciicast header's theme: `fg`, `bg`
+/// and an optional `palette` of 8 or 16 colors, either colon-separated or
+/// as an array.
+pub fn load(path: &Path) -> Result<ThemeConfig> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("can't read theme file {}", path.display()))?;
+
+    parse(&content).with_context(|| format!("invalid theme file {}", path.display()))
+}
+
+fn parse(content: &str) -> Result<ThemeConfig> {
+    let value: Value = serde_json::from_str(content)?;
+
+    let (Some(fg), Some(bg)) = (value["fg"].as_str(), value["bg"].as_str()) else {
+        bail!("both fg and bg colors are required");
+    };
+
+    let palette = match &value["palette"] {
+        Value::Null => None,
+        Value::String(palette) => Some(palette.clone()),
+
+        Value::Array(colors) => {
+            let colors = colors
+                .iter()
+                .map(|color| color.as_str().context("palette colors must be strings"))
+                .collect::<Result<Vec<_>>>()?;
+
+            Some(colors.join(":"))
+        }
+
+        _ => bail!("palette must be a string or an array"),
+    };
+
+    let theme = ThemeConfig {
+        fg: fg.to_string(),
+        bg: bg.to_string(),
+        palette,
+    };
+
+    Palette::from_theme(Some(&theme))?;
+
+    Ok(theme)
+}
+
+/// Resolves the theme options: a built-in theme name or a theme file,
+/// overridden by individual colors
+///
+/// Colors given without a base theme override the default (asciinema)
+/// theme.
+pub fn from_options(
+    name_or_path: Option<&str>,
+    fg: Option<&str>,
+    bg: Option<&str>,
+    palette: Option<&str>,
+) -> Result<Option<ThemeConfig>> {
+    let mut theme = match name_or_path {
+        Some(name_or_path) => match builtin(name_or_path) {
+            Some(theme) => theme,
+
+            None if Path::new(name_or_path).exists() => load(Path::new(name_or_path))?,
+
+            None => {
+                let names: Vec<_> = THEMES.iter().map(|(name, _, _, _)| *name).collect();
+
+                bail!(
+                    "unknown theme: {name_or_path} (use {} or a path to a theme file)",
+                    names.join(", ")
+                );
+            }
+        },
+
+        None if fg.is_none() && bg.is_none() && palette.is_none() => return Ok(None),
+        None => builtin("asciinema").unwrap(),
+    };
+
+    if let Some(fg) = fg {
+        theme.fg = fg.to_string();
+    }
+
+    if let Some(bg) = bg {
+        theme.bg = bg.to_string();
+    }
+
+    if let Some(palette) = palette {
+        theme.palette = Some(palette.to_string());
+    }
+
+    Palette::from_theme(Some(&theme))?;
+
+    Ok(Some(theme))
+}
+
+impl From<&ThemeConfig> for alis::Theme {
+    /// Themes without a palette get the default one, since ALiS can't carry
+    /// just the foreground and background
+    fn from(theme: &ThemeConfig) -> Self {
+        let palette = theme
+            .palette
+            .clone()
+            .or_else(|| builtin("asciinema").and_then(|theme| theme.palette))
+            .unwrap_or_default();
+
+        alis::Theme {
+            fg: theme.fg.clone(),
+            bg: theme.bg.clone(),
+            palette: palette.split(':').map(String::from).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_themes() {
+        for (name, _, _, _) in THEMES {
+            let theme = builtin(name).unwrap();
+            assert!(Palette::from_theme(Some(&theme)).is_ok(), "{name}");
+        }
+
+        assert_eq!(builtin("solarized").unwrap().bg, "#002b36");
+        assert!(builtin("solarised").is_none());
+    }
+
+    #[test]
+    fn test_parse_theme_file() {
+        let theme = parse(r##"{"fg": "#ffffff", "bg": "#000000"}"##).unwrap();
+        assert_eq!(theme.palette, None);
+
+        let colors: Vec<_> = (0..8).map(|i| format!("\"#00000{i}\"")).collect();
+        let json = format!(
+            r##"{{"fg": "#ffffff", "bg": "#000000", "palette": [{}]}}"##,
+            colors.join(",")
+        );
+        let theme = parse(&json).unwrap();
+        assert_eq!(theme.palette.unwrap().split(':').nth(7), Some("#000007"));
+
+        assert!(parse(r##"{"fg": "#ffffff"}"##).is_err());
+        assert!(parse(r##"{"fg": "#ffffff", "bg": "#000000", "palette": "#111111"}"##).is_err());
+    }
+
+    #[test]
+    fn test_options() {
+        assert!(from_options(None, None, None, None).unwrap().is_none());
+
+        let theme = from_options(Some("dracula"), Some("#ffffff"), None, None)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(theme.fg, "#ffffff");
+        assert_eq!(theme.bg, "#282a36");
+
+        let theme = from_options(None, None, Some("#000000"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(theme.fg, "#cccccc");
+        assert_eq!(theme.bg, "#000000");
+
+        assert!(from_options(Some("no-such-theme"), None, None, None).is_err());
+        assert!(from_options(None, Some("white"), None, None).is_err());
+    }
+
+    #[test]
+    fn test_alis_theme() {
+        let theme = ThemeConfig {
+            fg: "#ffffff".to_string(),
+            bg: "#000000".to_string(),
+            palette: None,
+        };
+
+        let theme = alis::Theme::from(&theme);
+
+        assert_eq!(theme.palette.len(), 16);
+        assert_eq!(theme.palette[1], "#dd3c69");
+    }
+}
@@ -0,0 +1,91 @@
+/// Local terminal handling
+use anyhow::Result;
+use nix::libc;
+use nix::sys::termios::{self, LocalFlags, SetArg, SpecialCharacterIndices, Termios};
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+
+/// Puts a terminal in raw mode until dropped
+pub struct RawMode {
+    fd: OwnedFd,
+    termios: Termios,
+}
+
+impl RawMode {
+    /// Disables line editing, echo, and keyboard signals unless `signals` is
+    /// set, in which case Ctrl-C and Ctrl-Z still work, but Ctrl-\ is left to
+    /// ht
+    pub fn enable(fd: BorrowedFd, signals: bool) -> Result<Self> {
+        let raw_mode = RawMode {
+            fd: fd.try_clone_to_owned()?,
+            termios: termios::tcgetattr(fd)?,
+        };
+
+        raw_mode.set_signals(signals)?;
+
+        Ok(raw_mode)
+    }
+
+    pub fn set_signals(&self, signals: bool) -> Result<()> {
+        let mut raw = self.termios.clone();
+        termios::cfmakeraw(&mut raw);
+        raw.local_flags.set(LocalFlags::ISIG, signals);
+        raw.control_chars[SpecialCharacterIndices::VQUIT as usize] = libc::_POSIX_VDISABLE;
+        termios::tcsetattr(&self.fd, SetArg::TCSANOW, &raw)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(&self.fd, SetArg::TCSANOW, &self.termios);
+    }
+}
+
+/// Returns the terminal's size, as (cols, rows)
+pub fn size(fd: BorrowedFd) -> Option<(u16, u16)> {
+    let mut winsize: libc::winsize = unsafe { std::mem::zeroed() };
+    unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCGWINSZ, &mut winsize) };
+
+    if winsize.ws_col == 0 || winsize.ws_row == 0 {
+        return None;
+    }
+
+    Some((winsize.ws_col, winsize.ws_row))
+}
+
+/// Splits off the end of `data` starting a UTF-8 character which isn't
+/// complete yet, like a character typed or pasted across two reads
+pub fn split_incomplete_utf8(data: &mut Vec<u8>) -> Vec<u8> {
+    let start = data.len().saturating_sub(3);
+
+    for i in (start..data.len()).rev() {
+        match std::str::from_utf8(&data[i..]) {
+            Err(e) if e.valid_up_to() == 0 && e.error_len().is_none() => return data.split_off(i),
+            _ => (),
+        }
+    }
+
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_incomplete_utf8() {
+        let mut data = "aż".as_bytes().to_vec();
+        assert!(split_incomplete_utf8(&mut data).is_empty());
+        assert_eq!(data, "aż".as_bytes());
+
+        let mut data = "aż🐢".as_bytes()[..5].to_vec();
+        assert_eq!(split_incomplete_utf8(&mut data), "🐢".as_bytes()[..2]);
+        assert_eq!(data, "aż".as_bytes());
+
+        // Invalid bytes are left to the lossy conversion
+        let mut data = vec![b'a', 0xff];
+        assert!(split_incomplete_utf8(&mut data).is_empty());
+        assert_eq!(data, [b'a', 0xff]);
+    }
+}